@@ -0,0 +1,957 @@
+use rusqlite::Connection;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use error::*;
+use IRC;
+use IRC::command::Typed;
+use SQL;
+use TCP;
+use time;
+
+// what we call ourselves to attached clients
+const NAME : &'static str = "ochatd";
+// the longest line we take from a client
+const MAX_LINE : usize = 512 + 4096;
+// how long a client gets to register before we hang up, in seconds
+const REGISTER_TIMEOUT : i64 = 60;
+// numerics only the daemon needs to see, a client got its own version in the burst
+const REGISTRATION : &'static [u16] = &[1, 2, 3, 4, 5, 250, 251, 252, 253, 254, 255, 265, 266, 372, 375, 376, 422];
+// the capabilities we offer clients
+const CAPS : &'static [&'static str] = &["batch", "draft/chathistory", "message-tags", "server-time",
+                                        "soju.im/bouncer-networks", "soju.im/bouncer-networks-notify"];
+// how many missed messages to play back per channel or query, unless configured otherwise
+pub const PLAYBACK_LIMIT : usize = 500;
+// what BOUNCER NETWORK tells clients about a network, on top of state
+const ATTRIBUTES : &'static [&'static str] = &["name", "host", "port", "tls", "pass", "nickname", "username", "realname", "state", "error"];
+// the most messages one CHATHISTORY request gets
+const HISTORY_LIMIT : usize = 1000;
+
+// an IRC client attached to us as if we were its server
+pub struct Client {
+  sock: TCP::Stream,
+  lines: TCP::Lines,
+  // where it connected from, for warnings
+  pub name: String,
+  pass: Option<String>,
+  nick: Option<String>,
+  // the username also names the client, to keep track of what it's missed
+  user: Option<String>,
+  // registration waits for CAP END once a client starts negotiating
+  negotiating: bool,
+  caps: Vec<String>,
+  pub registered: bool,
+  // registered, but not yet sent what it missed
+  playback: bool,
+  // the last logged message it was sent, and whether that's been saved
+  last: Option<i64>,
+  dirty: bool,
+  connected: i64,
+  // the network it asked for, by BOUNCER BIND or a user/network username
+  bind: Option<String>,
+  // the id of the network it's attached to, once registered, empty for a client that
+  // only manages networks
+  pub network: String,
+  closed: bool
+}
+impl Client {
+  pub fn fd(&self) -> RawFd {
+    return self.sock.as_raw_fd();
+  }
+
+  pub fn wants_write(&self) -> bool {
+    return self.lines.pending();
+  }
+
+  fn send(&mut self, line: &str) {
+    self.lines.push(line);
+    self.lines.flush(&mut self.sock, &self.name);
+  }
+
+  // a numeric from us, addressed to whatever nick the client has
+  fn reply(&mut self, n: u16, params: &str) {
+    let nick = self.nick.clone().unwrap_or("*".to_string());
+    self.send(&format!(":{} {:03} {} {}", NAME, n, nick, params));
+  }
+
+  fn has(&self, cap: &str) -> bool {
+    return self.caps.iter().any(|c| c == cap);
+  }
+
+  // a line as this client wants it, with the time it happened and the id it was logged
+  // under if it asked for those
+  fn send_at(&mut self, line: &str, date: i64, batch: Option<&str>, id: Option<i64>) {
+    let mut tags = Vec::new();
+    if let Some(b) = batch {
+      tags.push(format!("batch={}", b));
+    }
+    if let (Some(id), true) = (id, self.has("message-tags")) {
+      tags.push(format!("msgid={}", id));
+    }
+    if self.has("server-time") {
+      tags.push(format!("time={}", timestamp(date)));
+    }
+    if tags.is_empty() {
+      self.send(line);
+    } else {
+      self.send(&format!("@{} {}", tags.join(";"), line));
+    }
+  }
+
+  fn fail(&mut self, command: &str, code: &str, context: &str, reason: &str) {
+    self.send(&format!(":{} FAIL {} {} {} :{}", NAME, command, code, context, reason));
+  }
+
+  fn reply_cap(&mut self, sub: &str, caps: &str) {
+    let nick = self.nick.clone().unwrap_or("*".to_string());
+    self.send(&format!(":{} CAP {} {} :{}", NAME, nick, sub, caps));
+  }
+
+  // hang up, Bouncer::tick() drops it
+  fn close(&mut self, reason: &str) {
+    self.send(&format!("ERROR :Closing link: {}", reason));
+    self.closed = true;
+  }
+}
+
+pub struct Bouncer {
+  listener: Option<TcpListener>,
+  password: String,
+  // most missed messages to play back per channel or query
+  limit: usize,
+  // for naming batches
+  batches: u64,
+  pub clients: Vec<Client>
+}
+impl Bouncer {
+  // only listens if given an address, and then only with a password
+  pub fn new(addr: Option<&str>, password: Option<&str>, limit: usize) -> Result<Bouncer> {
+    let listener = match (addr, password) {
+      (None, _) => None,
+      (Some(_), None) => bail!("Bouncer: a password is required to listen for IRC clients"),
+      (Some(addr), Some(_)) => {
+        let l = TcpListener::bind(addr).chain_err(|| format!("Bouncer: failed to listen on {}", addr))?;
+        l.set_nonblocking(true).chain_err(|| "Bouncer: failed to make listener nonblocking")?;
+        Some(l)
+      }
+    };
+    return Ok(Bouncer {
+      listener: listener,
+      password: password.unwrap_or("").to_string(),
+      limit: limit,
+      batches: 0,
+      clients: Vec::new()
+    });
+  }
+
+  pub fn fd(&self) -> Option<RawFd> {
+    return self.listener.as_ref().map(|l| l.as_raw_fd());
+  }
+
+  pub fn accept(&mut self, now: i64) {
+    let listener = match self.listener {
+      Some(ref l) => l,
+      None => return
+    };
+    loop {
+      match listener.accept() {
+        Ok((sock, addr)) => {
+          let sock = TCP::Stream::Plain(sock);
+          if let Err(e) = sock.set_nonblocking() {
+            eprintln!("Warning: {}: {}", addr, e);
+            continue;
+          }
+          self.clients.push(Client {
+            sock: sock,
+            lines: TCP::Lines::default(),
+            name: addr.to_string(),
+            pass: None,
+            nick: None,
+            user: None,
+            negotiating: false,
+            caps: Vec::new(),
+            registered: false,
+            playback: false,
+            last: None,
+            dirty: false,
+            connected: now,
+            bind: None,
+            network: String::new(),
+            closed: false
+          });
+        },
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+        Err(e) => {
+          eprintln!("Warning: Bouncer: accept failure: {}", e);
+          break;
+        }
+      }
+    }
+  }
+
+  // everything a client has sent, acted on, and whether any of it was from someone
+  // actually using it rather than the client keeping the connection alive
+  pub fn read(&mut self, i: usize, ctxs: &mut Vec<IRC::Context>, sql: &mut Connection) -> Result<bool> {
+    let (lines, eof) = {
+      let c = &mut self.clients[i];
+      c.lines.flush(&mut c.sock, &c.name);
+      c.lines.read(&mut c.sock, MAX_LINE, &c.name)
+    };
+    let mut active = false;
+    for line in lines {
+      if self.clients[i].closed {
+        break;
+      }
+      active |= self.line(i, &line, ctxs, sql)?;
+    }
+    if eof {
+      self.clients[i].closed = true;
+    }
+    return Ok(active);
+  }
+
+  fn line(&mut self, i: usize, line: &str, ctxs: &mut Vec<IRC::Context>, sql: &mut Connection) -> Result<bool> {
+    let msg = match IRC::parse::parse_message("", 0, line) {
+      Ok(m) => m,
+      Err(e) => {
+        eprintln!("Warning: {}: unparseable line: {}", self.clients[i].name, e);
+        return Ok(false);
+      }
+    };
+    let cmd = match Typed::from_message(&msg) {
+      Ok(cmd) => cmd,
+      Err(e) => {
+        self.clients[i].reply(461, &format!("{} :{}", msg.command, e));
+        return Ok(false);
+      }
+    };
+    let registered = self.clients[i].registered;
+    let active = registered && match cmd {
+      Typed::Ping { .. } => false,
+      Typed::Other(ref c, _) => !c.eq_ignore_ascii_case("PONG"),
+      _ => true
+    };
+    match cmd {
+      Typed::Ping { token } => {
+        self.clients[i].send(&format!(":{} PONG {} :{}", NAME, NAME, token));
+      },
+      Typed::Quit { .. } => {
+        self.clients[i].close("Quit");
+      },
+      Typed::Other(ref c, ref params) if c.eq_ignore_ascii_case("CAP") => {
+        self.cap(i, params, ctxs)?;
+      },
+      Typed::Other(ref c, _) if registered && (c.eq_ignore_ascii_case("PASS") || c.eq_ignore_ascii_case("USER")) => {
+        self.clients[i].reply(462, ":You may not reregister");
+      },
+      Typed::Other(ref c, ref params) if c.eq_ignore_ascii_case("PASS") => {
+        self.clients[i].pass = params.get(0).map(|p| p.to_string());
+      },
+      // user/network picks the network, and the rest names the client
+      Typed::Other(ref c, ref params) if c.eq_ignore_ascii_case("USER") => {
+        if let Some(user) = params.get(0) {
+          let mut parts = user.splitn(2, '/');
+          self.clients[i].user = parts.next().map(|u| u.to_string());
+          if let Some(network) = parts.next() {
+            self.clients[i].bind = Some(network.to_string());
+          }
+        }
+        self.register(i, ctxs)?;
+      },
+      Typed::Nick { nick } if !registered => {
+        self.clients[i].nick = Some(nick.to_string());
+        self.register(i, ctxs)?;
+      },
+      Typed::Other(ref c, ref params) if c.eq_ignore_ascii_case("BOUNCER") => {
+        self.bouncer(i, params, ctxs, sql)?;
+      },
+      _ if !registered => {
+        self.clients[i].reply(451, ":You have not registered");
+      },
+      _ if self.clients[i].network.is_empty() => {
+        let nick = self.clients[i].nick.clone().unwrap_or("*".to_string());
+        self.clients[i].send(&format!(":{} NOTICE {} :Not bound to a network, reconnect with BOUNCER BIND", NAME, nick));
+      },
+      Typed::Other(ref c, ref params) if c.eq_ignore_ascii_case("CHATHISTORY") => {
+        let n = IRC::lookup(&self.clients[i].network, ctxs);
+        self.chathistory(i, params, &ctxs[n], sql)?;
+      },
+      // through the network, so that auto-away knows it was set on purpose
+      Typed::Away { message } => {
+        let n = IRC::lookup(&self.clients[i].network, ctxs);
+        ctxs[n].set_away(message)?;
+      },
+      cmd => {
+        let n = IRC::lookup(&self.clients[i].network, ctxs);
+        self.upstream(i, &cmd, &mut ctxs[n])?;
+      }
+    }
+    return Ok(active);
+  }
+
+  fn cap(&mut self, i: usize, params: &[&str], ctxs: &mut Vec<IRC::Context>) -> Result<()> {
+    let sub = params.get(0).map_or(String::new(), |s| s.to_ascii_uppercase());
+    match &sub[..] {
+      "LS" => {
+        self.clients[i].negotiating = !self.clients[i].registered;
+        self.clients[i].reply_cap("LS", &CAPS.join(" "));
+      },
+      "LIST" => {
+        let caps = self.clients[i].caps.join(" ");
+        self.clients[i].reply_cap("LIST", &caps);
+      },
+      // all or nothing, and a leading - takes a capability away again
+      "REQ" => {
+        let req = params.get(1).map_or("", |c| *c).to_string();
+        let c = &mut self.clients[i];
+        if req.split_whitespace().all(|r| CAPS.contains(&r.trim_start_matches('-'))) {
+          for r in req.split_whitespace() {
+            let cap = r.trim_start_matches('-').to_string();
+            c.caps.retain(|c| *c != cap);
+            if !r.starts_with('-') {
+              c.caps.push(cap);
+            }
+          }
+          c.reply_cap("ACK", &req);
+        } else {
+          c.reply_cap("NAK", &req);
+        }
+      },
+      "END" => {
+        self.clients[i].negotiating = false;
+        self.register(i, ctxs)?;
+      },
+      _ => self.clients[i].reply(410, &format!("{} :Invalid CAP command", sub))
+    }
+    return Ok(());
+  }
+
+  // once a client has sent everything registration needs, check its password and
+  // show it the network as if it had just connected
+  fn register(&mut self, i: usize, ctxs: &mut Vec<IRC::Context>) -> Result<()> {
+    {
+      let password = self.clients[i].pass.as_ref() == Some(&self.password);
+      let c = &mut self.clients[i];
+      if c.registered || c.negotiating || c.nick.is_none() || c.user.is_none() {
+        return Ok(());
+      }
+      if !password {
+        c.reply(464, ":Password incorrect");
+        c.close("Bad password");
+        return Ok(());
+      }
+      // a client managing networks doesn't have to be bound to one
+      let network = match c.bind {
+        Some(ref id) => ctxs.iter().find(|irc| irc.id == *id).map(|irc| irc.id.to_string()),
+        None if c.has("soju.im/bouncer-networks") => Some(String::new()),
+        None => ctxs.get(0).map(|irc| irc.id.to_string())
+      };
+      match network {
+        Some(network) => {
+          c.registered = true;
+          c.playback = !network.is_empty();
+          c.network = network;
+        },
+        None => {
+          c.close("No such network");
+          return Ok(());
+        }
+      }
+    }
+    let network = self.clients[i].network.clone();
+    self.burst(i, ctxs.iter().find(|irc| irc.id == network));
+    return Ok(());
+  }
+
+  // what a server would send a newly registered client, then the state of every
+  // channel we're in
+  fn burst(&mut self, i: usize, irc: Option<&IRC::Context>) {
+    let c = &mut self.clients[i];
+    let asked = c.nick.clone().unwrap_or("*".to_string());
+    c.reply(1, &format!(":Welcome to ochat, {}", asked));
+    c.reply(2, &format!(":Your host is {}, relaying {}", NAME, irc.map_or("no network", |irc| &irc.id)));
+    c.reply(4, &format!("{} ochat iosw beIklmnopstv", NAME));
+    let irc = match irc {
+      Some(irc) => irc,
+      None => {
+        c.reply(422, ":MOTD File is missing");
+        return;
+      }
+    };
+    // history comes from our log, whatever the network offers
+    let mut tokens : Vec<String> = irc.isupport.iter().filter(|&(k, _)| k != "CHATHISTORY" && k != "MSGREFTYPES").map(|(k, v)| {
+      if v.is_empty() { k.clone() } else { format!("{}={}", k, v) }
+    }).collect();
+    tokens.push(format!("CHATHISTORY={}", HISTORY_LIMIT));
+    tokens.push("MSGREFTYPES=msgid,timestamp".to_string());
+    tokens.push(format!("BOUNCER_NETID={}", irc.id));
+    tokens.sort();
+    for t in tokens.chunks(12) {
+      c.reply(5, &format!("{} :are supported by this server", t.join(" ")));
+    }
+    c.reply(422, ":MOTD File is missing");
+    // the client has whatever nick the network gave us, not the one it asked for
+    if asked != irc.me {
+      c.send(&format!(":{} NICK :{}", asked, irc.me));
+      c.nick = Some(irc.me.clone());
+    }
+    let me = mask(irc);
+    for channel in irc.channels.iter() {
+      c.send(&format!(":{} JOIN {}", me, channel));
+      let j = match irc.joined.get(&channel.to_ascii_lowercase()) {
+        Some(j) => j,
+        None => continue
+      };
+      match j.topic {
+        Some(ref topic) => {
+          c.reply(332, &format!("{} :{}", channel, topic));
+          if let (&Some(ref by), Some(at)) = (&j.topic_by, j.topic_at) {
+            c.reply(333, &format!("{} {} {}", channel, by, at));
+          }
+        },
+        None => c.reply(331, &format!("{} :No topic is set", channel))
+      }
+      for names in IRC::chunk(&j.names(), ' ') {
+        c.reply(353, &format!("= {} :{}", channel, names));
+      }
+      c.reply(366, &format!("{} :End of /NAMES list", channel));
+    }
+    if irc.away.is_some() {
+      c.reply(306, ":You have been marked as being away");
+    }
+  }
+
+  // soju's BOUNCER, for listing, adding, changing and removing networks, see
+  // https://codeberg.org/emersion/soju/src/branch/master/doc/ext/bouncer-networks.md
+  fn bouncer(&mut self, i: usize, params: &[&str], ctxs: &mut Vec<IRC::Context>, sql: &mut Connection) -> Result<()> {
+    let sub = params.get(0).map_or(String::new(), |s| s.to_ascii_uppercase());
+    let netid = params.get(1).map_or("", |n| *n);
+    let n = ctxs.iter().position(|irc| irc.id == netid);
+    if sub == "BIND" {
+      let c = &mut self.clients[i];
+      match n {
+        _ if c.registered => c.fail("BOUNCER", "REGISTRATION_IS_COMPLETED", "BIND", "Already registered"),
+        Some(_) => c.bind = Some(netid.to_string()),
+        None => c.fail("BOUNCER", "INVALID_NETID", &format!("BIND {}", netid), "No such network")
+      }
+      return Ok(());
+    }
+    if !self.clients[i].registered {
+      self.clients[i].reply(451, ":You have not registered");
+      return Ok(());
+    }
+    match (&sub[..], n) {
+      ("LISTNETWORKS", _) => {
+        let batch = self.batch(i);
+        let c = &mut self.clients[i];
+        if let Some(ref b) = batch {
+          c.send(&format!(":{} BATCH +{} soju.im/bouncer-networks", NAME, b));
+        }
+        for irc in ctxs.iter() {
+          let line = format!(":{} BOUNCER NETWORK {} {}", NAME, irc.id, attributes(irc));
+          match batch {
+            Some(ref b) => c.send(&format!("@batch={} {}", b, line)),
+            None => c.send(&line)
+          }
+        }
+        if let Some(ref b) = batch {
+          c.send(&format!(":{} BATCH -{}", NAME, b));
+        }
+      },
+      ("ADDNETWORK", _) => {
+        // ADDNETWORK has no netid, just the attributes
+        let attrs = match self.settable(i, "ADDNETWORK", netid, &["name", "host", "port", "tls", "pass", "nickname", "username", "realname"]) {
+          Some(attrs) => attrs,
+          None => return Ok(())
+        };
+        if !attrs.contains_key("host") {
+          self.clients[i].fail("BOUNCER", "NEED_ATTRIBUTE", "ADDNETWORK host", "A network needs a host");
+          return Ok(());
+        }
+        let server = match server(&attrs, None) {
+          Ok(server) => server,
+          Err(attr) => {
+            self.clients[i].fail("BOUNCER", "INVALID_ATTRIBUTE", &format!("ADDNETWORK {}", attr), "Missing or bad value");
+            return Ok(());
+          }
+        };
+        let id = attrs.get("name").cloned().unwrap_or(server.host.clone());
+        if id.is_empty() || id.contains(|c| c == ' ' || c == '/' || c == ',') || ctxs.iter().any(|irc| irc.id == id) {
+          self.clients[i].fail("BOUNCER", "INVALID_ATTRIBUTE", "ADDNETWORK name", "Taken or not usable as a network name");
+          return Ok(());
+        }
+        let nick = attrs.get("nickname").cloned().unwrap_or(self.clients[i].nick.clone().unwrap_or("ochat".to_string()));
+        let ident = attrs.get("username").cloned().unwrap_or(nick.clone());
+        let realname = attrs.get("realname").cloned().unwrap_or(nick.clone());
+        SQL::identity_set(sql, &id, &nick, &ident, &realname)?;
+        let mut irc = IRC::Context::new(id, nick, ident, realname);
+        SQL::load(sql, &mut irc)?;
+        irc.servers = vec![server];
+        SQL::servers_set(sql, &irc.id, &irc.servers)?;
+        // the next tick connects it
+        self.clients[i].send(&format!(":{} BOUNCER ADDNETWORK {}", NAME, irc.id));
+        self.notify(&irc.id, &attributes(&irc));
+        ctxs.push(irc);
+      },
+      ("CHANGENETWORK", Some(n)) => {
+        let attrs = match self.settable(i, &format!("CHANGENETWORK {}", netid), params.get(2).map_or("", |a| *a), &["host", "port", "tls", "pass"]) {
+          Some(attrs) => attrs,
+          None => return Ok(())
+        };
+        // changes the server we're on, which takes effect when we next connect
+        let irc = &mut ctxs[n];
+        let current = irc.servers.get(irc.server).cloned();
+        match server(&attrs, current) {
+          Ok(server) => {
+            if irc.server < irc.servers.len() {
+              irc.servers[irc.server] = server;
+            } else {
+              irc.servers.push(server);
+            }
+          },
+          Err(attr) => {
+            self.clients[i].fail("BOUNCER", "INVALID_ATTRIBUTE", &format!("CHANGENETWORK {} {}", netid, attr), "Missing or bad value");
+            return Ok(());
+          }
+        }
+        SQL::servers_set(sql, &irc.id, &irc.servers)?;
+        self.clients[i].send(&format!(":{} BOUNCER CHANGENETWORK {}", NAME, irc.id));
+        self.notify(&irc.id, &attributes(irc));
+      },
+      ("DELNETWORK", Some(n)) => {
+        if !SQL::identities(sql)?.iter().any(|identity| identity.0 == netid) {
+          self.clients[i].fail("BOUNCER", "INVALID_NETID", &format!("DELNETWORK {}", netid), "Only networks added with ADDNETWORK can be removed");
+          return Ok(());
+        }
+        // a query in flight still owes the ZMQ client waiting on it an answer
+        if ctxs[n].querying() {
+          self.clients[i].fail("BOUNCER", "TEMPORARILY_UNAVAILABLE", &format!("DELNETWORK {}", netid), "Busy answering a query, try again shortly");
+          return Ok(());
+        }
+        // with nobody to tell but the clients we're about to drop
+        ctxs[n].quit("Network removed", time::now_utc().to_timespec().sec)?;
+        let irc = ctxs.remove(n);
+        SQL::identity_remove(sql, &irc.id)?;
+        self.clients[i].send(&format!(":{} BOUNCER DELNETWORK {}", NAME, irc.id));
+        for c in self.clients.iter_mut().filter(|c| c.network == irc.id) {
+          c.close("Network removed");
+        }
+        self.notify(&irc.id, "*");
+      },
+      ("CHANGENETWORK", None) | ("DELNETWORK", None) => {
+        self.clients[i].fail("BOUNCER", "INVALID_NETID", &format!("{} {}", sub, netid), "No such network");
+      },
+      _ => self.clients[i].fail("BOUNCER", "UNKNOWN_COMMAND", &sub, "Unknown subcommand")
+    }
+    return Ok(());
+  }
+
+  // the attributes of an ADDNETWORK or CHANGENETWORK, None if it had some we don't
+  // take, which the client has been told about
+  fn settable(&mut self, i: usize, context: &str, raw: &str, allowed: &[&str]) -> Option<HashMap<String, String>> {
+    let mut attrs = HashMap::new();
+    for a in raw.split(';').filter(|a| !a.is_empty()) {
+      let mut kv = a.splitn(2, '=');
+      let k = kv.next().unwrap_or("");
+      if !allowed.contains(&k) {
+        let code = if ATTRIBUTES.contains(&k) { "READ_ONLY_ATTRIBUTE" } else { "UNKNOWN_ATTRIBUTE" };
+        self.clients[i].fail("BOUNCER", code, &format!("{} {}", context, k), "Can't set that");
+        return None;
+      }
+      attrs.insert(k.to_string(), IRC::unescape(kv.next().unwrap_or("")));
+    }
+    return Some(attrs);
+  }
+
+  // tell clients that asked to be kept up to date about a network, * when it's gone
+  fn notify(&mut self, id: &str, attrs: &str) {
+    for c in self.clients.iter_mut().filter(|c| c.registered && c.has("soju.im/bouncer-networks-notify")) {
+      c.send(&format!(":{} BOUNCER NETWORK {} {}", NAME, id, attrs));
+    }
+  }
+
+  // a command from a registered client, sent on to its network
+  fn upstream(&mut self, i: usize, cmd: &Typed, irc: &mut IRC::Context) -> Result<()> {
+    if let Err(e) = cmd.line() {
+      self.clients[i].send(&format!(":{} NOTICE {} :Not sent: {}", NAME, irc.me, e));
+      return Ok(());
+    }
+    match *cmd {
+      // through join_many so it waits for NickServ like any other join
+      Typed::Join { ref channels, ref keys } => {
+        let mut keys = keys.iter();
+        let chans = channels.iter().map(|c| (c.to_string(), keys.next().map(|k| k.to_string()))).collect();
+        irc.join_many(chans)?;
+      },
+      Typed::Privmsg { .. } | Typed::Notice { .. } => {
+        irc.send(cmd)?;
+        self.echo(irc, cmd, Some(i));
+      },
+      _ => irc.send(cmd)?
+    }
+    return Ok(());
+  }
+
+  // the server won't echo our own messages, so show them to every attached client but
+  // the one that sent them, if it was one of ours
+  pub fn echo(&mut self, irc: &IRC::Context, cmd: &Typed, except: Option<usize>) {
+    let line = match cmd.line() {
+      Ok(l) => format!(":{} {}", mask(irc), l),
+      Err(_) => return
+    };
+    let now = time::now_utc().to_timespec();
+    for (n, c) in self.clients.iter_mut().enumerate() {
+      if Some(n) != except && c.registered && c.network == irc.id {
+        c.send_at(&line, now.sec << 32 | now.nsec as i64, None, None);
+      }
+    }
+  }
+
+  // send a client everything it missed since it was last attached, keeping only the
+  // newest messages of each channel or query; a client we've never seen before just
+  // starts from here
+  fn playback(&mut self, i: usize, irc: &IRC::Context, sql: &Connection) -> Result<()> {
+    let user = self.clients[i].user.clone().unwrap_or(String::new());
+    let last = match SQL::delivered(sql, &user, &irc.id)? {
+      Some(id) => id,
+      None => {
+        self.clients[i].last = SQL::last_id(sql, &irc.id)?;
+        self.clients[i].dirty = true;
+        return Ok(());
+      }
+    };
+    // the newest message played back, whichever buffer it's in
+    let mut newest = None;
+    for buffer in SQL::missed(sql, &irc.id, last)? {
+      let missed = SQL::since(sql, &irc.id, &buffer, last, self.limit)?;
+      self.history(i, &buffer, &missed);
+      newest = cmp::max(newest, missed.last().map(|m| m.id));
+    }
+    // relay may already have sent it something newer
+    let c = &mut self.clients[i];
+    if newest > c.last {
+      c.last = newest;
+      c.dirty = true;
+    }
+    return Ok(());
+  }
+
+  // a batch id, if the client takes batches
+  fn batch(&mut self, i: usize) -> Option<String> {
+    if !self.clients[i].has("batch") {
+      return None;
+    }
+    self.batches += 1;
+    return Some(format!("ochat{}", self.batches));
+  }
+
+  // logged messages of one channel or query, as a chathistory batch
+  fn history(&mut self, i: usize, buffer: &str, messages: &[SQL::Stored]) {
+    let batch = self.batch(i);
+    let c = &mut self.clients[i];
+    if let Some(ref b) = batch {
+      c.send(&format!(":{} BATCH +{} chathistory {}", NAME, b, buffer));
+    }
+    for m in messages.iter() {
+      c.send_at(&stored_line(m), m.date, batch.as_ref().map(|b| &b[..]), Some(m.id));
+    }
+    if let Some(ref b) = batch {
+      c.send(&format!(":{} BATCH -{}", NAME, b));
+    }
+  }
+
+  // answer CHATHISTORY from the log rather than the network, msgids are our own
+  // messages.id
+  fn chathistory(&mut self, i: usize, params: &[&str], irc: &IRC::Context, sql: &Connection) -> Result<()> {
+    let sub = params.get(0).map_or(String::new(), |s| s.to_ascii_uppercase());
+    let wanted = match &sub[..] {
+      "BETWEEN" => 5,
+      "TARGETS" | "BEFORE" | "AFTER" | "LATEST" | "AROUND" => 4,
+      _ => {
+        self.clients[i].fail("CHATHISTORY", "INVALID_PARAMS", &sub, "Unknown subcommand");
+        return Ok(());
+      }
+    };
+    let limit = match params.last().and_then(|l| l.parse::<usize>().ok()) {
+      Some(l) if params.len() == wanted => if l == 0 || l > HISTORY_LIMIT { HISTORY_LIMIT } else { l },
+      _ => {
+        self.clients[i].fail("CHATHISTORY", "INVALID_PARAMS", &sub, "Wrong number of parameters or bad limit");
+        return Ok(());
+      }
+    };
+    if sub == "TARGETS" {
+      let (from, to) = match (reference(params[1]), reference(params[2])) {
+        (Some(SQL::Bound::Date(from)), Some(SQL::Bound::Date(to))) => (from, to),
+        _ => {
+          self.clients[i].fail("CHATHISTORY", "INVALID_PARAMS", &sub, "TARGETS takes two timestamps");
+          return Ok(());
+        }
+      };
+      let chantypes = irc.isupport.get("CHANTYPES").map_or("#&", |c| &c[..]);
+      let targets = SQL::targets(sql, &irc.id, chantypes, from, to, limit)?;
+      let batch = self.batch(i);
+      let c = &mut self.clients[i];
+      if let Some(ref b) = batch {
+        c.send(&format!(":{} BATCH +{} draft/chathistory-targets", NAME, b));
+      }
+      for (target, date) in targets {
+        let line = format!(":{} CHATHISTORY TARGETS {} {}", NAME, target, timestamp(date));
+        match batch {
+          Some(ref b) => c.send(&format!("@batch={} {}", b, line)),
+          None => c.send(&line)
+        }
+      }
+      if let Some(ref b) = batch {
+        c.send(&format!(":{} BATCH -{}", NAME, b));
+      }
+      return Ok(());
+    }
+    let target = params[1];
+    let chantypes = irc.isupport.get("CHANTYPES").map_or("#&", |c| &c[..]);
+    if target.is_empty() || target.contains(',') || target == "*" {
+      self.clients[i].fail("CHATHISTORY", "INVALID_TARGET", &format!("{} {}", sub, target), "Not a channel or nick");
+      return Ok(());
+    }
+    // a query is found by who sent it to us
+    let me = if target.starts_with(|c| chantypes.contains(c)) { None } else { Some(&irc.me[..]) };
+    // every reference has to be one we know, msgids only name messages on this network
+    let mut refs = Vec::new();
+    for r in params[2..params.len() - 1].iter() {
+      if sub == "LATEST" && *r == "*" {
+        continue;
+      }
+      let resolved = match reference(r) {
+        Some(SQL::Bound::Id(id)) => SQL::date(sql, &irc.id, id)?.map(|date| (SQL::Bound::Id(id), date)),
+        Some(SQL::Bound::Date(date)) => Some((SQL::Bound::Date(date), date)),
+        None => None
+      };
+      match resolved {
+        Some(r) => refs.push(r),
+        None => {
+          self.clients[i].fail("CHATHISTORY", "INVALID_PARAMS", &format!("{} {}", sub, r), "Unknown or malformed message reference");
+          return Ok(());
+        }
+      }
+    }
+    let messages = match &sub[..] {
+      "BEFORE" => SQL::history(sql, &irc.id, target, me, None, Some(&refs[0].0), true, limit)?,
+      "AFTER" => SQL::history(sql, &irc.id, target, me, Some(&refs[0].0), None, false, limit)?,
+      "LATEST" => SQL::history(sql, &irc.id, target, me, refs.get(0).map(|r| &r.0), None, true, limit)?,
+      // the referenced message and as many after it as before it
+      "AROUND" => {
+        let from = match refs[0].0 {
+          SQL::Bound::Id(id) => SQL::Bound::Id(id - 1),
+          SQL::Bound::Date(date) => SQL::Bound::Date(date - 1)
+        };
+        let mut messages = SQL::history(sql, &irc.id, target, me, None, Some(&refs[0].0), true, limit / 2)?;
+        messages.extend(SQL::history(sql, &irc.id, target, me, Some(&from), None, false, limit - limit / 2)?);
+        messages
+      },
+      // starting from whichever end was given first
+      _ => if refs[0].1 <= refs[1].1 {
+        SQL::history(sql, &irc.id, target, me, Some(&refs[0].0), Some(&refs[1].0), false, limit)?
+      } else {
+        SQL::history(sql, &irc.id, target, me, Some(&refs[1].0), Some(&refs[0].0), true, limit)?
+      }
+    };
+    self.history(i, target, &messages);
+    return Ok(());
+  }
+
+  // fan a line from a network out to every client attached to it, date is when it
+  // arrived in the same form as messages.date
+  pub fn relay(&mut self, irc: &IRC::Context, msg: &IRC::Message, line: &str, date: i64) {
+    match msg.command {
+      IRC::Command::Numeric(n) if REGISTRATION.contains(&n) => return,
+      // our own capability negotiation is no business of the client's, nor what it got us
+      IRC::Command::Named(ref c) if c == "PING" || c == "PONG" || c == "CAP" || c == "MARKREAD" => return,
+      // a TAGMSG is nothing without the tags we strip below
+      IRC::Command::Named(ref c) if c == "TAGMSG" => return,
+      _ => ()
+    }
+    // the network's tags aren't ours to pass on, clients get our own msgid instead
+    let line = if line.starts_with('@') {
+      line.splitn(2, ' ').nth(1).unwrap_or("")
+    } else {
+      line
+    };
+    for c in self.clients.iter_mut().filter(|c| c.registered && c.network == irc.id) {
+      // only messages with a source are logged, see main
+      c.send_at(line, date, None, msg.prefix.as_ref().map(|_| msg.id));
+      if msg.prefix.is_some() {
+        c.last = Some(msg.id);
+        c.dirty = true;
+      }
+    }
+  }
+
+  // let attached clients know when their network goes away or comes back
+  pub fn connection(&mut self, irc: &IRC::Context) {
+    let text = if irc.sock.is_some() {
+      format!("Connecting to {}", irc.id)
+    } else {
+      format!("Lost the connection to {}, reconnecting", irc.id)
+    };
+    for c in self.clients.iter_mut().filter(|c| c.registered && c.network == irc.id) {
+      c.send(&format!(":{} NOTICE {} :{}", NAME, irc.me, text));
+    }
+    self.notify(&irc.id, &attributes(irc));
+  }
+
+  pub fn flush(&mut self) {
+    for c in self.clients.iter_mut() {
+      c.lines.flush(&mut c.sock, &c.name);
+    }
+  }
+
+  // play back to clients that just registered, remember what every client has been
+  // sent, and drop clients that are gone or took too long to register
+  pub fn tick(&mut self, now: i64, ctxs: &Vec<IRC::Context>, sql: &Connection) -> Result<()> {
+    for i in 0..self.clients.len() {
+      if self.clients[i].playback && !self.clients[i].closed {
+        self.clients[i].playback = false;
+        let n = IRC::lookup(&self.clients[i].network, ctxs);
+        self.playback(i, &ctxs[n], sql)?;
+      }
+    }
+    for c in self.clients.iter_mut() {
+      if !c.registered && !c.closed && now - c.connected >= REGISTER_TIMEOUT {
+        c.close("Registration timed out");
+      }
+      if c.dirty {
+        if let (&Some(ref user), Some(id)) = (&c.user, c.last) {
+          SQL::delivered_set(sql, user, &c.network, id)?;
+        }
+        c.dirty = false;
+      }
+    }
+    self.clients.retain(|c| !c.closed);
+    return Ok(());
+  }
+}
+
+// our own nick!user@host on a network, as far as we know it
+fn mask(irc: &IRC::Context) -> String {
+  return match irc.userhost {
+    Some(ref uh) => format!("{}!{}", irc.me, uh),
+    None => irc.me.clone()
+  };
+}
+
+// messages.date as an IRCv3 server-time timestamp
+pub fn timestamp(date: i64) -> String {
+  let t = time::at_utc(time::Timespec::new(date >> 32, (date & 0xffffffff) as i32));
+  return format!("{}.{:03}Z", time::strftime("%Y-%m-%dT%H:%M:%S", &t).unwrap(), t.tm_nsec / 1000000);
+}
+
+// a network as BOUNCER NETWORK describes it, never with its password
+fn attributes(irc: &IRC::Context) -> String {
+  let state = match (&irc.sock, irc.registered) {
+    (&Some(_), true) => "connected",
+    (&Some(_), false) => "connecting",
+    (&None, _) if irc.dialing() => "connecting",
+    (&None, _) => "disconnected"
+  };
+  let mut attrs = vec![("name", irc.id.to_string()),
+                       ("state", state.to_string()),
+                       ("nickname", irc.nick.to_string()),
+                       ("username", irc.ident.to_string()),
+                       ("realname", irc.realname.to_string())];
+  if let Some(s) = irc.servers.get(irc.server) {
+    attrs.push(("host", s.host.clone()));
+    attrs.push(("port", s.port.to_string()));
+    attrs.push(("tls", if s.tls { "1" } else { "0" }.to_string()));
+  }
+  let attrs : Vec<String> = attrs.iter().map(|&(k, ref v)| format!("{}={}", k, IRC::escape(v))).collect();
+  return attrs.join(";");
+}
+
+// the server in ADDNETWORK or CHANGENETWORK attributes, on top of base for changes, or
+// the name of the attribute that's wrong
+fn server(attrs: &HashMap<String, String>, base: Option<IRC::Server>) -> ::std::result::Result<IRC::Server, &'static str> {
+  let tls = match attrs.get("tls").map(|t| &t[..]) {
+    Some("1") => true,
+    Some("0") => false,
+    Some(_) => return Err("tls"),
+    None => base.as_ref().map_or(true, |b| b.tls)
+  };
+  let port = match attrs.get("port").map(|p| p.parse::<u16>()) {
+    Some(Ok(p)) if p > 0 => p,
+    Some(_) => return Err("port"),
+    None => base.as_ref().map_or(if tls { 6697 } else { 6667 }, |b| b.port)
+  };
+  // an empty password removes it
+  let password = match attrs.get("pass") {
+    Some(p) if p.is_empty() => None,
+    Some(p) => Some(p.clone()),
+    None => base.as_ref().and_then(|b| b.password.clone())
+  };
+  let host = match (attrs.get("host"), base) {
+    (Some(h), _) if !h.is_empty() => h.clone(),
+    (None, Some(b)) => b.host,
+    _ => return Err("host")
+  };
+  return Ok(IRC::Server {
+    host: host,
+    port: port,
+    tls: tls,
+    password: password
+  });
+}
+
+// a CHATHISTORY message reference, msgid=<messages.id> or timestamp=<server-time>
+pub fn reference(r: &str) -> Option<SQL::Bound> {
+  if r.starts_with("msgid=") {
+    return r["msgid=".len()..].parse().ok().map(SQL::Bound::Id);
+  }
+  if !r.starts_with("timestamp=") {
+    return None;
+  }
+  let t = r["timestamp=".len()..].trim_end_matches('Z');
+  let (whole, fraction) = match t.find('.') {
+    Some(dot) => (&t[..dot], &t[dot + 1..]),
+    None => (t, "")
+  };
+  if !fraction.chars().all(|c| c.is_ascii_digit()) {
+    return None;
+  }
+  let sec = time::strptime(whole, "%Y-%m-%dT%H:%M:%S").ok()?.to_timespec().sec;
+  let nsec = format!("{:0<9}", &fraction[..fraction.len().min(9)]).parse::<i64>().ok()?;
+  return Some(SQL::Bound::Date(sec << 32 | nsec));
+}
+
+// a logged message as the line it arrived as, give or take tags and spacing
+fn stored_line(m: &SQL::Stored) -> String {
+  let mut line = match (&m.server, &m.nick) {
+    (&Some(ref s), _) => format!(":{} ", s),
+    (&None, &Some(ref n)) => {
+      let mut p = format!(":{}", n);
+      if let Some(ref u) = m.ident {
+        p.push_str(&format!("!{}", u));
+      }
+      if let Some(ref h) = m.host {
+        p.push_str(&format!("@{}", h));
+      }
+      p + " "
+    },
+    _ => String::new()
+  };
+  match (&m.command, m.numeric) {
+    (_, Some(n)) => line.push_str(&format!("{:03}", n)),
+    (&Some(ref c), None) => line.push_str(c),
+    (&None, None) => ()
+  }
+  for (i, p) in m.params.iter().enumerate() {
+    if i + 1 == m.params.len() {
+      line.push_str(" :");
+    } else {
+      line.push(' ');
+    }
+    line.push_str(p);
+  }
+  return line;
+}
@@ -0,0 +1,132 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use error::*;
+use IRC;
+use TCP;
+
+// the longest query we take, RFC 1413 queries are just a pair of ports
+const MAX_LINE : usize = 64;
+// how long a querier gets to ask before we hang up, in seconds
+const TIMEOUT : i64 = 30;
+
+// a connection from somebody asking who owns one of our connections
+pub struct Query {
+  sock: TCP::Stream,
+  lines: TCP::Lines,
+  name: String,
+  peer: IpAddr,
+  connected: i64,
+  closed: bool
+}
+impl Query {
+  pub fn fd(&self) -> RawFd {
+    return self.sock.as_raw_fd();
+  }
+}
+
+// an RFC 1413 ident server answering for our connections to networks, and nothing else
+pub struct Responder {
+  listener: TcpListener,
+  pub queries: Vec<Query>
+}
+impl Responder {
+  // addr can be an unprivileged port, with port 113 redirected to it
+  pub fn new(addr: &str) -> Result<Responder> {
+    let listener = TcpListener::bind(addr).chain_err(|| format!("Ident: failed to listen on {}", addr))?;
+    listener.set_nonblocking(true).chain_err(|| "Ident: failed to make listener nonblocking")?;
+    return Ok(Responder {
+      listener: listener,
+      queries: Vec::new()
+    });
+  }
+
+  pub fn fd(&self) -> RawFd {
+    return self.listener.as_raw_fd();
+  }
+
+  pub fn accept(&mut self, now: i64) {
+    loop {
+      match self.listener.accept() {
+        Ok((sock, addr)) => {
+          let sock = TCP::Stream::Plain(sock);
+          if let Err(e) = sock.set_nonblocking() {
+            eprintln!("Warning: {}: {}", addr, e);
+            continue;
+          }
+          self.queries.push(Query {
+            sock: sock,
+            lines: TCP::Lines::default(),
+            name: addr.to_string(),
+            peer: addr.ip(),
+            connected: now,
+            closed: false
+          });
+        },
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+        Err(e) => {
+          eprintln!("Warning: Ident: accept failure: {}", e);
+          break;
+        }
+      }
+    }
+  }
+
+  // answer the first query on a connection, then hang up
+  pub fn read(&mut self, i: usize, ctxs: &[IRC::Context]) {
+    let q = &mut self.queries[i];
+    let (lines, eof) = q.lines.read(&mut q.sock, MAX_LINE, &q.name);
+    if let Some(line) = lines.first() {
+      let reply = answer(line, q.peer, ctxs);
+      q.lines.push(&reply);
+      q.lines.flush(&mut q.sock, &q.name);
+      q.closed = true;
+    }
+    if eof {
+      q.closed = true;
+    }
+  }
+
+  // drop queries that are answered, gone, or too slow to ask
+  pub fn tick(&mut self, now: i64) {
+    self.queries.retain(|q| !q.closed && now - q.connected < TIMEOUT);
+  }
+}
+
+// the reply to "<our port> , <their port>", only naming a connection if the query came
+// from the far end of it
+fn answer(line: &str, peer: IpAddr, ctxs: &[IRC::Context]) -> String {
+  let mut ports = line.splitn(2, ',').map(|p| p.trim().parse::<u16>());
+  let (local, remote) = match (ports.next(), ports.next()) {
+    (Some(Ok(l)), Some(Ok(r))) if l > 0 && r > 0 => (l, r),
+    _ => return format!("{} : ERROR : INVALID-PORT", line.trim())
+  };
+  for irc in ctxs.iter() {
+    let (ours, theirs) = match irc.addrs() {
+      Some(addrs) => addrs,
+      None => continue
+    };
+    if ours.port() == local && theirs.port() == remote && same(theirs, peer) {
+      return format!("{} , {} : USERID : UNIX : {}", local, remote, irc.ident);
+    }
+  }
+  return format!("{} , {} : ERROR : NO-USER", local, remote);
+}
+
+// whether a connection's far end is who's asking, when one of them might be an IPv4
+// address mapped into IPv6 by a dual stack listener
+fn same(addr: SocketAddr, peer: IpAddr) -> bool {
+  return unmapped(addr.ip()) == unmapped(peer);
+}
+
+fn unmapped(ip: IpAddr) -> IpAddr {
+  if let IpAddr::V6(v6) = ip {
+    let s = v6.segments();
+    if s[..5].iter().all(|&x| x == 0) && s[5] == 0xffff {
+      return IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8));
+    }
+  }
+  return ip;
+}
@@ -1,22 +1,34 @@
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::Write;
-use std::net::TcpStream;
+use regex::Regex;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashMap;
 use std::fmt;
+use std::net::SocketAddr;
 
 use error::*;
+use TCP;
 
-// stuff from https://github.com/Detegr/RBot-parser
+// a message source, the user parts are optional since servers are free to leave
+// them out, eg for our own nick early on
 #[derive(PartialEq, Debug)]
 pub enum Prefix<'a> {
-  User(&'a str, &'a str, &'a str),
+  User(&'a str, Option<&'a str>, Option<&'a str>),
   Server(&'a str)
 }
 impl<'a> fmt::Display for Prefix<'a> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match *self {
-      Prefix::User(nick, user, host) => write!(f, "{}!{}@{}", nick, user, host),
+      Prefix::User(nick, user, host) => {
+        write!(f, "{}", nick)?;
+        if let Some(user) = user {
+          write!(f, "!{}", user)?;
+        }
+        if let Some(host) = host {
+          write!(f, "@{}", host)?;
+        }
+        Ok(())
+      },
       Prefix::Server(serverstr) => write!(f, "{}", serverstr)
     }
   }
@@ -26,233 +38,3114 @@ pub enum Command<'a> {
   Named(Cow<'a, str>),
   Numeric(u16)
 }
+impl<'a> Command<'a> {
+  // RPL_WELCOME for 001 and so on, None for named commands and numerics we don't know
+  pub fn name(&self) -> Option<&'static str> {
+    return match *self {
+      Command::Numeric(n) => reply(n).map(|r| r.name),
+      Command::Named(_) => None
+    };
+  }
+}
 impl<'a> fmt::Display for Command<'a> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match *self {
       Command::Named(ref s) => write!(f, "{}", s),
-      Command::Numeric(n) => write!(f, "{}", n)
+      // numerics are always three digits on the wire
+      Command::Numeric(n) => write!(f, "{:03}", n)
+    }
+  }
+}
+
+// an IRCv3 message tag, the value is kept as it was sent, still escaped
+#[derive(PartialEq, Debug)]
+pub struct Tag<'a> {
+  pub key: &'a str,
+  pub value: Option<&'a str>
+}
+impl<'a> Tag<'a> {
+  // the unescaped value, a missing value is the same as an empty one
+  pub fn value(&self) -> String {
+    return unescape(self.value.unwrap_or(""));
+  }
+}
+
+pub fn unescape(v: &str) -> String {
+  let mut ret = String::with_capacity(v.len());
+  let mut chars = v.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      ret.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some(':') => ret.push(';'),
+      Some('s') => ret.push(' '),
+      Some('r') => ret.push('\r'),
+      Some('n') => ret.push('\n'),
+      // includes \\, an unknown escape is just the character
+      Some(c) => ret.push(c),
+      // a lone backslash at the end is dropped
+      None => ()
+    }
+  }
+  return ret;
+}
+
+// how a network folds case in nicks and channels, from CASEMAPPING
+#[derive(Clone, Copy, PartialEq)]
+pub enum Casemapping {
+  Ascii,
+  // also {}|^ as the lowercase of []\~, the default
+  Rfc1459,
+  // the same without ^ and ~
+  StrictRfc1459
+}
+impl Casemapping {
+  pub fn from_name(s: Option<&str>) -> Casemapping {
+    return match s {
+      Some("ascii") => Casemapping::Ascii,
+      Some("strict-rfc1459") => Casemapping::StrictRfc1459,
+      _ => Casemapping::Rfc1459
+    };
+  }
+  pub fn eq(&self, a: &str, b: &str) -> bool {
+    return a.len() == b.len() && a.bytes().zip(b.bytes()).all(|(x, y)| self.fold(x) == self.fold(y));
+  }
+  pub fn fold(&self, b: u8) -> u8 {
+    return match (b, *self) {
+      (b, _) if b.is_ascii_uppercase() => b.to_ascii_lowercase(),
+      (_, Casemapping::Ascii) => b,
+      (b'[', _) => b'{',
+      (b']', _) => b'}',
+      (b'\\', _) => b'|',
+      (b'~', Casemapping::Rfc1459) => b'^',
+      _ => b
+    };
+  }
+}
+
+// whether s matches a mask with * and ? wildcards, where a backslash makes the next
+// character literal, folding case the way the network does
+pub fn wildcard(mask: &str, s: &str, cm: Casemapping) -> bool {
+  // None for *, Some(None) for ?, and Some(Some(c)) for a literal
+  let mut pattern : Vec<Option<Option<u8>>> = Vec::new();
+  let mut bytes = mask.bytes();
+  while let Some(b) = bytes.next() {
+    pattern.push(match b {
+      b'*' => None,
+      b'?' => Some(None),
+      // a trailing backslash is just a backslash
+      b'\\' => Some(Some(cm.fold(bytes.next().unwrap_or(b'\\')))),
+      b => Some(Some(cm.fold(b)))
+    });
+  }
+  let s : Vec<u8> = s.bytes().map(|b| cm.fold(b)).collect();
+  // where to go back to after the last *, in pattern and in s
+  let (mut p, mut i) = (0, 0);
+  let mut star : Option<(usize, usize)> = None;
+  while i < s.len() {
+    match pattern.get(p) {
+      Some(&Some(None)) => {
+        p += 1;
+        i += 1;
+      },
+      Some(&Some(Some(c))) if c == s[i] => {
+        p += 1;
+        i += 1;
+      },
+      Some(&None) => {
+        star = Some((p, i));
+        p += 1;
+      },
+      _ => match star {
+        Some((sp, si)) => {
+          p = sp + 1;
+          i = si + 1;
+          star = Some((sp, si + 1));
+        },
+        None => return false
+      }
+    }
+  }
+  return pattern[p..].iter().all(|t| t.is_none());
+}
+
+// what we know about someone, for matching masks against
+pub struct User {
+  pub nick: String,
+  pub user: Option<String>,
+  pub host: Option<String>,
+  pub account: Option<String>,
+  pub realname: Option<String>
+}
+impl User {
+  // the sender of a message, with their account if the server tagged it
+  pub fn from_message(msg: &Message) -> Option<User> {
+    return match msg.prefix {
+      Some(Prefix::User(nick, user, host)) => Some(User {
+        nick: nick.to_string(),
+        user: user.map(|u| u.to_string()),
+        host: host.map(|h| h.to_string()),
+        // * is how servers say nobody's logged in
+        account: msg.tag("account").and_then(|a| if a == "*" { None } else { Some(a) }),
+        realname: None
+      }),
+      _ => None
+    };
+  }
+
+  // nick!user@host, with * for whatever we don't know
+  pub fn mask(&self) -> String {
+    return format!("{}!{}@{}", self.nick, self.user.as_ref().map_or("*", |u| &u[..]), self.host.as_ref().map_or("*", |h| &h[..]));
+  }
+}
+
+// the kinds of ban mask we can make for someone
+#[derive(Clone, Copy)]
+pub enum Shape {
+  // nick!*@*
+  Nick,
+  // *!*@host
+  Host,
+  // *!*user@host, without any ~ from a missing ident reply
+  UserHost,
+  // *!*@*.example.org, or *!*@192.0.2.* for an address
+  Domain,
+  // an account extban, eg $a:account
+  Account
+}
+impl Shape {
+  pub fn from_name(s: &str) -> Option<Shape> {
+    return match s {
+      "nick" => Some(Shape::Nick),
+      "host" => Some(Shape::Host),
+      "userhost" => Some(Shape::UserHost),
+      "domain" => Some(Shape::Domain),
+      "account" => Some(Shape::Account),
+      _ => None
+    };
+  }
+}
+
+// the other way round, for values we put in tags
+pub fn escape(v: &str) -> String {
+  let mut ret = String::with_capacity(v.len());
+  for c in v.chars() {
+    match c {
+      ';' => ret.push_str("\\:"),
+      ' ' => ret.push_str("\\s"),
+      '\\' => ret.push_str("\\\\"),
+      '\r' => ret.push_str("\\r"),
+      '\n' => ret.push_str("\\n"),
+      c => ret.push(c)
     }
   }
+  return ret;
 }
 
 #[derive(Debug)]
 pub struct Message<'a> {
   pub server: &'a str,
   pub id: i64,
+  pub tags: Vec<Tag<'a>>,
   pub prefix: Option<Prefix<'a>>,
   pub command: Command<'a>,
-  pub params: Vec<&'a str>
+  pub params: Vec<&'a str>,
+  // whether the last param was sent after a ':', which it needn't have been
+  pub trailing: bool
+}
+impl<'a> Message<'a> {
+  // a repeated tag is the same as its last occurrence
+  pub fn tag(&self, key: &str) -> Option<String> {
+    return self.tags.iter().rev().find(|t| t.key == key).map(|t| t.value());
+  }
 }
 
+// writes the message back out exactly as it was parsed, as long as it was well formed
+// to begin with, ie single spaces between everything
 impl<'a> fmt::Display for Message<'a> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    // TODO: I don't think this ret.push_str() stuff is ideal
-    let mut ret = match self.prefix {
-      Some(ref prefix) => format!(":{} ", prefix),
-      None => "".to_string()
-    };
-    ret.push_str(format!("{} ", self.command).as_ref());
-    for param in self.params.iter() {
-      // TODO: The output format of this is not 1:1 to the string that was parsed
-      ret.push_str(format!("{} ", param).as_ref());
+    if !self.tags.is_empty() {
+      write!(f, "@")?;
+      for (i, t) in self.tags.iter().enumerate() {
+        if i > 0 {
+          write!(f, ";")?;
+        }
+        match t.value {
+          Some(v) => write!(f, "{}={}", t.key, v)?,
+          None => write!(f, "{}", t.key)?
+        }
+      }
+      write!(f, " ")?;
     }
-    write!(f, "{}", ret)
+    if let Some(ref prefix) = self.prefix {
+      write!(f, ":{} ", prefix)?;
+    }
+    write!(f, "{}", self.command)?;
+    for (i, param) in self.params.iter().enumerate() {
+      let last = i + 1 == self.params.len();
+      if last && (self.trailing || param.is_empty() || param.starts_with(':') || param.contains(' ')) {
+        write!(f, " :{}", param)?;
+      } else {
+        write!(f, " {}", param)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+// what we know about a numeric reply
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reply {
+  pub code: u16,
+  pub name: &'static str,
+  // the index of the param the reply is about, eg the channel or nick, if there is one;
+  // param 0 is always our own nick
+  pub target: Option<usize>
+}
+impl Reply {
+  pub fn error(&self) -> bool {
+    return self.name.starts_with("ERR_");
   }
 }
 
-pub struct Context<'a> {
-  pub sock: TcpStream,
-  pub id: &'a str,
+pub fn reply(code: u16) -> Option<Reply> {
+  return NUMERICS.binary_search_by_key(&code, |&(c, _, _)| c).ok().map(|i| Reply {
+    code: code,
+    name: NUMERICS[i].1,
+    target: NUMERICS[i].2
+  });
+}
+
+// every numeric we have a name for, sorted by code; names follow
+// https://modern.ircdocs.horse/ and, where that has nothing, RFC 2812
+pub const NUMERICS : &'static [(u16, &'static str, Option<usize>)] = &[
+  (1, "RPL_WELCOME", None),
+  (2, "RPL_YOURHOST", None),
+  (3, "RPL_CREATED", None),
+  (4, "RPL_MYINFO", None),
+  (5, "RPL_ISUPPORT", None),
+  (10, "RPL_BOUNCE", None),
+  (221, "RPL_UMODEIS", None),
+  (251, "RPL_LUSERCLIENT", None),
+  (252, "RPL_LUSEROP", None),
+  (253, "RPL_LUSERUNKNOWN", None),
+  (254, "RPL_LUSERCHANNELS", None),
+  (255, "RPL_LUSERME", None),
+  (256, "RPL_ADMINME", None),
+  (257, "RPL_ADMINLOC1", None),
+  (258, "RPL_ADMINLOC2", None),
+  (259, "RPL_ADMINEMAIL", None),
+  (263, "RPL_TRYAGAIN", None),
+  (265, "RPL_LOCALUSERS", None),
+  (266, "RPL_GLOBALUSERS", None),
+  (276, "RPL_WHOISCERTFP", Some(1)),
+  (301, "RPL_AWAY", Some(1)),
+  (302, "RPL_USERHOST", None),
+  (303, "RPL_ISON", None),
+  (305, "RPL_UNAWAY", None),
+  (306, "RPL_NOWAWAY", None),
+  (307, "RPL_WHOISREGNICK", Some(1)),
+  (311, "RPL_WHOISUSER", Some(1)),
+  (312, "RPL_WHOISSERVER", Some(1)),
+  (313, "RPL_WHOISOPERATOR", Some(1)),
+  (314, "RPL_WHOWASUSER", Some(1)),
+  (315, "RPL_ENDOFWHO", Some(1)),
+  (317, "RPL_WHOISIDLE", Some(1)),
+  (318, "RPL_ENDOFWHOIS", Some(1)),
+  (319, "RPL_WHOISCHANNELS", Some(1)),
+  (320, "RPL_WHOISSPECIAL", Some(1)),
+  (321, "RPL_LISTSTART", None),
+  (322, "RPL_LIST", Some(1)),
+  (323, "RPL_LISTEND", None),
+  (324, "RPL_CHANNELMODEIS", Some(1)),
+  (329, "RPL_CREATIONTIME", Some(1)),
+  (330, "RPL_WHOISACCOUNT", Some(1)),
+  (331, "RPL_NOTOPIC", Some(1)),
+  (332, "RPL_TOPIC", Some(1)),
+  (333, "RPL_TOPICWHOTIME", Some(1)),
+  (336, "RPL_INVITELIST", Some(1)),
+  (337, "RPL_ENDOFINVITELIST", None),
+  (338, "RPL_WHOISACTUALLY", Some(1)),
+  (341, "RPL_INVITING", Some(2)),
+  (346, "RPL_INVEXLIST", Some(1)),
+  (347, "RPL_ENDOFINVEXLIST", Some(1)),
+  (348, "RPL_EXCEPTLIST", Some(1)),
+  (349, "RPL_ENDOFEXCEPTLIST", Some(1)),
+  (351, "RPL_VERSION", None),
+  (352, "RPL_WHOREPLY", Some(1)),
+  (353, "RPL_NAMREPLY", Some(2)),
+  (354, "RPL_WHOSPCRPL", None),
+  (364, "RPL_LINKS", None),
+  (365, "RPL_ENDOFLINKS", None),
+  (366, "RPL_ENDOFNAMES", Some(1)),
+  (367, "RPL_BANLIST", Some(1)),
+  (368, "RPL_ENDOFBANLIST", Some(1)),
+  (369, "RPL_ENDOFWHOWAS", Some(1)),
+  (371, "RPL_INFO", None),
+  (372, "RPL_MOTD", None),
+  (374, "RPL_ENDOFINFO", None),
+  (375, "RPL_MOTDSTART", None),
+  (376, "RPL_ENDOFMOTD", None),
+  (378, "RPL_WHOISHOST", Some(1)),
+  (379, "RPL_WHOISMODES", Some(1)),
+  (381, "RPL_YOUREOPER", None),
+  (382, "RPL_REHASHING", None),
+  (391, "RPL_TIME", None),
+  (396, "RPL_HOSTHIDDEN", None),
+  (400, "ERR_UNKNOWNERROR", None),
+  (401, "ERR_NOSUCHNICK", Some(1)),
+  (402, "ERR_NOSUCHSERVER", Some(1)),
+  (403, "ERR_NOSUCHCHANNEL", Some(1)),
+  (404, "ERR_CANNOTSENDTOCHAN", Some(1)),
+  (405, "ERR_TOOMANYCHANNELS", Some(1)),
+  (406, "ERR_WASNOSUCHNICK", Some(1)),
+  (407, "ERR_TOOMANYTARGETS", Some(1)),
+  (409, "ERR_NOORIGIN", None),
+  (411, "ERR_NORECIPIENT", None),
+  (412, "ERR_NOTEXTTOSEND", None),
+  (417, "ERR_INPUTTOOLONG", None),
+  (421, "ERR_UNKNOWNCOMMAND", Some(1)),
+  (422, "ERR_NOMOTD", None),
+  (431, "ERR_NONICKNAMEGIVEN", None),
+  (432, "ERR_ERRONEUSNICKNAME", Some(1)),
+  (433, "ERR_NICKNAMEINUSE", Some(1)),
+  (436, "ERR_NICKCOLLISION", Some(1)),
+  (437, "ERR_UNAVAILRESOURCE", Some(1)),
+  (441, "ERR_USERNOTINCHANNEL", Some(1)),
+  (442, "ERR_NOTONCHANNEL", Some(1)),
+  (443, "ERR_USERONCHANNEL", Some(1)),
+  (451, "ERR_NOTREGISTERED", None),
+  (461, "ERR_NEEDMOREPARAMS", Some(1)),
+  (462, "ERR_ALREADYREGISTERED", None),
+  (464, "ERR_PASSWDMISMATCH", None),
+  (465, "ERR_YOUREBANNEDCREEP", None),
+  (471, "ERR_CHANNELISFULL", Some(1)),
+  (472, "ERR_UNKNOWNMODE", Some(1)),
+  (473, "ERR_INVITEONLYCHAN", Some(1)),
+  (474, "ERR_BANNEDFROMCHAN", Some(1)),
+  (475, "ERR_BADCHANNELKEY", Some(1)),
+  (476, "ERR_BADCHANMASK", Some(1)),
+  (477, "ERR_NEEDREGGEDNICK", Some(1)),
+  (478, "ERR_BANLISTFULL", Some(1)),
+  (481, "ERR_NOPRIVILEGES", None),
+  (482, "ERR_CHANOPRIVSNEEDED", Some(1)),
+  (483, "ERR_CANTKILLSERVER", None),
+  (491, "ERR_NOOPERHOST", None),
+  (501, "ERR_UMODEUNKNOWNFLAG", None),
+  (502, "ERR_USERSDONTMATCH", None),
+  (524, "ERR_HELPNOTFOUND", Some(1)),
+  (525, "ERR_INVALIDKEY", Some(1)),
+  (670, "RPL_STARTTLS", None),
+  (671, "RPL_WHOISSECURE", Some(1)),
+  (691, "ERR_STARTTLS", None),
+  (696, "ERR_INVALIDMODEPARAM", Some(1)),
+  (704, "RPL_HELPSTART", Some(1)),
+  (705, "RPL_HELPTXT", Some(1)),
+  (706, "RPL_ENDOFHELP", Some(1)),
+  (723, "ERR_NOPRIVS", Some(1)),
+  (730, "RPL_MONONLINE", None),
+  (731, "RPL_MONOFFLINE", None),
+  (732, "RPL_MONLIST", None),
+  (733, "RPL_ENDOFMONLIST", None),
+  (734, "ERR_MONLISTFULL", Some(2)),
+  (900, "RPL_LOGGEDIN", None),
+  (901, "RPL_LOGGEDOUT", None),
+  (902, "ERR_NICKLOCKED", None),
+  (903, "RPL_SASLSUCCESS", None),
+  (904, "ERR_SASLFAIL", None),
+  (905, "ERR_SASLTOOLONG", None),
+  (906, "ERR_SASLABORTED", None),
+  (907, "ERR_SASLALREADY", None),
+  (908, "RPL_SASLMECHS", None)
+];
+
+// the longest line we accept, 512 bytes of message plus 8191 of IRCv3 tags
+const MAX_LINE : usize = 512 + 8191;
 
-  pub host: &'a str,
+// how often to poll contacts with ISON on servers without MONITOR, in seconds
+const ISON_INTERVAL : i64 = 60;
+// how long to hold back joins waiting for NickServ to confirm we identified, in seconds
+const IDENTIFY_TIMEOUT : i64 = 30;
+// how long to wait before joining a channel we were kicked from, in seconds
+const REJOIN_DELAY : i64 = 5;
+// how long to wait before trying the next server, doubling after every full pass
+// through the server list up to RECONNECT_MAX, in seconds
+const RECONNECT_DELAY : i64 = 10;
+const RECONNECT_MAX : i64 = 300;
+
+// one of the servers making up a network
+#[derive(Clone)]
+pub struct Server {
+  pub host: String,
   pub port: u16,
-  
-  pub nick: &'a str,
-  pub ident: &'a str,
-  pub realname: &'a str,
+  pub tls: bool,
+  pub password: Option<String>
+}
 
-  pub channels: Vec<String>
+// an IRCv3 STS policy, only connect to host over TLS on port until expires
+#[derive(Clone)]
+pub struct Policy {
+  pub host: String,
+  pub port: u16,
+  pub expires: i64
 }
-impl<'a> Context<'a> {
-  pub fn connect(&mut self) -> Result<()> {
-    let s = format!("NICK {nick}\nUSER {user} 8 * : {realname}\n",
-                    nick=self.nick,
-                    user=self.ident,
-                    realname=self.realname);
-    self.sock.write_all(s.as_bytes()).chain_err(|| "TCP: write failure")?;
-    return Ok(());
+
+// an invite into a channel, for nick, which is us unless it came by invite-notify
+#[derive(Clone)]
+pub struct Invite {
+  // nick!user@host of whoever sent it
+  pub from: String,
+  pub nick: String,
+  pub channel: String,
+  pub at: i64
+}
+
+// what to do with a message an ignore rule matches, from mildest to harshest
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Ignoring {
+  // store and publish it, saying it's ignored
+  Mark,
+  // store it, but don't publish it or pass it on to IRC clients
+  Hide,
+  // forget it entirely
+  Drop
+}
+impl Ignoring {
+  pub fn name(&self) -> &'static str {
+    return match *self {
+      Ignoring::Mark => "mark",
+      Ignoring::Hide => "hide",
+      Ignoring::Drop => "drop"
+    };
   }
-  
-  pub fn privmsg(&mut self, r : &str, m : &str) -> Result<()> {
-    self.sock.write_all(format!("PRIVMSG {recvr} :{msg}\n",
-                       recvr=r,
-                       msg=m).as_bytes()).chain_err(|| "TCP: write failure")?;
-    return Ok(());
+  pub fn from_name(s: &str) -> Option<Ignoring> {
+    return match s {
+      "mark" => Some(Ignoring::Mark),
+      "hide" => Some(Ignoring::Hide),
+      "drop" => Some(Ignoring::Drop),
+      _ => None
+    };
   }
+}
 
-  pub fn join(&mut self, c : &str) -> Result<()> { 
-    self.sock.write_all(format!("JOIN {channel}\n", channel=c).as_bytes()).chain_err(|| "TCP: write failure")?;
-    self.channels.push(c.to_string());
-    return Ok(());
+// who an ignore rule is about
+#[derive(Clone)]
+pub enum Ignored {
+  // a nick!user@host mask with * and ? wildcards
+  Mask(String),
+  // a services account, as the server tells us with account-tag
+  Account(String),
+  // a regex against nick!user@host
+  Regex(Regex)
+}
+impl Ignored {
+  pub fn kind(&self) -> &'static str {
+    return match *self {
+      Ignored::Mask(_) => "mask",
+      Ignored::Account(_) => "account",
+      Ignored::Regex(_) => "regex"
+    };
+  }
+  pub fn pattern(&self) -> &str {
+    return match *self {
+      Ignored::Mask(ref m) => m,
+      Ignored::Account(ref a) => a,
+      Ignored::Regex(ref r) => r.as_str()
+    };
   }
+  // None for an unknown kind or a bad regex
+  pub fn new(kind: &str, pattern: &str) -> Option<Ignored> {
+    return match kind {
+      "mask" => Some(Ignored::Mask(pattern.to_string())),
+      "account" => Some(Ignored::Account(pattern.to_string())),
+      "regex" => Regex::new(pattern).ok().map(Ignored::Regex),
+      _ => None
+    };
+  }
+}
 
-  pub fn part(&mut self, c : &str, r: &str) -> Result<()> {
-    self.sock.write_all(format!("PART {channel} :{reason}\n",
-                       channel=c,
-                       reason=r).as_bytes()).chain_err(|| "TCP: write failure")?;
-    return Ok(());
+// a rule for ignoring someone, on one network or every network
+#[derive(Clone)]
+pub struct Ignore {
+  pub rule: i64,
+  pub network: Option<String>,
+  pub who: Ignored,
+  // commands and channels it's limited to, empty for any
+  pub commands: Vec<String>,
+  pub channels: Vec<String>,
+  pub action: Ignoring
+}
+impl Ignore {
+  pub fn matches(&self, irc: &Context, msg: &Message) -> bool {
+    let prefix = match msg.prefix {
+      Some(ref p @ Prefix::User(..)) => p,
+      _ => return false
+    };
+    if self.network.as_ref().map_or(false, |n| *n != irc.id) {
+      return false;
+    }
+    let command = match msg.command {
+      Command::Named(ref c) => c.to_ascii_uppercase(),
+      Command::Numeric(n) => format!("{:03}", n)
+    };
+    if !self.commands.is_empty() && !self.commands.iter().any(|c| c.eq_ignore_ascii_case(&command)) {
+      return false;
+    }
+    if !self.channels.is_empty() {
+      // whatever channels the message is addressed to
+      let chantypes = irc.isupport.get("CHANTYPES").map_or("#&", |c| &c[..]);
+      let targets = msg.params.get(0).map_or("", |t| &t[..]);
+      if !targets.split(',').filter(|t| t.starts_with(|c| chantypes.contains(c)))
+          .any(|t| self.channels.iter().any(|c| c.eq_ignore_ascii_case(t))) {
+        return false;
+      }
+    }
+    return match self.who {
+      Ignored::Mask(ref m) => User::from_message(msg).map_or(false, |u| irc.matches(m, &u)),
+      Ignored::Account(ref a) => msg.tag("account").map_or(false, |t| t.eq_ignore_ascii_case(a)),
+      Ignored::Regex(ref r) => r.is_match(&prefix.to_string())
+    };
   }
+}
 
-  // TODO use less write() calls
-  pub fn pong(&mut self, s : String) -> Result<()> {
-    self.sock.write_all("PONG :".as_bytes()).chain_err(|| "TCP: write failure")?;
-    self.sock.write_all(s.as_bytes()).chain_err(|| "TCP: write failure")?;
-    self.sock.write_all("\n".as_bytes()).chain_err(|| "TCP: write failure")?;
-    return Ok(());
+// the harshest thing any rule says to do with a message, None if it's not ignored
+pub fn ignoring(rules: &[Ignore], irc: &Context, msg: &Message) -> Option<Ignoring> {
+  return rules.iter().filter(|r| r.matches(irc, msg)).map(|r| r.action)
+    .fold(None, |worst, a| if worst.map_or(true, |w| a > w) { Some(a) } else { worst });
+}
+
+// what a highlight rule looks for
+#[derive(Clone)]
+pub enum Highlighted {
+  // our nick, whatever it is at the time; it always counts unless excluded
+  Nick,
+  // a word of its own, ignoring case
+  Word(String),
+  // a regex against the text
+  Regex(Regex),
+  // anything said by people matching a mask
+  Mask(String)
+}
+impl Highlighted {
+  pub fn kind(&self) -> &'static str {
+    return match *self {
+      Highlighted::Nick => "nick",
+      Highlighted::Word(_) => "word",
+      Highlighted::Regex(_) => "regex",
+      Highlighted::Mask(_) => "mask"
+    };
+  }
+  pub fn pattern(&self) -> &str {
+    return match *self {
+      Highlighted::Nick => "",
+      Highlighted::Word(ref w) => w,
+      Highlighted::Regex(ref r) => r.as_str(),
+      Highlighted::Mask(ref m) => m
+    };
+  }
+  // None for an unknown kind, a bad regex, or a missing pattern
+  pub fn new(kind: &str, pattern: &str) -> Option<Highlighted> {
+    return match kind {
+      "nick" => Some(Highlighted::Nick),
+      _ if pattern.is_empty() => None,
+      "word" => Some(Highlighted::Word(pattern.to_string())),
+      "regex" => Regex::new(pattern).ok().map(Highlighted::Regex),
+      "mask" => Some(Highlighted::Mask(pattern.to_string())),
+      _ => None
+    };
   }
 }
 
-pub fn lookup<'a,'b>(id: &'a str, ctxs: &Vec<Context<'b>>) -> usize {
-  for i in 0..ctxs.len() {
-    if id == ctxs[i].id {
-      return i;
+// a rule for what mentions us, on one network or every network
+#[derive(Clone)]
+pub struct Highlight {
+  pub rule: i64,
+  pub network: Option<String>,
+  pub what: Highlighted,
+  // channels it's limited to, empty for any, including private messages
+  pub channels: Vec<String>,
+  // whether messages it matches are never highlights, eg our nick in a busy channel
+  pub exclude: bool
+}
+impl Highlight {
+  fn applies(&self, irc: &Context, target: &str) -> bool {
+    return self.network.as_ref().map_or(true, |n| *n == irc.id) &&
+      (self.channels.is_empty() || self.channels.iter().any(|c| irc.casemapping().eq(c, target)));
+  }
+
+  fn matches(&self, irc: &Context, msg: &Message, text: &str) -> bool {
+    return match self.what {
+      Highlighted::Nick => mentions(text, &irc.me),
+      Highlighted::Word(ref w) => mentions(text, w),
+      Highlighted::Regex(ref r) => r.is_match(text),
+      Highlighted::Mask(ref m) => User::from_message(msg).map_or(false, |u| irc.matches(m, &u))
+    };
+  }
+}
+
+// whether someone else's message or notice mentions us: our nick or any other rule,
+// unless an exclusion says otherwise
+pub fn highlighted(rules: &[Highlight], irc: &Context, msg: &Message) -> bool {
+  match (&msg.prefix, &msg.command) {
+    (&Some(Prefix::User(nick, ..)), &Command::Named(ref c)) if !irc.casemapping().eq(nick, &irc.me) &&
+      (c.eq_ignore_ascii_case("PRIVMSG") || c.eq_ignore_ascii_case("NOTICE")) => (),
+    _ => return false
+  }
+  let (target, text) = match (msg.params.get(0), msg.params.get(1)) {
+    (Some(t), Some(x)) => (*t, *x),
+    _ => return false
+  };
+  // the text of a /me, but no other CTCP
+  let text = if text.starts_with("\x01ACTION ") {
+    text[8..].trim_end_matches('\x01')
+  } else if text.starts_with('\x01') {
+    return false;
+  } else {
+    text
+  };
+  let rules : Vec<&Highlight> = rules.iter().filter(|r| r.applies(irc, target)).collect();
+  if rules.iter().any(|r| r.exclude && r.matches(irc, msg, text)) {
+    return false;
+  }
+  return mentions(text, &irc.me) || rules.iter().any(|r| !r.exclude && r.matches(irc, msg, text));
+}
+
+// a conversation messages belong to
+#[derive(Clone, PartialEq)]
+pub enum Buffer {
+  // numerics, server notices, and whatever else isn't part of a conversation
+  Server,
+  Channel(String),
+  // a private conversation, named after the other party
+  Query(String)
+}
+impl Buffer {
+  pub fn kind(&self) -> &'static str {
+    return match *self {
+      Buffer::Server => "server",
+      Buffer::Channel(_) => "channel",
+      Buffer::Query(_) => "query"
+    };
+  }
+  // empty for the server buffer
+  pub fn name(&self) -> &str {
+    return match *self {
+      Buffer::Server => "",
+      Buffer::Channel(ref c) => c,
+      Buffer::Query(ref n) => n
+    };
+  }
+}
+
+// someone typing in a buffer, from their +typing tags
+#[derive(Clone)]
+pub struct Typing {
+  pub nick: String,
+  pub buffer: Buffer,
+  // active, paused, or done once they stop or it expires
+  pub state: &'static str,
+  expires: i64
+}
+
+// a netsplit between two servers, or the netjoin when they link up again
+#[derive(Clone)]
+pub struct Split {
+  pub servers: (String, String),
+  pub nicks: Vec<String>,
+  pub channels: Vec<String>,
+  pub at: i64,
+  // the last quit or join that was part of it
+  last: i64
+}
+impl Split {
+  fn new(servers: (String, String), now: i64) -> Split {
+    return Split {
+      servers: servers,
+      nicks: Vec::new(),
+      channels: Vec::new(),
+      at: now,
+      last: now
+    };
+  }
+
+  fn has(&self, nick: &str) -> bool {
+    return self.nicks.iter().any(|n| n.eq_ignore_ascii_case(nick));
+  }
+
+  // one more user leaving or coming back, from these channels
+  fn add(&mut self, nick: &str, channels: Vec<String>, now: i64) {
+    if !self.has(nick) {
+      self.nicks.push(nick.to_string());
+    }
+    for c in channels {
+      if !self.channels.iter().any(|o| o.eq_ignore_ascii_case(&c)) {
+        self.channels.push(c);
+      }
     }
+    self.last = now;
   }
+}
 
-  panic!("context not found\n");
+// a host with its most specific part wildcarded: the last octet of an IPv4 address, all
+// but the first four groups of an IPv6 one, or the first label of a name; cloaks like
+// user/alice are left alone, since they're already as broad as they go
+fn domain(host: &str) -> String {
+  if host.contains('/') {
+    return host.to_string();
+  }
+  if host.contains(':') {
+    let groups : Vec<&str> = host.split(':').take(4).collect();
+    return format!("{}:*", groups.join(":"));
+  }
+  if host.split('.').count() == 4 && host.split('.').all(|o| o.parse::<u8>().is_ok()) {
+    return format!("{}.*", &host[..host.rfind('.').unwrap()]);
+  }
+  return match host.find('.') {
+    Some(i) if host[i+1..].contains('.') => format!("*{}", &host[i..]),
+    _ => host.to_string()
+  };
 }
 
-pub fn readline(sock : & mut TcpStream) -> String {
-  let mut buf = String::new();
-  {
-    let mut reader = BufReader::new(sock);
-    reader.read_line(&mut buf);
-    print!("{}", buf);
+// the two servers in a QUIT reason, if it's the kind a netsplit gives everyone on the far
+// side, eg "hub.example.net leaf.example.net"; users' own reasons can't look like this
+// since servers put "Quit: " in front of them
+pub fn netsplit(reason: &str) -> Option<(String, String)> {
+  let server = |s: &str| s.contains('.') && !s.starts_with('.') && !s.ends_with('.') &&
+    s.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '*');
+  let mut parts = reason.split(' ');
+  return match (parts.next(), parts.next(), parts.next()) {
+    (Some(a), Some(b), None) if server(a) && server(b) => Some((a.to_string(), b.to_string())),
+    _ => None
   };
+}
 
-  return buf;
+// how to identify to NickServ, and get our nick back when someone else has it
+#[derive(Clone)]
+pub struct Services {
+  pub account: String,
+  pub password: String,
+  // "GHOST", "RECOVER", or "REGAIN", whichever the network's services understand
+  pub regain: String,
+  // hold back joins until NickServ confirms we identified
+  pub wait: bool
 }
 
-// taken from https://github.com/Detegr/RBot-parser , i had to modify it slightly
-// to get it to work correctly, so it is here instead of used a dependency
-// TODO: file pull request/fork
-// also TODO: port this to latest version of nom
-pub mod parse {
-  use std;
-  use nom::*;
-  use std::str::from_utf8;
-  use nom::space;
-  use nom::IResult::*;
-  use std::str::FromStr;
+// what to ask ChanServ for in a channel
+#[derive(Clone)]
+pub struct ChanServ {
+  pub channel: String,
+  // ask for op whenever we join
+  pub op: bool,
+  // ask for an invite when the channel is full, invite only, or keyed
+  pub invite: bool,
+  // ask to be unbanned when we're banned
+  pub unban: bool
+}
 
-  named!(nick_parser <&[u8], &str>, map_res!(chain!(nick: take_until!("!") ~ tag!("!"), ||{nick}), from_utf8));
-  named!(user_parser <&[u8], &str>, map_res!(chain!(user: take_until!("@") ~ tag!("@"), ||{user}), from_utf8));
-  named!(word_parser <&[u8], &str>, map_res!(take_until!(" "), from_utf8));
-  named!(eol <&[u8], &str>, map_res!(take_until_and_consume!("\r"), from_utf8));
+// things the daemon should tell its clients about, beyond the raw message
+pub enum Event {
+  // a contact came online (true) or went offline (false)
+  Presence(String, bool),
+  // a private message or highlight arrived while we were away, with its id
+  Missed(i64),
+  // we joined or left a channel
+  Channels,
+  // we connected to or lost a server
+  Connection,
+  // a query finished, or failed with the server's reason
+  Answer(::std::result::Result<Answer, String>),
+  // a server set an STS policy for its host, or removed it
+  Policy(String, Option<Policy>),
+  // somebody was invited into a channel, and whether we joined because it was us and
+  // the inviter is trusted
+  Invite(Invite, bool),
+  // a netsplit, or the netjoin after one, has gone quiet
+  Netsplit(Split),
+  Netjoin(Split),
+  // another client on our account read a buffer up to a timestamp=, with draft/read-marker
+  Read(String, String),
+  // someone started, paused or stopped typing
+  Typing(Typing)
+}
 
-  #[derive(Debug)]
-  pub struct ParserError {
-    data: String
+// capabilities we ask for whenever the server offers them
+const CAPS : &'static [&'static str] = &["account-tag", "invite-notify", "draft/read-marker", "message-tags"];
+
+// how long to wait for the last reply to a query, in seconds
+const QUERY_TIMEOUT : i64 = 30;
+// how long a netsplit or netjoin has to go quiet before we sum it up, in seconds
+const SPLIT_QUIET : i64 = 5;
+// how long we wait for the users of a netsplit to come back, in seconds
+const SPLIT_MEMORY : i64 = 60 * 60;
+// how long someone's typing state lasts without being renewed, in seconds, see
+// https://ircv3.net/specs/client-tags/typing
+const TYPING_ACTIVE : i64 = 6;
+const TYPING_PAUSED : i64 = 30;
+
+// everything the server told us in reply to a query
+#[derive(Debug)]
+pub enum Answer {
+  Whois(Whois),
+  Who(Vec<Who>),
+  Names(Vec<String>),
+  List(Vec<Listed>),
+  Bans(Vec<Ban>)
+}
+
+#[derive(Default, Debug)]
+pub struct Whois {
+  pub nick: String,
+  pub user: Option<String>,
+  pub host: Option<String>,
+  pub realname: Option<String>,
+  pub server: Option<String>,
+  pub server_info: Option<String>,
+  // with their membership prefixes, eg @#channel
+  pub channels: Vec<String>,
+  // seconds idle, and when they connected
+  pub idle: Option<i64>,
+  pub signon: Option<i64>,
+  pub account: Option<String>,
+  pub away: Option<String>,
+  pub operator: bool,
+  pub secure: bool
+}
+
+#[derive(Debug)]
+pub struct Who {
+  pub channel: String,
+  pub user: String,
+  pub host: String,
+  pub server: String,
+  pub nick: String,
+  // H or G for here or gone, then * for opers and membership prefixes
+  pub flags: String,
+  pub hops: Option<u32>,
+  pub realname: String
+}
+
+#[derive(Debug)]
+pub struct Listed {
+  pub channel: String,
+  pub users: Option<u32>,
+  pub topic: String
+}
+
+#[derive(Debug)]
+pub struct Ban {
+  pub mask: String,
+  // who set it and when, if the server says
+  pub by: Option<String>,
+  pub at: Option<i64>
+}
+
+// a query waiting on its replies
+pub struct Query {
+  // the command we sent, to match errors like ERR_NEEDMOREPARAMS against
+  pub command: &'static str,
+  // what the replies are about, lowercase, empty for LIST without a mask
+  pub target: String,
+  // the network's, to match WHO masks with
+  pub casemapping: Casemapping,
+  pub sent: i64,
+  pub answer: Answer
+}
+impl Query {
+  // whether a numeric is a reply to this query, and whether it's the last one
+  fn wants(&self, n: u16, params: &[&str]) -> (bool, bool) {
+    let about = |i: usize| params.get(i).map_or(false, |p| p.to_ascii_lowercase() == self.target);
+    return match (&self.answer, n) {
+      (&Answer::Whois(_), 318) => (about(1), true),
+      (&Answer::Whois(_), 276) | (&Answer::Whois(_), 301) | (&Answer::Whois(_), 307) |
+      (&Answer::Whois(_), 311) | (&Answer::Whois(_), 312) | (&Answer::Whois(_), 313) |
+      (&Answer::Whois(_), 317) | (&Answer::Whois(_), 319) | (&Answer::Whois(_), 320) |
+      (&Answer::Whois(_), 330) | (&Answer::Whois(_), 338) | (&Answer::Whois(_), 378) |
+      (&Answer::Whois(_), 379) | (&Answer::Whois(_), 671) => (about(1), false),
+      // a WHO reply about a mask names one of the user's channels instead, so check
+      // the mask against the user the way the server did
+      (&Answer::Who(_), 352) if params.len() > 7 => {
+        let m = |s: &str| wildcard(&self.target, s, self.casemapping);
+        let realname = params[7].splitn(2, ' ').nth(1).unwrap_or("");
+        let user = format!("{}!{}@{}", params[5], params[2], params[3]);
+        (about(1) || m(params[5]) || m(&user) || m(params[3]) || m(params[4]) || m(realname), false)
+      },
+      (&Answer::Who(_), 315) => (about(1), true),
+      (&Answer::Names(_), 353) => (about(2), false),
+      (&Answer::Names(_), 366) => (about(1), true),
+      (&Answer::List(_), 321) | (&Answer::List(_), 322) => (true, false),
+      (&Answer::List(_), 323) => (true, true),
+      (&Answer::Bans(_), 367) => (about(1), false),
+      (&Answer::Bans(_), 368) => (about(1), true),
+      // an error about what we asked, or about the command itself, is the end of it
+      _ => match reply(n) {
+        Some(r) if r.error() => {
+          let e = about(1) || params.get(1).map_or(false, |p| p.eq_ignore_ascii_case(self.command));
+          (e, e)
+        },
+        _ => (false, false)
+      }
+    };
   }
-  impl std::fmt::Display for ParserError {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-      write!(fmt, "{}", self.data)
+
+  fn collect(&mut self, n: u16, p: &[&str]) {
+    let get = |i: usize| p.get(i).map(|s| s.to_string());
+    match (&mut self.answer, n) {
+      (&mut Answer::Whois(ref mut w), _) => {
+        if let Some(nick) = p.get(1) {
+          w.nick = nick.to_string();
+        }
+        match n {
+          301 => w.away = get(2),
+          311 => {
+            w.user = get(2);
+            w.host = get(3);
+            w.realname = get(5);
+          },
+          312 => {
+            w.server = get(2);
+            w.server_info = get(3);
+          },
+          313 => w.operator = true,
+          317 => {
+            w.idle = p.get(2).and_then(|i| i.parse().ok());
+            w.signon = p.get(3).and_then(|i| i.parse().ok());
+          },
+          319 => w.channels.extend(p.get(2).map_or("", |c| *c).split_whitespace().map(|c| c.to_string())),
+          330 => w.account = get(2),
+          671 => w.secure = true,
+          _ => ()
+        }
+      },
+      (&mut Answer::Who(ref mut who), 352) if p.len() > 7 => {
+        // the trailing param is the hop count and the realname
+        let mut last = p[7].splitn(2, ' ');
+        who.push(Who {
+          channel: p[1].to_string(),
+          user: p[2].to_string(),
+          host: p[3].to_string(),
+          server: p[4].to_string(),
+          nick: p[5].to_string(),
+          flags: p[6].to_string(),
+          hops: last.next().and_then(|h| h.parse().ok()),
+          realname: last.next().unwrap_or("").to_string()
+        });
+      },
+      (&mut Answer::Names(ref mut names), 353) => {
+        names.extend(p.get(3).map_or("", |n| *n).split_whitespace().map(|n| n.to_string()));
+      },
+      (&mut Answer::List(ref mut list), 322) if p.len() > 2 => {
+        list.push(Listed {
+          channel: p[1].to_string(),
+          users: p[2].parse().ok(),
+          topic: p.get(3).map_or("", |t| *t).to_string()
+        });
+      },
+      (&mut Answer::Bans(ref mut bans), 367) if p.len() > 2 => {
+        bans.push(Ban {
+          mask: p[2].to_string(),
+          by: get(3),
+          at: p.get(4).and_then(|t| t.parse().ok())
+        });
+      },
+      _ => ()
     }
   }
-  impl std::error::Error for ParserError {
-    fn description(&self) -> &str {
-      &self.data
+}
+
+// a channel we join whenever we connect
+#[derive(Clone)]
+pub struct Channel {
+  pub name: String,
+  pub key: Option<String>,
+  // join again after being kicked
+  pub rejoin: bool
+}
+
+// what we know about a channel we're in
+#[derive(Default, Clone)]
+pub struct Joined {
+  pub topic: Option<String>,
+  // who set the topic and when, if the server said
+  pub topic_by: Option<String>,
+  pub topic_at: Option<i64>,
+  // everyone in the channel with their membership prefixes, highest first
+  pub members: Vec<(String, String)>,
+  // RPL_NAMREPLY comes in parts, gathered here until RPL_ENDOFNAMES
+  names: Vec<(String, String)>
+}
+impl Joined {
+  // each member with their highest prefix, the way NAMES shows them without multi-prefix
+  pub fn names(&self) -> Vec<String> {
+    return self.members.iter().map(|&(ref p, ref n)| format!("{}{}", p.chars().take(1).collect::<String>(), n)).collect();
+  }
+}
+
+pub struct Context {
+  // None while we aren't connected
+  pub sock: Option<TCP::Stream>,
+  // the connection on its way up while sock is None, see TCP::Dial, and the server it's
+  // to, which a client may edit or remove from servers in the meantime
+  dial: Option<(TCP::Dial, Server)>,
+  lines: TCP::Lines,
+  pub id: String,
+
+  // tried in order, moving on to the next whenever one fails
+  pub servers: Vec<Server>,
+  // index into servers of the one we're on, or trying next
+  pub server: usize,
+  pub options: TCP::Options,
+  // whether to stay connected at all
+  pub enabled: bool,
+  // connection attempts since we last registered successfully
+  pub attempts: usize,
+  pub reconnect_at: i64,
+  // STS policies for every host we know of, see Policy
+  pub policies: Vec<Policy>,
+  // the port we're connected to if it's over TLS, None over plaintext
+  secure: Option<u16>,
+  // the TLS port a plaintext server's STS policy sent us to, for the next attempt
+  upgrade: Option<u16>,
+  // what the server offered in CAP LS so far, and what it agreed to let us have
+  caps: Vec<String>,
+  acked: Vec<String>,
+  
+  pub nick: String,
+  pub ident: String,
+  pub realname: String,
+  // the nick we actually have, which isn't nick if that was taken
+  pub me: String,
+
+  // channels we're in right now, and what we know about each keyed by lowercase name
+  pub channels: Vec<String>,
+  pub joined: HashMap<String, Joined>,
+  // user@host as the server sees us, once we've joined something
+  pub userhost: Option<String>,
+  pub autojoin: Vec<Channel>,
+  // channels we were kicked from, and when to join them again
+  pub kicked: Vec<(String, i64)>,
+
+  // RPL_ISUPPORT tokens, value is empty for tokens without one
+  pub isupport: HashMap<String, String>,
+  pub registered: bool,
+
+  // nicks we want presence notifications for, and which of them are online
+  pub contacts: Vec<String>,
+  pub online: Vec<String>,
+  pub last_ison: i64,
+  // the nicks of each ISON we're waiting on a reply to, oldest first
+  pub ison: Vec<Vec<String>>,
+
+  pub away: Option<String>,
+  // whether we went away for lack of clients, rather than because a client asked
+  pub away_auto: bool,
+
+  pub services: Option<Services>,
+  pub chanserv: Vec<ChanServ>,
+  pub identified: bool,
+  // when we sent IDENTIFY, 0 if we haven't
+  pub identify_sent: i64,
+  // whether we asked NickServ to free up our nick
+  pub regaining: bool,
+  // joins held back until we're identified, with their keys
+  pub deferred: Vec<(String, Option<String>)>,
+  // channels we asked ChanServ to let us into, to join again once it has
+  pub retry: Vec<String>,
+  // invites for us nobody has accepted or declined yet, cleared when we disconnect
+  pub invites: Vec<Invite>,
+  // masks of people whose invites we take straight away
+  pub trusted: Vec<String>,
+  // netsplits still collecting quits, then those waiting for their users to come back,
+  // and netjoins still collecting joins
+  splitting: Vec<Split>,
+  pub split: Vec<Split>,
+  joining: Vec<Split>,
+  // who's typing where right now
+  typing: Vec<Typing>,
+
+  // queries we sent, in the order we sent them
+  pub queries: Vec<Query>
+}
+impl Context {
+  pub fn new(id: String,
+             nick: String,
+             ident: String,
+             realname: String) -> Context {
+    return Context {
+      sock: None,
+      dial: None,
+      lines: TCP::Lines::default(),
+      id: id,
+      servers: Vec::new(),
+      server: 0,
+      options: TCP::Options::default(),
+      enabled: true,
+      attempts: 0,
+      reconnect_at: 0,
+      policies: Vec::new(),
+      secure: None,
+      upgrade: None,
+      caps: Vec::new(),
+      acked: Vec::new(),
+      me: nick.clone(),
+      nick: nick,
+      ident: ident,
+      realname: realname,
+      channels: Vec::new(),
+      joined: HashMap::new(),
+      userhost: None,
+      autojoin: Vec::new(),
+      kicked: Vec::new(),
+      isupport: HashMap::new(),
+      registered: false,
+      contacts: Vec::new(),
+      online: Vec::new(),
+      last_ison: 0,
+      ison: Vec::new(),
+      away: None,
+      away_auto: false,
+      services: None,
+      chanserv: Vec::new(),
+      identified: false,
+      identify_sent: 0,
+      regaining: false,
+      deferred: Vec::new(),
+      retry: Vec::new(),
+      invites: Vec::new(),
+      trusted: Vec::new(),
+      splitting: Vec::new(),
+      split: Vec::new(),
+      joining: Vec::new(),
+      typing: Vec::new(),
+      queries: Vec::new()
+    };
+  }
+
+  // start connecting to the current server, tick() takes it from there
+  pub fn connect(&mut self, now: i64) -> Vec<Event> {
+    if self.servers.is_empty() {
+      return Vec::new();
+    }
+    if self.server >= self.servers.len() {
+      self.server = 0;
+    }
+    self.attempts += 1;
+    let mut server = self.servers[self.server].clone();
+    // a host with an STS policy only gets TLS, on the port the policy names
+    let port = self.upgrade.take().or(self.policy(&server.host, now).map(|p| p.port));
+    if let (Some(port), false) = (port, server.tls) {
+      server.tls = true;
+      server.port = port;
+    }
+    self.secure = if server.tls { Some(server.port) } else { None };
+    match TCP::dial(&server.host, server.port, server.tls, &self.options, now) {
+      Ok(d) => self.dial = Some((d, server)),
+      Err(e) => self.failed(&server, e, now)
     }
+    return Vec::new();
   }
-  impl<'a> From<Err<&'a [u8]>> for ParserError {
-    fn from(e: Err<&'a [u8]>) -> ParserError {
-      ParserError {
-        data: format!("Error: {:?}", e)
+
+  pub fn dialing(&self) -> bool {
+    return self.dial.is_some();
+  }
+
+  // our end of the connection and theirs, while it's still on its way up too
+  pub fn addrs(&self) -> Option<(SocketAddr, SocketAddr)> {
+    return match self.dial {
+      Some((ref d, _)) => d.addrs(),
+      None => self.sock.as_ref().and_then(|s| s.addrs())
+    };
+  }
+
+  // move a connection on its way up along, and register once it's there
+  fn dialed(&mut self, now: i64) -> Vec<Event> {
+    let r = match self.dial {
+      Some((ref mut d, _)) => d.advance(now),
+      None => return Vec::new()
+    };
+    let sock = match r {
+      Ok(None) => return Vec::new(),
+      Ok(Some(sock)) => Ok(sock),
+      Err(e) => Err(e)
+    };
+    let (_, server) = self.dial.take().unwrap();
+    return match sock.and_then(|sock| self.open(sock, &server)) {
+      Ok(()) => vec![Event::Connection],
+      Err(e) => {
+        self.failed(&server, e, now);
+        Vec::new()
       }
+    };
+  }
+
+  // give up on a server, and try the next one on a later tick, so that one that's
+  // down never holds up the rest of the list or anything else
+  fn failed(&mut self, server: &Server, e: Error, now: i64) {
+    eprintln!("Warning: {}: connecting to {}:{} failed: {}", self.id, server.host, server.port, e);
+    self.dial = None;
+    self.sock = None;
+    self.next_server(now);
+  }
+
+  fn open(&mut self, sock: TCP::Stream, server: &Server) -> Result<()> {
+    self.sock = Some(sock);
+    self.lines.clear();
+    self.caps.clear();
+    self.acked.clear();
+    // servers that don't know CAP just ignore it
+    self.raw("CAP LS 302")?;
+    if let Some(ref p) = server.password {
+      self.raw(&format!("PASS {}", p))?;
+    }
+    let s = format!("NICK {nick}\r\nUSER {user} 8 * : {realname}",
+                    nick=self.nick,
+                    user=self.ident,
+                    realname=self.realname);
+    self.raw(&s)?;
+    return Ok(());
+  }
+
+  // the connection is gone, forget everything that only made sense while we had it
+  pub fn disconnect(&mut self, now: i64) -> Vec<Event> {
+    if self.sock.is_none() {
+      return Vec::new();
+    }
+    self.sock = None;
+    self.registered = false;
+    self.me = self.nick.to_string();
+    self.channels.clear();
+    self.joined.clear();
+    self.userhost = None;
+    self.kicked.clear();
+    self.isupport.clear();
+    self.ison.clear();
+    self.identified = false;
+    self.identify_sent = 0;
+    self.regaining = false;
+    self.deferred.clear();
+    self.retry.clear();
+    self.invites.clear();
+    self.splitting.clear();
+    self.split.clear();
+    self.joining.clear();
+    self.typing.clear();
+    self.next_server(now);
+    // we can't tell anymore, so nobody is online
+    let mut events : Vec<Event> = self.online.drain(..)
+      .map(|n| Event::Presence(n, false))
+      .collect();
+    events.extend(self.queries.drain(..).map(|_| Event::Answer(Err("disconnected".to_string()))));
+    events.push(Event::Connection);
+    return events;
+  }
+
+  // join a channel we were invited into, false if there's no such invite
+  pub fn invite_accept(&mut self, channel: &str) -> Result<bool> {
+    if !self.invite_decline(channel) {
+      return Ok(false);
     }
+    self.join_many(vec![(channel.to_string(), None)])?;
+    return Ok(true);
   }
 
-  named!(message_parser(&[u8]) -> (Option<super::Prefix>, super::Command, Vec<&str>),
-         chain!(
-           parsed_prefix: prefix_parser? ~
-             parsed_command: command_parser ~
-             parsed_params: map_res!(take_until_and_consume!(":"), from_utf8)? ~
-             parsed_trailing: eol,
-           || {
-             let params = match parsed_params {
-               Some(p) => {
-                 let _: &str = p; // TODO: This looks stupid. How should this be done?
-                 p.split_whitespace()
-                   .chain(std::iter::repeat(parsed_trailing).take(1))
-                   .collect()
-               },
-               None => parsed_trailing.split_whitespace().collect()
-             };
-             (
-               parsed_prefix,
-               parsed_command,
-               params
-             )
-             })
-  );
-
-  named!(command_parser <&[u8], super::Command>,
-         chain!(
-           cmd: word_parser,
-           || {
-             match FromStr::from_str(cmd) {
-               Ok(numericcmd) => super::Command::Numeric(numericcmd),
-               Err(_) => super::Command::Named(cmd.into())
-             }
-           }
-         )
-  );
-  named!(prefix_parser <&[u8], super::Prefix>,
-         chain!(
-           tag!(":") ~
-             r: alt!(chain!(
-               prefix: host_parser,
-               || {
-                 let (nick, user, host) = prefix;
-                 super::Prefix::User(nick, user, host)
-               }) |
-                     chain!(
-                       host: word_parser,
-                       || {
-                         super::Prefix::Server(host)
-                       }
-                     )
-             ) ~
-             space,
-           || {r}));
-
-  named!(host_parser <&[u8], (&str, &str, &str)>,
-         chain!(
-           nick: nick_parser ~
-             user: user_parser ~
-             host: word_parser ,
-           ||{(nick, user, host)}
-         )
-  );
+  // forget an invite, false if there's no such invite; the inviter isn't told
+  pub fn invite_decline(&mut self, channel: &str) -> bool {
+    let before = self.invites.len();
+    self.invites.retain(|i| !i.channel.eq_ignore_ascii_case(channel));
+    return self.invites.len() != before;
+  }
 
-  pub fn parse_message<'a>(server: &'a str, id: i64, input: &'a str) -> Result<super::Message<'a>, ParserError> {
-    match message_parser(input.as_bytes()) {
-      Done(_, msg) => {
-        let (parsed_prefix,
-             parsed_command,
-             params) = msg;
-        Ok(super::Message {
-          server: server,
-          id: id,
-          prefix: parsed_prefix,
-          command: parsed_command,
-          params: params
-        })
-      },
-      Incomplete(i) => Err(ParserError {
-        data: format!("Incomplete: {:?}", i)
-      }),
-      Error(e) => Err(From::from(e))
+  // the channels we're in that nick is in too
+  pub fn shared(&self, nick: &str) -> Vec<String> {
+    let cm = self.casemapping();
+    return self.channels.iter()
+      .filter(|c| self.joined.get(&c.to_ascii_lowercase()).map_or(false, |j| j.members.iter().any(|m| cm.eq(&m.1, nick))))
+      .cloned()
+      .collect();
+  }
+
+  // the buffers a message belongs to, which has to be worked out before handle() forgets
+  // who was in which channel
+  pub fn buffers(&self, msg: &Message) -> Vec<Buffer> {
+    let command = match msg.command {
+      Command::Named(ref c) => c.to_ascii_uppercase(),
+      Command::Numeric(_) => return vec![Buffer::Server]
+    };
+    let nick = match msg.prefix {
+      Some(Prefix::User(nick, ..)) => Some(nick),
+      _ => None
+    };
+    let chantypes = self.isupport.get("CHANTYPES").map_or("#&", |c| &c[..]);
+    let statusmsg = self.isupport.get("STATUSMSG").map_or("", |s| &s[..]);
+    let target = msg.params.get(0).map_or("", |t| *t);
+    // a list of channels, each without any STATUSMSG prefix like the @ of @#ochat
+    let channels = |t: &str| -> Vec<Buffer> {
+      t.split(',').map(|c| c.trim_start_matches(|p| statusmsg.contains(p)))
+        .filter(|c| c.starts_with(|p| chantypes.contains(p)))
+        .map(|c| Buffer::Channel(c.to_string()))
+        .collect()
+    };
+    let ret = match (&command[..], nick) {
+      ("PRIVMSG", Some(nick)) | ("NOTICE", Some(nick)) | ("TAGMSG", Some(nick)) => {
+        let c = channels(target);
+        if !c.is_empty() {
+          c
+        } else if nick.eq_ignore_ascii_case(&self.me) {
+          // our own, echoed back
+          vec![Buffer::Query(target.to_string())]
+        } else if target.eq_ignore_ascii_case(&self.me) {
+          vec![Buffer::Query(nick.to_string())]
+        } else {
+          // eg a notice to $$*.example.org
+          Vec::new()
+        }
+      },
+      ("JOIN", _) | ("PART", _) | ("KICK", _) | ("TOPIC", _) | ("MODE", _) => channels(target),
+      ("QUIT", Some(nick)) | ("NICK", Some(nick)) | ("AWAY", Some(nick)) | ("CHGHOST", Some(nick)) |
+      ("ACCOUNT", Some(nick)) | ("SETNAME", Some(nick)) => {
+        if nick.eq_ignore_ascii_case(&self.me) {
+          self.channels.iter().map(|c| Buffer::Channel(c.clone())).collect()
+        } else {
+          self.shared(nick).into_iter().map(Buffer::Channel).collect()
+        }
+      },
+      _ => Vec::new()
+    };
+    if ret.is_empty() {
+      return vec![Buffer::Server];
+    }
+    return ret;
+  }
+
+  pub fn casemapping(&self) -> Casemapping {
+    return Casemapping::from_name(self.isupport.get("CASEMAPPING").map(|c| &c[..]));
+  }
+
+  // the character extbans start with, and the kinds the server has, eg ('$', "ajrxz")
+  pub fn extbans(&self) -> (char, String) {
+    return match self.isupport.get("EXTBAN") {
+      Some(e) => {
+        let mut parts = e.splitn(2, ',');
+        let prefix = parts.next().and_then(|p| p.chars().next()).unwrap_or('$');
+        (prefix, parts.next().unwrap_or("").to_string())
+      },
+      None => ('$', String::new())
+    };
+  }
+
+  // whether a ban or ignore mask covers someone, as nick!user@host or as an account or
+  // realname extban; other extbans can't be checked from here, so they never match
+  pub fn matches(&self, mask: &str, user: &User) -> bool {
+    let cm = self.casemapping();
+    let (prefix, _) = self.extbans();
+    if !mask.starts_with(prefix) {
+      return wildcard(mask, &user.mask(), cm);
     }
+    let rest = &mask[prefix.len_utf8()..];
+    let (negated, rest) = if rest.starts_with('~') { (true, &rest[1..]) } else { (false, rest) };
+    let mut parts = rest.splitn(2, ':');
+    let kind = parts.next().unwrap_or("");
+    let arg = parts.next();
+    let matched = match kind {
+      "a" | "account" => match (arg, user.account.as_ref()) {
+        (None, a) => a.is_some(),
+        (Some(pattern), Some(a)) => wildcard(pattern, a, cm),
+        (Some(_), None) => false
+      },
+      "r" | "realname" => match (arg, user.realname.as_ref()) {
+        (Some(pattern), Some(r)) => wildcard(pattern, r, Casemapping::Ascii),
+        _ => return false
+      },
+      _ => return false
+    };
+    return matched != negated;
   }
-}
\ No newline at end of file
+
+  // a ban mask of some shape for someone, None if we don't know enough about them
+  pub fn ban(&self, user: &User, shape: Shape) -> Option<String> {
+    return match shape {
+      Shape::Nick => Some(format!("{}!*@*", user.nick)),
+      Shape::Host => user.host.as_ref().map(|h| format!("*!*@{}", h)),
+      Shape::UserHost => match (user.user.as_ref(), user.host.as_ref()) {
+        (Some(u), Some(h)) => Some(format!("*!*{}@{}", u.trim_start_matches('~'), h)),
+        _ => None
+      },
+      Shape::Domain => user.host.as_ref().map(|h| format!("*!*@{}", domain(h))),
+      Shape::Account => {
+        let (prefix, kinds) = self.extbans();
+        match user.account {
+          Some(ref a) if kinds.is_empty() || kinds.contains('a') => Some(format!("{}a:{}", prefix, a)),
+          _ => None
+        }
+      }
+    };
+  }
+
+  // tell the server we've read a buffer up to a time, so its other clients know, if it
+  // has draft/read-marker; the server buffer has no name to tell it
+  pub fn mark_read(&mut self, target: &str, timestamp: &str) -> Result<()> {
+    if self.sock.is_none() || !self.acked("draft/read-marker") || target.is_empty() {
+      return Ok(());
+    }
+    return self.raw(&format!("MARKREAD {} timestamp={}", target, timestamp));
+  }
+
+  // the STS policy in force for a host, if any
+  pub fn policy(&self, host: &str, now: i64) -> Option<&Policy> {
+    return self.policies.iter().find(|p| p.host.eq_ignore_ascii_case(host) && p.expires > now);
+  }
+
+  // whether the server agreed to a capability we asked for
+  pub fn acked(&self, cap: &str) -> bool {
+    return self.acked.iter().any(|c| c == cap);
+  }
+
+  // what the server offers, asking for CAPS and looking for an STS policy
+  fn cap(&mut self, params: &[&str], now: i64) -> Result<Vec<Event>> {
+    let sub = params.get(1).map_or(String::new(), |s| s.to_ascii_uppercase());
+    let caps = params.last().map_or("", |c| *c).split_whitespace();
+    if sub == "ACK" {
+      // a - would mean we'd asked to turn it off, which we never do
+      self.acked.extend(caps.filter(|c| !c.starts_with('-')).map(|c| c.to_string()));
+      return Ok(Vec::new());
+    }
+    if sub == "DEL" {
+      for c in caps {
+        self.caps.retain(|o| o != c && !o.starts_with(&format!("{}=", c)));
+        self.acked.retain(|a| a != c);
+      }
+      return Ok(Vec::new());
+    }
+    if sub != "LS" && sub != "NEW" {
+      return Ok(Vec::new());
+    }
+    // a multiline LS has a * before all but its last line
+    let more = sub == "LS" && params.len() > 3 && params[2] == "*";
+    let offered = params.last().map_or("", |c| *c);
+    self.caps.extend(offered.split_whitespace().map(|c| c.to_string()));
+    if more {
+      return Ok(Vec::new());
+    }
+    let events = self.sts(now)?;
+    if sub == "LS" && self.sock.is_some() {
+      let wanted : Vec<&str> = CAPS.iter().cloned().filter(|c| self.caps.iter().any(|o| o == c || o.starts_with(&format!("{}=", c)))).collect();
+      if !wanted.is_empty() {
+        self.raw(&format!("CAP REQ :{}", wanted.join(" ")))?;
+      }
+      self.raw("CAP END")?;
+    }
+    return Ok(events);
+  }
+
+  // act on the sts capability, following https://ircv3.net/specs/extensions/sts
+  fn sts(&mut self, now: i64) -> Result<Vec<Event>> {
+    let value = match self.caps.iter().find(|c| *c == "sts" || c.starts_with("sts=")) {
+      Some(c) => c.splitn(2, '=').nth(1).unwrap_or("").to_string(),
+      None => return Ok(Vec::new())
+    };
+    let mut port = None;
+    let mut duration = None;
+    for kv in value.split(',') {
+      let mut kv = kv.splitn(2, '=');
+      match (kv.next(), kv.next()) {
+        (Some("port"), Some(p)) => port = p.parse::<u16>().ok(),
+        (Some("duration"), Some(d)) => duration = d.parse::<i64>().ok(),
+        _ => ()
+      }
+    }
+    let host = self.servers[self.server].host.clone();
+    let secure = match self.secure {
+      Some(p) => p,
+      // over plaintext the policy only says where to go instead, and we go straight there
+      None => {
+        let port = match port {
+          Some(p) => p,
+          None => return Ok(Vec::new())
+        };
+        let server = self.server;
+        self.raw("QUIT :Upgrading to TLS")?;
+        let events = self.disconnect(now);
+        self.server = server;
+        self.upgrade = Some(port);
+        self.reconnect_at = now;
+        return Ok(events);
+      }
+    };
+    self.policies.retain(|p| !p.host.eq_ignore_ascii_case(&host));
+    return Ok(match duration {
+      // a duration of 0 takes the policy away
+      Some(0) => vec![Event::Policy(host, None)],
+      Some(d) => {
+        let p = Policy {
+          host: host.clone(),
+          port: secure,
+          expires: now + d
+        };
+        self.policies.push(p.clone());
+        vec![Event::Policy(host, Some(p))]
+      },
+      None => Vec::new()
+    });
+  }
+
+  // schedule an attempt on the next server in the list
+  fn next_server(&mut self, now: i64) {
+    self.server = (self.server + 1) % self.servers.len().max(1);
+    let passes = (self.attempts / self.servers.len().max(1)) as u32;
+    let delay = RECONNECT_DELAY.checked_shl(passes).unwrap_or(RECONNECT_MAX).min(RECONNECT_MAX);
+    self.reconnect_at = now + delay;
+  }
+
+  // drop the connection on purpose, and reconnect according to enabled
+  pub fn quit(&mut self, reason: &str, now: i64) -> Result<Vec<Event>> {
+    // a connection still on its way up has nobody to say goodbye to, so just drop it
+    if self.dial.take().is_some() {
+      self.next_server(now);
+    }
+    self.raw(&format!("QUIT :{}", reason))?;
+    let events = self.disconnect(now);
+    // there was nothing wrong with the server, so don't make anyone wait for it
+    self.reconnect_at = now;
+    return Ok(events);
+  }
+
+  pub fn fd(&self) -> Option<RawFd> {
+    return match self.dial {
+      Some((ref d, _)) => d.fd(),
+      None => self.sock.as_ref().map(|s| s.as_raw_fd())
+    };
+  }
+  
+  // join several channels with as few lines as TARGMAX and the line limit allow
+  pub fn join_many(&mut self, mut chans: Vec<(String, Option<String>)>) -> Result<()> {
+    if self.holding() {
+      self.deferred.extend(chans);
+      return Ok(());
+    }
+    // keyed channels go first, so the keys line up with them
+    chans.sort_by_key(|&(_, ref k)| k.is_none());
+    let limit = self.targmax("JOIN");
+    let mut batch = Vec::new();
+    let mut len = 0;
+    for (c, k) in chans {
+      let n = c.len() + k.as_ref().map_or(0, |k| k.len()) + 2;
+      if !batch.is_empty() && (limit.map_or(false, |l| batch.len() >= l) || len + n > 400) {
+        self.send_join(&batch)?;
+        batch.clear();
+        len = 0;
+      }
+      len += n;
+      batch.push((c, k));
+    }
+    if !batch.is_empty() {
+      self.send_join(&batch)?;
+    }
+    return Ok(());
+  }
+
+  fn send_join(&mut self, batch: &[(String, Option<String>)]) -> Result<()> {
+    let chans : Vec<&str> = batch.iter().map(|&(ref c, _)| &c[..]).collect();
+    let keys : Vec<&str> = batch.iter().filter_map(|&(_, ref k)| k.as_ref().map(|k| &k[..])).collect();
+    let cmd = command::Typed::Join { channels: chans, keys: keys };
+    // these come from our own lists, so one bad name shouldn't stop everything else
+    if let Err(e) = cmd.line() {
+      eprintln!("Warning: {}: not joining {:?}: {}", self.id, batch, e);
+      return Ok(());
+    }
+    return self.send(&cmd);
+  }
+
+  // join everything on the autojoin list we aren't already in
+  pub fn autojoin(&mut self) -> Result<()> {
+    let chans = self.autojoin.iter()
+      .filter(|a| !self.channels.iter().any(|c| c.eq_ignore_ascii_case(&a.name)))
+      .map(|a| (a.name.clone(), a.key.clone()))
+      .collect();
+    return self.join_many(chans);
+  }
+
+  // the limit on targets for a command from TARGMAX, None if there isn't one
+  pub fn targmax(&self, command: &str) -> Option<usize> {
+    let targmax = match self.isupport.get("TARGMAX") {
+      Some(t) => t,
+      None => return None
+    };
+    for t in targmax.split(',') {
+      let mut kv = t.splitn(2, ':');
+      if kv.next().map_or(false, |k| k.eq_ignore_ascii_case(command)) {
+        return kv.next().and_then(|v| v.parse().ok());
+      }
+    }
+    return None;
+  }
+
+  fn key(&self, channel: &str) -> Option<String> {
+    return self.autojoin.iter()
+      .find(|a| a.name.eq_ignore_ascii_case(channel))
+      .and_then(|a| a.key.clone());
+  }
+
+  pub fn pong(&mut self, s : &str) -> Result<()> {
+    return self.send(&command::Typed::Pong { server: None, token: s });
+  }
+
+  // ask the server something whose many replies we collect into one Event::Answer,
+  // false if we aren't registered or the query makes no sense
+  pub fn query(&mut self, kind: &str, target: Option<&str>, now: i64) -> Result<bool> {
+    use self::command::Typed;
+    if !self.registered {
+      return Ok(false);
+    }
+    let (cmd, command, answer) = match (kind, target) {
+      // asking the nick's own server too gets us their idle time
+      ("whois", Some(t)) => (Typed::Other("WHOIS", vec![t, t]), "WHOIS", Answer::Whois(Whois::default())),
+      ("who", Some(t)) => (Typed::Other("WHO", vec![t]), "WHO", Answer::Who(Vec::new())),
+      ("names", Some(t)) => (Typed::Other("NAMES", vec![t]), "NAMES", Answer::Names(Vec::new())),
+      ("list", t) => (Typed::Other("LIST", t.into_iter().collect()), "LIST", Answer::List(Vec::new())),
+      ("bans", Some(t)) => (Typed::Mode { target: t, modes: Some("+b"), args: Vec::new() }, "MODE", Answer::Bans(Vec::new())),
+      _ => return Ok(false)
+    };
+    if cmd.line().is_err() {
+      return Ok(false);
+    }
+    self.send(&cmd)?;
+    self.queries.push(Query {
+      command: command,
+      target: target.unwrap_or("").to_ascii_lowercase(),
+      casemapping: self.casemapping(),
+      sent: now,
+      answer: answer
+    });
+    return Ok(true);
+  }
+
+  pub fn querying(&self) -> bool {
+    return !self.queries.is_empty();
+  }
+
+  // hand a numeric to the oldest query it belongs to, returning its answer when it's done
+  fn answer(&mut self, n: u16, params: &[&str]) -> Option<Event> {
+    let found = self.queries.iter().enumerate()
+      .map(|(i, q)| (i, q.wants(n, params)))
+      .find(|&(_, (wanted, _))| wanted);
+    let (i, last) = match found {
+      Some((i, (_, last))) => (i, last),
+      None => return None
+    };
+    if reply(n).map_or(false, |r| r.error()) {
+      self.queries.remove(i);
+      let reason = params.last().map_or("", |p| *p);
+      return Some(Event::Answer(Err(reason.to_string())));
+    }
+    self.queries[i].collect(n, params);
+    if last {
+      return Some(Event::Answer(Ok(self.queries.remove(i).answer)));
+    }
+    return None;
+  }
+
+  // anything that doesn't make a valid line is refused rather than sent
+  pub fn send(&mut self, cmd: &command::Typed) -> Result<()> {
+    let line = cmd.line().chain_err(|| "IRC: invalid command")?;
+    return self.raw(&line);
+  }
+
+  // lines sent while we aren't connected are dropped, and anything the socket won't
+  // take right away is written once poll says it's writable
+  pub fn raw(&mut self, line: &str) -> Result<()> {
+    if self.sock.is_some() {
+      self.lines.push(line);
+      self.flush();
+    }
+    return Ok(());
+  }
+
+  pub fn flush(&mut self) {
+    if let Some(ref mut sock) = self.sock {
+      self.lines.flush(sock, &self.id);
+    }
+  }
+
+  pub fn wants_write(&self) -> bool {
+    return match self.dial {
+      Some((ref d, _)) => d.wants_write(),
+      None => self.lines.pending()
+    };
+  }
+
+  // every complete line the server has sent so far, and whether we've lost it
+  pub fn read(&mut self) -> (Vec<String>, bool) {
+    return match self.sock {
+      Some(ref mut sock) => self.lines.read(sock, MAX_LINE, &self.id),
+      None => (Vec::new(), true)
+    };
+  }
+
+  // update our state from an incoming message
+  pub fn handle(&mut self, msg: &Message, now: i64) -> Result<Vec<Event>> {
+    use self::command::Typed;
+    let mut events = Vec::new();
+    let from = match msg.prefix {
+      Some(Prefix::User(nick, _, _)) => Some(nick),
+      _ => None
+    };
+    if let Command::Numeric(n) = msg.command {
+      events.extend(self.answer(n, &msg.params));
+    }
+    events.extend(self.typed(msg, now));
+    let typed = match Typed::from_message(msg) {
+      Ok(t) => t,
+      // nothing we keep track of can be learned from a malformed message
+      Err(e) => {
+        eprintln!("Warning: {}: ignoring message {}: {}", self.id, msg.id, e);
+        return Ok(events);
+      }
+    };
+    match typed {
+      Typed::Ping { token } => {
+        self.pong(token)?;
+      },
+      Typed::Other(c, ref params) if c.eq_ignore_ascii_case("CAP") => {
+        events.extend(self.cap(params, now)?);
+      },
+      // timestamp=* is a buffer nobody's read yet, which tells us nothing
+      Typed::Other(c, ref params) if c.eq_ignore_ascii_case("MARKREAD") && params.len() > 1 && params[1] != "timestamp=*" => {
+        events.push(Event::Read(params[0].to_string(), params[1].to_string()));
+      },
+      // the nick we ended up with
+      Typed::Welcome { nick, .. } => {
+        self.me = nick.to_string();
+        self.attempts = 0;
+      },
+      Typed::ISupport { tokens, .. } => {
+        for token in tokens {
+          if token.starts_with('-') {
+            self.isupport.remove(&token[1..]);
+          } else {
+            let mut kv = token.splitn(2, '=');
+            let k = kv.next().unwrap();
+            let v = kv.next().unwrap_or("");
+            self.isupport.insert(k.to_string(), v.to_string());
+          }
+        }
+      },
+      // end of MOTD, or no MOTD at all, means registration is done
+      Typed::EndOfMotd { .. } | Typed::NoMotd { .. } => {
+        self.registered = true;
+        self.watch()?;
+        if let Some(m) = self.away.clone() {
+          self.send(&Typed::Away { message: Some(&m[..]) })?;
+        }
+        if let Some(s) = self.services.clone() {
+          self.send(&Typed::Privmsg { target: "NickServ", text: &format!("IDENTIFY {} {}", s.account, s.password) })?;
+          self.identify_sent = now;
+          if self.me != self.nick {
+            self.send(&Typed::Privmsg { target: "NickServ", text: &format!("{} {} {}", s.regain, self.nick, s.password) })?;
+            self.regaining = true;
+          }
+        }
+        self.autojoin()?;
+      },
+      // during registration we have to pick something else
+      Typed::NicknameInUse { .. } => {
+        if !self.registered {
+          // NICKLEN usually isn't known yet, assume the nick we were given fits
+          let max = self.isupport.get("NICKLEN").and_then(|v| v.parse().ok())
+            .unwrap_or(cmp::max(self.nick.chars().count(), 9));
+          if let Some(n) = next_nick(&self.me, max) {
+            self.me = n.clone();
+            self.send(&Typed::Nick { nick: &n })?;
+          }
+        }
+      },
+      Typed::LoggedIn { .. } => {
+        self.identify()?;
+      },
+      Typed::ChannelIsFull { channel, .. } | Typed::InviteOnlyChan { channel, .. } |
+      Typed::BannedFromChan { channel, .. } | Typed::BadChannelKey { channel, .. } => {
+        let banned = msg.command == Command::Numeric(474);
+        let cs = self.chanserv.iter().find(|cs| cs.channel.eq_ignore_ascii_case(channel)).cloned();
+        let already = self.retry.iter().any(|r| r.eq_ignore_ascii_case(channel));
+        match cs {
+          // only ask once, if ChanServ couldn't help the first time it won't the second
+          Some(ref cs) if !already && banned && cs.unban => {
+            self.send(&Typed::Privmsg { target: "ChanServ", text: &format!("UNBAN {}", channel) })?;
+            self.retry.push(channel.to_string());
+          },
+          Some(ref cs) if !already && !banned && cs.invite => {
+            self.send(&Typed::Privmsg { target: "ChanServ", text: &format!("INVITE {}", channel) })?;
+            self.retry.push(channel.to_string());
+          },
+          _ => ()
+        }
+      },
+      Typed::Nick { nick } => {
+        if let Some(old) = from {
+          for j in self.joined.values_mut() {
+            for m in j.members.iter_mut().filter(|m| m.1.eq_ignore_ascii_case(old)) {
+              m.1 = nick.to_string();
+            }
+          }
+        }
+        if from.map_or(false, |n| n.eq_ignore_ascii_case(&self.me)) {
+          self.me = nick.to_string();
+          if self.me == self.nick {
+            self.regaining = false;
+          }
+        }
+      },
+      Typed::Join { channels, .. } => {
+        if from.map_or(false, |n| n.eq_ignore_ascii_case(&self.me)) {
+          if let Some(Prefix::User(_, Some(user), Some(host))) = msg.prefix {
+            self.userhost = Some(format!("{}@{}", user, host));
+          }
+          for channel in channels {
+            if !self.channels.iter().any(|c| c.eq_ignore_ascii_case(channel)) {
+              self.channels.push(channel.to_string());
+              self.joined.insert(channel.to_ascii_lowercase(), Joined::default());
+              events.push(Event::Channels);
+            }
+            self.retry.retain(|r| !r.eq_ignore_ascii_case(channel));
+            self.invites.retain(|i| !i.channel.eq_ignore_ascii_case(channel));
+            if self.chanserv.iter().any(|cs| cs.op && cs.channel.eq_ignore_ascii_case(channel)) {
+              self.send(&Typed::Privmsg { target: "ChanServ", text: &format!("OP {}", channel) })?;
+            }
+          }
+        } else if let Some(nick) = from {
+          for channel in channels {
+            if let Some(j) = self.joined.get_mut(&channel.to_ascii_lowercase()) {
+              if !j.members.iter().any(|m| m.1.eq_ignore_ascii_case(nick)) {
+                j.members.push((String::new(), nick.to_string()));
+              }
+            }
+            self.split_join(nick, channel, now);
+          }
+        }
+      },
+      Typed::Part { channels, .. } => {
+        if from.map_or(false, |n| n.eq_ignore_ascii_case(&self.me)) {
+          for channel in channels {
+            self.channels.retain(|c| !c.eq_ignore_ascii_case(channel));
+            self.joined.remove(&channel.to_ascii_lowercase());
+            events.push(Event::Channels);
+          }
+        } else if let Some(nick) = from {
+          for channel in channels {
+            self.gone(Some(channel), nick);
+          }
+        }
+      },
+      Typed::Kick { channel, nick, .. } => {
+        if nick.eq_ignore_ascii_case(&self.me) {
+          self.channels.retain(|c| !c.eq_ignore_ascii_case(channel));
+          self.joined.remove(&channel.to_ascii_lowercase());
+          events.push(Event::Channels);
+          if self.autojoin.iter().any(|a| a.rejoin && a.name.eq_ignore_ascii_case(channel)) {
+            self.kicked.push((channel.to_string(), now + REJOIN_DELAY));
+          }
+        } else {
+          self.gone(Some(channel), nick);
+        }
+      },
+      Typed::Quit { reason } => {
+        if let Some(nick) = from {
+          if let Some(servers) = reason.and_then(netsplit) {
+            self.split_quit(nick, servers, now);
+          }
+          self.gone(None, nick);
+        }
+      },
+      Typed::Mode { target, modes: Some(modes), args } => {
+        self.modes(target, modes, &args);
+      },
+      Typed::NoTopic { channel, .. } => {
+        if let Some(j) = self.joined.get_mut(&channel.to_ascii_lowercase()) {
+          j.topic = None;
+          j.topic_by = None;
+          j.topic_at = None;
+        }
+      },
+      Typed::TopicIs { channel, topic, .. } => {
+        if let Some(j) = self.joined.get_mut(&channel.to_ascii_lowercase()) {
+          j.topic = Some(topic.to_string());
+        }
+      },
+      Typed::TopicWhoTime { channel, by, at, .. } => {
+        if let Some(j) = self.joined.get_mut(&channel.to_ascii_lowercase()) {
+          j.topic_by = Some(by.to_string());
+          j.topic_at = Some(at);
+        }
+      },
+      Typed::Topic { channel, topic: Some(topic) } => {
+        if let Some(j) = self.joined.get_mut(&channel.to_ascii_lowercase()) {
+          j.topic = if topic.is_empty() { None } else { Some(topic.to_string()) };
+          j.topic_by = msg.prefix.as_ref().map(|p| p.to_string());
+          j.topic_at = Some(now);
+        }
+      },
+      Typed::NamReply { channel, names, .. } => {
+        let names : Vec<(String, String)> = names.iter().map(|n| self.split_prefix(n)).collect();
+        if let Some(j) = self.joined.get_mut(&channel.to_ascii_lowercase()) {
+          j.names.extend(names);
+        }
+      },
+      Typed::EndOfNames { channel, .. } => {
+        if let Some(j) = self.joined.get_mut(&channel.to_ascii_lowercase()) {
+          j.members = j.names.split_off(0);
+        }
+      },
+      // ChanServ invites us in response to INVITE
+      Typed::Invite { nick, channel } => {
+        if from.map_or(false, |n| n.eq_ignore_ascii_case("ChanServ")) {
+          self.rejoin(channel)?;
+        }
+        let invite = Invite {
+          from: msg.prefix.as_ref().map_or(String::new(), |p| p.to_string()),
+          nick: nick.to_string(),
+          channel: channel.to_string(),
+          at: now
+        };
+        // anyone else is only invite-notify telling us about our channel
+        if !nick.eq_ignore_ascii_case(&self.me) {
+          events.push(Event::Invite(invite, false));
+        } else if User::from_message(msg).map_or(false, |u| self.trusted.iter().any(|m| self.matches(m, &u))) {
+          self.join_many(vec![(channel.to_string(), None)])?;
+          events.push(Event::Invite(invite, true));
+        } else {
+          self.invites.retain(|i| !i.channel.eq_ignore_ascii_case(channel));
+          self.invites.push(invite.clone());
+          events.push(Event::Invite(invite, false));
+        }
+      },
+      Typed::Notice { text, .. } => {
+        if from.map_or(false, |n| n.eq_ignore_ascii_case("NickServ")) {
+          let lower = text.to_ascii_lowercase();
+          if lower.contains("you are now identified") || lower.contains("password accepted") ||
+             lower.contains("you are now logged in") {
+            self.identify()?;
+          }
+          // once the ghost is gone, or was never there, take the nick back
+          if self.regaining && self.me != self.nick && regained(&lower) {
+            self.regaining = false;
+            if self.services.as_ref().map_or(false, |s| s.regain != "REGAIN") {
+              let n = self.nick.clone();
+              self.send(&Typed::Nick { nick: &n })?;
+            }
+          }
+        } else if from.map_or(false, |n| n.eq_ignore_ascii_case("ChanServ")) {
+          // eg "You have been unbanned from #channel."
+          for channel in self.retry.clone() {
+            if text.to_ascii_lowercase().contains(&channel.to_ascii_lowercase()) {
+              self.rejoin(&channel)?;
+            }
+          }
+        }
+      },
+      // everyone we asked about in the ISON this answers that isn't listed is offline
+      Typed::Ison { nicks, .. } => {
+        let asked = if self.ison.is_empty() { Vec::new() } else { self.ison.remove(0) };
+        // anyone removed from contacts since is none of our business
+        let asked : Vec<String> = asked.into_iter()
+          .filter(|a| self.contacts.iter().any(|c| c.eq_ignore_ascii_case(a)))
+          .collect();
+        for c in asked {
+          let online = nicks.iter().any(|n| n.eq_ignore_ascii_case(&c));
+          if let Some(e) = self.presence(&c, online) {
+            events.push(e);
+          }
+        }
+      },
+      Typed::MonOnline { targets, .. } | Typed::MonOffline { targets, .. } => {
+        let online = msg.command == Command::Numeric(730);
+        for t in targets {
+          let nick = t.split('!').next().unwrap();
+          if let Some(e) = self.presence(nick, online) {
+            events.push(e);
+          }
+        }
+      },
+      // give up on MONITOR and poll with ISON instead
+      Typed::MonListFull { .. } => {
+        self.isupport.remove("MONITOR");
+        self.raw("MONITOR C")?;
+        self.last_ison = 0;
+      },
+      Typed::Privmsg { target, text } => {
+        if self.away.is_some() && (target.eq_ignore_ascii_case(&self.me) || mentions(text, &self.me)) {
+          events.push(Event::Missed(msg.id));
+        }
+      },
+      _ => ()
+    }
+    return Ok(events);
+  }
+
+  // periodic work, called from the main loop every time poll returns
+  pub fn tick(&mut self, now: i64) -> Result<Vec<Event>> {
+    if self.sock.is_none() {
+      if self.dial.is_some() {
+        return Ok(self.dialed(now));
+      }
+      if self.enabled && now >= self.reconnect_at {
+        return Ok(self.connect(now));
+      }
+      return Ok(Vec::new());
+    }
+    let mut events = Vec::new();
+    events.extend(self.splits(now));
+    // typing nobody's renewed is as good as done
+    let (expired, typing) : (Vec<Typing>, Vec<Typing>) = self.typing.drain(..).partition(|t| now >= t.expires);
+    self.typing = typing;
+    events.extend(expired.into_iter().map(|t| Event::Typing(Typing { state: "done", ..t })));
+    // the server never finished answering, so the client can stop waiting
+    while self.queries.first().map_or(false, |q| now - q.sent >= QUERY_TIMEOUT) {
+      self.queries.remove(0);
+      events.push(Event::Answer(Err("timed out".to_string())));
+    }
+    // NickServ never answered, don't keep our channels waiting forever
+    if self.holding() && self.identify_sent != 0 && now - self.identify_sent >= IDENTIFY_TIMEOUT {
+      self.identify()?;
+    }
+    if self.kicked.iter().any(|&(_, at)| now >= at) {
+      let (due, later) : (Vec<_>, Vec<_>) = self.kicked.split_off(0).into_iter().partition(|&(_, at)| now >= at);
+      self.kicked = later;
+      let chans = due.into_iter().map(|(c, _)| {
+        let k = self.key(&c);
+        (c, k)
+      }).collect();
+      self.join_many(chans)?;
+    }
+    if self.registered && !self.monitoring() && !self.contacts.is_empty() &&
+       now - self.last_ison >= ISON_INTERVAL {
+      self.last_ison = now;
+      for nicks in chunk(&self.contacts, ' ') {
+        self.raw(&format!("ISON {}", nicks))?;
+        self.ison.push(nicks.split(' ').map(|n| n.to_string()).collect());
+      }
+    }
+    return Ok(events);
+  }
+
+  // whether a message is a netsplit quit or a netjoin join, which are only published
+  // summed up; call it before handle(), which moves nicks from splits to joins
+  pub fn folds(&self, msg: &Message) -> bool {
+    let nick = match msg.prefix {
+      Some(Prefix::User(nick, _, _)) => nick,
+      _ => return false
+    };
+    return match msg.command {
+      Command::Named(ref c) if c.eq_ignore_ascii_case("QUIT") => msg.params.get(0).map_or(false, |r| netsplit(r).is_some()),
+      Command::Named(ref c) if c.eq_ignore_ascii_case("JOIN") =>
+        self.splitting.iter().chain(self.split.iter()).chain(self.joining.iter()).any(|s| s.has(nick)),
+      _ => false
+    };
+  }
+
+  // someone lost in a netsplit, from every channel we shared with them
+  fn split_quit(&mut self, nick: &str, servers: (String, String), now: i64) {
+    let channels = self.shared(nick);
+    if !self.splitting.iter().any(|s| s.servers == servers) {
+      self.splitting.push(Split::new(servers.clone(), now));
+    }
+    self.splitting.iter_mut().find(|s| s.servers == servers).unwrap().add(nick, channels, now);
+  }
+
+  // someone coming back from a netsplit, which makes it a netjoin
+  fn split_join(&mut self, nick: &str, channel: &str, now: i64) {
+    let servers = match self.splitting.iter_mut().chain(self.split.iter_mut()).find(|s| s.has(nick)) {
+      Some(s) => {
+        s.nicks.retain(|n| !n.eq_ignore_ascii_case(nick));
+        s.servers.clone()
+      },
+      // or the same user joining the rest of their channels
+      None => match self.joining.iter().find(|j| j.has(nick)) {
+        Some(j) => j.servers.clone(),
+        None => return
+      }
+    };
+    if !self.joining.iter().any(|j| j.servers == servers) {
+      self.joining.push(Split::new(servers.clone(), now));
+    }
+    self.joining.iter_mut().find(|j| j.servers == servers).unwrap().add(nick, vec![channel.to_string()], now);
+  }
+
+  // someone's typing state from a TAGMSG, which a message of theirs also ends
+  fn typed(&mut self, msg: &Message, now: i64) -> Vec<Event> {
+    let mut events = Vec::new();
+    let nick = match msg.prefix {
+      Some(Prefix::User(nick, ..)) if !nick.eq_ignore_ascii_case(&self.me) => nick,
+      _ => return events
+    };
+    let command = match msg.command {
+      Command::Named(ref c) => c.to_ascii_uppercase(),
+      Command::Numeric(_) => return events
+    };
+    let (state, expires) = match (&command[..], msg.tag("+typing").as_ref().map(|t| &t[..])) {
+      ("TAGMSG", Some("active")) => ("active", now + TYPING_ACTIVE),
+      ("TAGMSG", Some("paused")) => ("paused", now + TYPING_PAUSED),
+      ("TAGMSG", Some("done")) | ("PRIVMSG", _) | ("NOTICE", _) => ("done", now),
+      _ => return events
+    };
+    for buffer in self.buffers(msg) {
+      let was = self.typing.iter().position(|t| t.nick.eq_ignore_ascii_case(nick) && t.buffer == buffer);
+      let t = Typing {
+        nick: nick.to_string(),
+        buffer: buffer,
+        state: state,
+        expires: expires
+      };
+      match was {
+        Some(i) if state == "done" => {
+          self.typing.remove(i);
+          events.push(Event::Typing(t));
+        },
+        None if state == "done" => (),
+        // active again just keeps it going
+        Some(i) if self.typing[i].state == state => self.typing[i].expires = expires,
+        Some(i) => {
+          self.typing[i] = t.clone();
+          events.push(Event::Typing(t));
+        },
+        None => {
+          self.typing.push(t.clone());
+          events.push(Event::Typing(t));
+        }
+      }
+    }
+    return events;
+  }
+
+  // send a command with client-only tags like +typing, which needs message-tags; false
+  // if the server doesn't have it, or a tag isn't client-only
+  pub fn send_tagged(&mut self, cmd: &command::Typed, tags: &[(String, String)]) -> Result<bool> {
+    if !self.acked("message-tags") || tags.iter().any(|&(ref k, _)| !k.starts_with('+') || k.len() < 2) {
+      return Ok(false);
+    }
+    let line = cmd.line().chain_err(|| "IRC: invalid command")?;
+    let tags : Vec<String> = tags.iter().map(|&(ref k, ref v)| if v.is_empty() { k.clone() } else { format!("{}={}", k, escape(v)) }).collect();
+    self.raw(&format!("@{} {}", tags.join(";"), line))?;
+    return Ok(true);
+  }
+
+  // netsplits and netjoins that have gone quiet, summed up
+  fn splits(&mut self, now: i64) -> Vec<Event> {
+    let mut events = Vec::new();
+    let (quiet, splitting) : (Vec<Split>, Vec<Split>) = self.splitting.drain(..).partition(|s| now - s.last >= SPLIT_QUIET);
+    self.splitting = splitting;
+    for s in quiet {
+      events.push(Event::Netsplit(s.clone()));
+      self.split.push(s);
+    }
+    let (quiet, joining) : (Vec<Split>, Vec<Split>) = self.joining.drain(..).partition(|j| now - j.last >= SPLIT_QUIET);
+    self.joining = joining;
+    events.extend(quiet.into_iter().map(Event::Netjoin));
+    // whoever hasn't come back by now isn't coming back this way
+    self.split.retain(|s| !s.nicks.is_empty() && now - s.at < SPLIT_MEMORY);
+    return events;
+  }
+
+  // someone left a channel, or every channel when it's None
+  fn gone(&mut self, channel: Option<&str>, nick: &str) {
+    for (name, j) in self.joined.iter_mut() {
+      if channel.map_or(true, |c| c.eq_ignore_ascii_case(name)) {
+        j.members.retain(|m| !m.1.eq_ignore_ascii_case(nick));
+      }
+    }
+  }
+
+  // membership modes and their prefixes from RPL_ISUPPORT, eg ("ov", "@+")
+  pub fn prefixes(&self) -> (String, String) {
+    let prefix = self.isupport.get("PREFIX").map_or("(ov)@+", |p| &p[..]);
+    if prefix.starts_with('(') {
+      if let Some(i) = prefix.find(')') {
+        return (prefix[1..i].to_string(), prefix[i+1..].to_string());
+      }
+    }
+    return (String::new(), String::new());
+  }
+
+  // a name from RPL_NAMREPLY as its prefixes and the nick
+  fn split_prefix(&self, name: &str) -> (String, String) {
+    let (_, symbols) = self.prefixes();
+    let nick = name.trim_start_matches(|c| symbols.contains(c));
+    return (name[..name.len()-nick.len()].to_string(), nick.to_string());
+  }
+
+  // follow changes to membership prefixes, skipping over the arguments of every other
+  // mode according to CHANMODES
+  fn modes(&mut self, channel: &str, modes: &str, args: &[&str]) {
+    let (pmodes, symbols) = self.prefixes();
+    let chanmodes = self.isupport.get("CHANMODES").cloned().unwrap_or("beI,k,l,imnpst".to_string());
+    let types : Vec<&str> = chanmodes.split(',').collect();
+    let always = format!("{}{}", types.get(0).unwrap_or(&""), types.get(1).unwrap_or(&""));
+    let when_set = types.get(2).unwrap_or(&"");
+    let mut args = args.iter();
+    let mut adding = true;
+    for c in modes.chars() {
+      match c {
+        '+' => adding = true,
+        '-' => adding = false,
+        c if pmodes.contains(c) => {
+          let nick = match args.next() {
+            Some(n) => n,
+            None => break
+          };
+          let symbol = symbols.chars().nth(pmodes.find(c).unwrap()).unwrap_or('?');
+          if let Some(j) = self.joined.get_mut(&channel.to_ascii_lowercase()) {
+            for m in j.members.iter_mut().filter(|m| m.1.eq_ignore_ascii_case(nick)) {
+              m.0.retain(|s| s != symbol);
+              if adding {
+                m.0.push(symbol);
+                // highest first, in the order PREFIX lists them
+                let mut p : Vec<char> = m.0.chars().collect();
+                p.sort_by_key(|s| symbols.find(*s));
+                m.0 = p.into_iter().collect();
+              }
+            }
+          }
+        },
+        c if always.contains(c) || (adding && when_set.contains(c)) => {
+          args.next();
+        },
+        _ => ()
+      }
+    }
+  }
+
+  // MONITOR limit from RPL_ISUPPORT, Some(0) if unlimited and None if unsupported
+  pub fn monitor(&self) -> Option<usize> {
+    return self.isupport.get("MONITOR").map(|v| v.parse().unwrap_or(0));
+  }
+
+  fn monitoring(&self) -> bool {
+    return match self.monitor() {
+      Some(0) => true,
+      Some(n) => self.contacts.len() <= n,
+      None => false
+    };
+  }
+
+  // start watching our contacts once we're registered
+  fn watch(&mut self) -> Result<()> {
+    if self.monitoring() {
+      for nicks in chunk(&self.contacts, ',') {
+        self.raw(&format!("MONITOR + {}", nicks))?;
+      }
+    } else {
+      // tick() will send an ISON next time around
+      self.last_ison = 0;
+    }
+    return Ok(());
+  }
+
+  // record a contact's presence, returning an event if it changed
+  fn presence(&mut self, nick: &str, online: bool) -> Option<Event> {
+    match (self.online.iter().position(|n| n.eq_ignore_ascii_case(nick)), online) {
+      (None, true) => {
+        self.online.push(nick.to_string());
+        Some(Event::Presence(nick.to_string(), true))
+      },
+      (Some(i), false) => {
+        self.online.remove(i);
+        Some(Event::Presence(nick.to_string(), false))
+      },
+      _ => None
+    }
+  }
+
+  pub fn is_online(&self, nick: &str) -> bool {
+    return self.online.iter().any(|n| n.eq_ignore_ascii_case(nick));
+  }
+
+  // whether joins should wait until NickServ confirms we identified
+  fn holding(&self) -> bool {
+    return !self.identified && self.services.as_ref().map_or(false, |s| s.wait);
+  }
+
+  // we're identified (or have given up waiting), let the held back joins through
+  fn identify(&mut self) -> Result<()> {
+    self.identified = true;
+    let deferred = self.deferred.split_off(0);
+    return self.join_many(deferred);
+  }
+
+  // join a channel again after ChanServ has let us in
+  fn rejoin(&mut self, channel: &str) -> Result<()> {
+    if let Some(i) = self.retry.iter().position(|r| r.eq_ignore_ascii_case(channel)) {
+      self.retry.remove(i);
+      let key = self.key(channel);
+      self.send_join(&[(channel.to_string(), key)])?;
+    }
+    return Ok(());
+  }
+
+  // set or clear away on behalf of a client
+  pub fn set_away(&mut self, message: Option<&str>) -> Result<()> {
+    self.away = message.map(|m| m.to_string());
+    self.away_auto = false;
+    if self.registered {
+      match message {
+        Some(m) => self.send(&command::Typed::Away { message: Some(m) })?,
+        None => self.send(&command::Typed::Away { message: None })?
+      }
+    }
+    return Ok(());
+  }
+
+  // go away when no client has been around for a while, and come back when one is
+  pub fn auto_away(&mut self, idle: bool, message: &str) -> Result<()> {
+    if idle && self.away.is_none() {
+      self.away = Some(message.to_string());
+      self.away_auto = true;
+      if self.registered {
+        self.raw(&format!("AWAY :{}", message))?;
+      }
+    } else if !idle && self.away_auto {
+      self.away = None;
+      self.away_auto = false;
+      if self.registered {
+        self.raw("AWAY")?;
+      }
+    }
+    return Ok(());
+  }
+
+  pub fn contact_add(&mut self, nick: &str) -> Result<()> {
+    if self.contacts.iter().any(|c| c.eq_ignore_ascii_case(nick)) {
+      return Ok(());
+    }
+    self.contacts.push(nick.to_string());
+    if self.registered {
+      if self.monitoring() {
+        self.raw(&format!("MONITOR + {}", nick))?;
+      } else {
+        self.last_ison = 0;
+      }
+    }
+    return Ok(());
+  }
+
+  pub fn contact_remove(&mut self, nick: &str) -> Result<()> {
+    self.contacts.retain(|c| !c.eq_ignore_ascii_case(nick));
+    self.online.retain(|c| !c.eq_ignore_ascii_case(nick));
+    if self.registered && self.monitor().is_some() {
+      self.raw(&format!("MONITOR - {}", nick))?;
+    }
+    return Ok(());
+  }
+}
+
+// the nick to try when nick is taken, counting up in the last character once it's
+// max long, None when we've run out
+fn next_nick(nick: &str, max: usize) -> Option<String> {
+  if nick.chars().count() < max {
+    return Some(format!("{}_", nick));
+  }
+  let mut n : String = nick.chars().take(max).collect();
+  return match n.pop() {
+    Some('9') | None => None,
+    Some(c) if c.is_ascii_digit() => {
+      n.push((c as u8 + 1) as char);
+      Some(n)
+    },
+    Some(_) => {
+      n.push('0');
+      Some(n)
+    }
+  };
+}
+
+// whether a NickServ notice (lowercased) says our nick is free for us, eg atheme's
+// "nick has been ghosted." or anope's "Ghost with your nick has been killed."
+fn regained(notice: &str) -> bool {
+  return notice.contains("has been ghosted") || notice.contains("ghost with your nick has been killed") ||
+         notice.contains("has been regained") || notice.contains("you have regained") ||
+         notice.contains("is not online") || notice.contains("isn't currently in use");
+}
+
+// whether nick appears in text as a word of its own
+pub fn mentions(text: &str, nick: &str) -> bool {
+  let lower = text.to_ascii_lowercase();
+  let nick = nick.to_ascii_lowercase();
+  let word = |c: char| c.is_alphanumeric() || "-_[]{}\\`^|".contains(c);
+  for (i, _) in lower.match_indices(&nick[..]) {
+    let before = lower[..i].chars().next_back();
+    let after = lower[i+nick.len()..].chars().next();
+    if !before.map_or(false, &word) && !after.map_or(false, &word) {
+      return true;
+    }
+  }
+  return false;
+}
+
+// join nicks with sep, splitting into several strings to stay well under the line limit
+pub fn chunk(nicks: &[String], sep: char) -> Vec<String> {
+  let mut ret = Vec::new();
+  let mut cur = String::new();
+  for n in nicks {
+    if !cur.is_empty() && cur.len() + n.len() + 1 > 400 {
+      ret.push(cur);
+      cur = String::new();
+    }
+    if !cur.is_empty() {
+      cur.push(sep);
+    }
+    cur.push_str(n);
+  }
+  if !cur.is_empty() {
+    ret.push(cur);
+  }
+  return ret;
+}
+
+pub fn lookup(id: &str, ctxs: &Vec<Context>) -> usize {
+  for i in 0..ctxs.len() {
+    if id == ctxs[i].id {
+      return i;
+    }
+  }
+
+  panic!("context not found\n");
+}
+
+// RFC 1459 and 2812 messages, with IRCv3 tags, see
+// https://modern.ircdocs.horse/#message-format for the grammar
+pub mod parse {
+  use std;
+  use nom;
+  use nom::IResult;
+  use nom::bytes::complete::{take_while, take_while1};
+  use nom::character::complete::{char, satisfy};
+  use nom::combinator::{all_consuming, opt, recognize, rest};
+  use nom::multi::many0;
+  use nom::sequence::{pair, preceded, terminated};
+
+  #[derive(Debug)]
+  pub struct ParserError {
+    data: String
+  }
+  impl std::fmt::Display for ParserError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+      write!(fmt, "{}", self.data)
+    }
+  }
+  impl std::error::Error for ParserError {
+    fn description(&self) -> &str {
+      &self.data
+    }
+  }
+  impl<'a> From<nom::Err<nom::error::Error<&'a str>>> for ParserError {
+    fn from(e: nom::Err<nom::error::Error<&'a str>>) -> ParserError {
+      ParserError {
+        data: format!("Error: {:?}", e)
+      }
+    }
+  }
+
+  // only spaces separate things, a tab is part of whatever it's in
+  fn spaces(i: &str) -> IResult<&str, &str> {
+    return take_while1(|c| c == ' ')(i);
+  }
+
+  fn word(c: char) -> bool {
+    return c != ' ' && c != '\0' && c != '\r' && c != '\n';
+  }
+
+  // a param that isn't the trailing one can't be empty or start with ':'
+  fn middle(i: &str) -> IResult<&str, &str> {
+    return recognize(pair(satisfy(|c| word(c) && c != ':'), take_while(word)))(i);
+  }
+
+  fn message(i: &str) -> IResult<&str, (Option<&str>, Option<&str>, &str, Vec<&str>, Option<&str>)> {
+    let (i, tags) = opt(terminated(preceded(char('@'), take_while1(word)), spaces))(i)?;
+    let (i, source) = opt(terminated(preceded(char(':'), take_while1(word)), spaces))(i)?;
+    let (i, command) = take_while1(|c: char| c.is_ascii_alphanumeric())(i)?;
+    let (i, params) = many0(preceded(spaces, middle))(i)?;
+    let (i, trailing) = opt(preceded(spaces, preceded(char(':'), rest)))(i)?;
+    let (i, _) = take_while(|c| c == ' ')(i)?;
+    return Ok((i, (tags, source, command, params, trailing)));
+  }
+
+  fn tags(s: &str) -> Vec<super::Tag> {
+    return s.split(';').filter(|t| !t.is_empty()).map(|t| {
+      let mut kv = t.splitn(2, '=');
+      super::Tag {
+        key: kv.next().unwrap(),
+        value: kv.next()
+      }
+    }).collect();
+  }
+
+  // nick!user@host, where user and host can be missing; a source with neither is a
+  // server if it's dotted, or if it sent a numeric, which only servers do (localhost's
+  // 001, say), and otherwise just a nick, like some servers send our own MODE or NICK from
+  fn prefix(s: &str, numeric: bool) -> super::Prefix {
+    let (rest, host) = match s.find('@') {
+      Some(i) => (&s[..i], Some(&s[i+1..])),
+      None => (s, None)
+    };
+    let (nick, user) = match rest.find('!') {
+      Some(i) => (&rest[..i], Some(&rest[i+1..])),
+      None => (rest, None)
+    };
+    if user.is_none() && host.is_none() && (numeric || nick.contains('.')) {
+      return super::Prefix::Server(nick);
+    }
+    return super::Prefix::User(nick, user, host);
+  }
+
+  pub fn command(s: &str) -> super::Command {
+    if s.len() == 3 && s.bytes().all(|b| b.is_ascii_digit()) {
+      return super::Command::Numeric(s.parse().unwrap());
+    }
+    return super::Command::Named(s.into());
+  }
+
+  // input shouldn't have its line ending any more, but one is tolerated
+  pub fn parse_message<'a>(server: &'a str, id: i64, input: &'a str) -> Result<super::Message<'a>, ParserError> {
+    let input = input.trim_end_matches(|c| c == '\r' || c == '\n');
+    let (_, (t, source, cmd, mut params, trailing)) = all_consuming(message)(input)?;
+    if let Some(t) = trailing {
+      params.push(t);
+    }
+    let command = command(cmd);
+    let numeric = match command {
+      super::Command::Numeric(_) => true,
+      _ => false
+    };
+    return Ok(super::Message {
+      server: server,
+      id: id,
+      tags: t.map_or(Vec::new(), tags),
+      prefix: source.map(|s| prefix(s, numeric)),
+      command: command,
+      params: params,
+      trailing: trailing.is_some()
+    });
+  }
+}
+
+// messages as the commands they stand for, so nothing has to pick params out by index
+pub mod command {
+  use std;
+  use std::fmt;
+
+  // why a message doesn't make a command, or a command can't be sent
+  #[derive(Debug, PartialEq)]
+  pub enum Invalid {
+    // fewer params than the command needs, with how many it needs
+    Params(String, usize),
+    // a param that makes no sense where it is, eg a mode string without + or -
+    Param(String, String),
+    // something that wouldn't survive the trip to the server, eg a line break
+    Unsendable(String)
+  }
+  impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match *self {
+        Invalid::Params(ref c, n) => write!(f, "{} needs at least {} params", c, n),
+        Invalid::Param(ref c, ref p) => write!(f, "{} can't take {:?}", c, p),
+        Invalid::Unsendable(ref why) => write!(f, "{}", why)
+      }
+    }
+  }
+  impl std::error::Error for Invalid {
+    fn description(&self) -> &str {
+      return "invalid IRC command";
+    }
+  }
+
+  // the numerics carry our own nick first, and most end in human readable text
+  #[derive(Debug, PartialEq)]
+  pub enum Typed<'a> {
+    Privmsg { target: &'a str, text: &'a str },
+    Notice { target: &'a str, text: &'a str },
+    // with extended-join the server sends account and realname where keys would be
+    Join { channels: Vec<&'a str>, keys: Vec<&'a str> },
+    Part { channels: Vec<&'a str>, reason: Option<&'a str> },
+    Kick { channel: &'a str, nick: &'a str, reason: Option<&'a str> },
+    Invite { nick: &'a str, channel: &'a str },
+    // no modes is a query
+    Mode { target: &'a str, modes: Option<&'a str>, args: Vec<&'a str> },
+    // no topic is a query, an empty one clears it
+    Topic { channel: &'a str, topic: Option<&'a str> },
+    Nick { nick: &'a str },
+    Quit { reason: Option<&'a str> },
+    Away { message: Option<&'a str> },
+    Ping { token: &'a str },
+    Pong { server: Option<&'a str>, token: &'a str },
+
+    // RPL_WELCOME
+    Welcome { nick: &'a str, text: Option<&'a str> },
+    // RPL_ISUPPORT
+    ISupport { nick: &'a str, tokens: Vec<&'a str>, text: Option<&'a str> },
+    // RPL_ISON
+    Ison { nick: &'a str, nicks: Vec<&'a str> },
+    // RPL_NOTOPIC, RPL_TOPIC and RPL_TOPICWHOTIME
+    NoTopic { nick: &'a str, channel: &'a str, text: Option<&'a str> },
+    TopicIs { nick: &'a str, channel: &'a str, topic: &'a str },
+    TopicWhoTime { nick: &'a str, channel: &'a str, by: &'a str, at: i64 },
+    // RPL_NAMREPLY, names keep their membership prefixes
+    NamReply { nick: &'a str, symbol: &'a str, channel: &'a str, names: Vec<&'a str> },
+    // RPL_ENDOFNAMES
+    EndOfNames { nick: &'a str, channel: &'a str, text: Option<&'a str> },
+    // RPL_ENDOFMOTD and ERR_NOMOTD
+    EndOfMotd { nick: &'a str, text: Option<&'a str> },
+    NoMotd { nick: &'a str, text: Option<&'a str> },
+    // ERR_NICKNAMEINUSE
+    NicknameInUse { nick: &'a str, attempted: &'a str, text: Option<&'a str> },
+    // ERR_CHANNELISFULL, ERR_INVITEONLYCHAN, ERR_BANNEDFROMCHAN, ERR_BADCHANNELKEY
+    ChannelIsFull { nick: &'a str, channel: &'a str, text: Option<&'a str> },
+    InviteOnlyChan { nick: &'a str, channel: &'a str, text: Option<&'a str> },
+    BannedFromChan { nick: &'a str, channel: &'a str, text: Option<&'a str> },
+    BadChannelKey { nick: &'a str, channel: &'a str, text: Option<&'a str> },
+    // RPL_MONONLINE and RPL_MONOFFLINE, targets are nick!user@host or just nick
+    MonOnline { nick: &'a str, targets: Vec<&'a str> },
+    MonOffline { nick: &'a str, targets: Vec<&'a str> },
+    // ERR_MONLISTFULL
+    MonListFull { nick: &'a str, limit: usize, targets: Vec<&'a str>, text: Option<&'a str> },
+    // RPL_LOGGEDIN
+    LoggedIn { nick: &'a str, mask: &'a str, account: &'a str, text: Option<&'a str> },
+
+    // everything we don't have a name for, as it came
+    Numeric(u16, Vec<&'a str>),
+    Other(&'a str, Vec<&'a str>)
+  }
+
+  impl<'a> Typed<'a> {
+    // a command from its name and params, the way a client would put it
+    pub fn new(command: &'a str, params: Vec<&'a str>) -> Result<Typed<'a>, Invalid> {
+      return match super::parse::command(command) {
+        super::Command::Numeric(n) => numeric(n, params),
+        super::Command::Named(_) => named(command, params)
+      };
+    }
+
+    pub fn from_message(msg: &'a super::Message<'a>) -> Result<Typed<'a>, Invalid> {
+      return match msg.command {
+        super::Command::Numeric(n) => numeric(n, msg.params.clone()),
+        super::Command::Named(ref c) => named(c, msg.params.clone())
+      };
+    }
+
+    // the line to send for this command, without its line ending
+    pub fn line(&self) -> Result<String, Invalid> {
+      let (command, params, trailing) = self.parts();
+      if command.is_empty() || !command.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Invalid::Unsendable(format!("{:?} isn't a command", command)));
+      }
+      let mut line = command;
+      for (i, p) in params.iter().enumerate() {
+        if p.contains(|c| c == '\r' || c == '\n' || c == '\0') {
+          return Err(Invalid::Unsendable(format!("{:?} has a line break in it", p)));
+        }
+        if i + 1 == params.len() {
+          if trailing || p.is_empty() || p.starts_with(':') || p.contains(' ') {
+            line.push_str(" :");
+          } else {
+            line.push(' ');
+          }
+        } else if p.is_empty() || p.starts_with(':') || p.contains(' ') {
+          return Err(Invalid::Unsendable(format!("{:?} can only be the last param", p)));
+        } else {
+          line.push(' ');
+        }
+        line.push_str(p);
+      }
+      // 512 bytes including the line ending
+      if line.len() > 510 {
+        return Err(Invalid::Unsendable(format!("line is {} bytes long", line.len())));
+      }
+      return Ok(line);
+    }
+
+    // the command, its params, and whether the last one is text that always goes after ':'
+    fn parts(&self) -> (String, Vec<String>, bool) {
+      let s = |p: &&str| p.to_string();
+      return match *self {
+        Typed::Privmsg { target, text } => ("PRIVMSG".to_string(), vec![s(&target), s(&text)], true),
+        Typed::Notice { target, text } => ("NOTICE".to_string(), vec![s(&target), s(&text)], true),
+        Typed::Join { ref channels, ref keys } => {
+          let mut params = vec![channels.join(",")];
+          if !keys.is_empty() {
+            params.push(keys.join(","));
+          }
+          ("JOIN".to_string(), params, false)
+        },
+        Typed::Part { ref channels, ref reason } => {
+          let mut params = vec![channels.join(",")];
+          params.extend(reason.iter().map(s));
+          ("PART".to_string(), params, reason.is_some())
+        },
+        Typed::Kick { channel, nick, ref reason } => {
+          let mut params = vec![s(&channel), s(&nick)];
+          params.extend(reason.iter().map(s));
+          ("KICK".to_string(), params, reason.is_some())
+        },
+        Typed::Invite { nick, channel } => ("INVITE".to_string(), vec![s(&nick), s(&channel)], false),
+        Typed::Mode { target, ref modes, ref args } => {
+          let mut params = vec![s(&target)];
+          params.extend(modes.iter().map(s));
+          params.extend(args.iter().map(s));
+          ("MODE".to_string(), params, false)
+        },
+        Typed::Topic { channel, ref topic } => {
+          let mut params = vec![s(&channel)];
+          params.extend(topic.iter().map(s));
+          ("TOPIC".to_string(), params, topic.is_some())
+        },
+        Typed::Nick { nick } => ("NICK".to_string(), vec![s(&nick)], false),
+        Typed::Quit { ref reason } => ("QUIT".to_string(), reason.iter().map(s).collect(), true),
+        Typed::Away { ref message } => ("AWAY".to_string(), message.iter().map(s).collect(), true),
+        Typed::Ping { token } => ("PING".to_string(), vec![s(&token)], true),
+        Typed::Pong { ref server, token } => {
+          let mut params : Vec<String> = server.iter().map(s).collect();
+          params.push(s(&token));
+          ("PONG".to_string(), params, true)
+        },
+        Typed::Welcome { nick, ref text } => reply(1, nick, Vec::new(), text),
+        Typed::ISupport { nick, ref tokens, ref text } => reply(5, nick, tokens.iter().map(s).collect(), text),
+        Typed::Ison { nick, ref nicks } => reply(303, nick, Vec::new(), &Some(&nicks.join(" ")[..])),
+        Typed::NoTopic { nick, channel, ref text } => reply(331, nick, vec![s(&channel)], text),
+        Typed::TopicIs { nick, channel, topic } => reply(332, nick, vec![s(&channel)], &Some(topic)),
+        Typed::TopicWhoTime { nick, channel, by, at } =>
+          reply(333, nick, vec![s(&channel), s(&by), at.to_string()], &None),
+        Typed::NamReply { nick, symbol, channel, ref names } =>
+          reply(353, nick, vec![s(&symbol), s(&channel)], &Some(&names.join(" ")[..])),
+        Typed::EndOfNames { nick, channel, ref text } => reply(366, nick, vec![s(&channel)], text),
+        Typed::EndOfMotd { nick, ref text } => reply(376, nick, Vec::new(), text),
+        Typed::NoMotd { nick, ref text } => reply(422, nick, Vec::new(), text),
+        Typed::NicknameInUse { nick, attempted, ref text } => reply(433, nick, vec![s(&attempted)], text),
+        Typed::ChannelIsFull { nick, channel, ref text } => reply(471, nick, vec![s(&channel)], text),
+        Typed::InviteOnlyChan { nick, channel, ref text } => reply(473, nick, vec![s(&channel)], text),
+        Typed::BannedFromChan { nick, channel, ref text } => reply(474, nick, vec![s(&channel)], text),
+        Typed::BadChannelKey { nick, channel, ref text } => reply(475, nick, vec![s(&channel)], text),
+        Typed::MonOnline { nick, ref targets } => reply(730, nick, Vec::new(), &Some(&targets.join(",")[..])),
+        Typed::MonOffline { nick, ref targets } => reply(731, nick, Vec::new(), &Some(&targets.join(",")[..])),
+        Typed::MonListFull { nick, limit, ref targets, ref text } =>
+          reply(734, nick, vec![limit.to_string(), targets.join(",")], text),
+        Typed::LoggedIn { nick, mask, account, ref text } => reply(900, nick, vec![s(&mask), s(&account)], text),
+        Typed::Numeric(n, ref params) => (format!("{:03}", n), params.iter().map(s).collect(), false),
+        Typed::Other(command, ref params) => (command.to_string(), params.iter().map(s).collect(), false)
+      };
+    }
+  }
+
+  // a numeric's params are our nick, then whatever it carries, then maybe some text
+  fn reply(n: u16, nick: &str, mut rest: Vec<String>, text: &Option<&str>) -> (String, Vec<String>, bool) {
+    let mut params = vec![nick.to_string()];
+    params.append(&mut rest);
+    params.extend(text.iter().map(|t| t.to_string()));
+    return (format!("{:03}", n), params, text.is_some());
+  }
+
+  fn need(command: &str, params: &[&str], n: usize) -> Result<(), Invalid> {
+    if params.len() < n {
+      return Err(Invalid::Params(command.to_string(), n));
+    }
+    return Ok(());
+  }
+
+  // a comma or space separated list, without the empty items a stray separator leaves
+  fn list<'a>(s: Option<&&'a str>, sep: char) -> Vec<&'a str> {
+    return s.map_or(Vec::new(), |s| s.split(sep).filter(|i| !i.is_empty()).collect());
+  }
+
+  fn named<'a>(command: &'a str, p: Vec<&'a str>) -> Result<Typed<'a>, Invalid> {
+    let upper = command.to_ascii_uppercase();
+    let get = |i: usize| p.get(i).cloned();
+    let typed = match &upper[..] {
+      "PRIVMSG" => {
+        need(&upper, &p, 2)?;
+        Typed::Privmsg { target: p[0], text: p[1] }
+      },
+      "NOTICE" => {
+        need(&upper, &p, 2)?;
+        Typed::Notice { target: p[0], text: p[1] }
+      },
+      "JOIN" => {
+        need(&upper, &p, 1)?;
+        Typed::Join { channels: list(p.get(0), ','), keys: list(p.get(1), ',') }
+      },
+      "PART" => {
+        need(&upper, &p, 1)?;
+        Typed::Part { channels: list(p.get(0), ','), reason: get(1) }
+      },
+      "KICK" => {
+        need(&upper, &p, 2)?;
+        Typed::Kick { channel: p[0], nick: p[1], reason: get(2) }
+      },
+      "INVITE" => {
+        need(&upper, &p, 2)?;
+        Typed::Invite { nick: p[0], channel: p[1] }
+      },
+      "MODE" => {
+        need(&upper, &p, 1)?;
+        if let Some(m) = get(1) {
+          if !m.starts_with('+') && !m.starts_with('-') {
+            return Err(Invalid::Param(upper, m.to_string()));
+          }
+        }
+        Typed::Mode { target: p[0], modes: get(1), args: p.iter().skip(2).cloned().collect() }
+      },
+      "TOPIC" => {
+        need(&upper, &p, 1)?;
+        Typed::Topic { channel: p[0], topic: get(1) }
+      },
+      "NICK" => {
+        need(&upper, &p, 1)?;
+        Typed::Nick { nick: p[0] }
+      },
+      "QUIT" => Typed::Quit { reason: get(0) },
+      // an empty message is the same as none, both mean we're back
+      "AWAY" => Typed::Away { message: get(0).and_then(|m| if m.is_empty() { None } else { Some(m) }) },
+      "PING" => {
+        need(&upper, &p, 1)?;
+        Typed::Ping { token: p[0] }
+      },
+      "PONG" => {
+        need(&upper, &p, 1)?;
+        if p.len() > 1 {
+          Typed::Pong { server: Some(p[0]), token: p[p.len()-1] }
+        } else {
+          Typed::Pong { server: None, token: p[0] }
+        }
+      },
+      _ => Typed::Other(command, p.clone())
+    };
+    return Ok(typed);
+  }
+
+  fn numeric<'a>(n: u16, p: Vec<&'a str>) -> Result<Typed<'a>, Invalid> {
+    let name = format!("{:03}", n);
+    let get = |i: usize| p.get(i).cloned();
+    let typed = match n {
+      1 => {
+        need(&name, &p, 1)?;
+        Typed::Welcome { nick: p[0], text: get(1) }
+      },
+      // the last param is the text, everything between it and our nick is a token
+      5 => {
+        need(&name, &p, 2)?;
+        Typed::ISupport { nick: p[0], tokens: p[1..p.len()-1].to_vec(), text: get(p.len()-1) }
+      },
+      303 => {
+        need(&name, &p, 1)?;
+        Typed::Ison { nick: p[0], nicks: list(p.get(1), ' ') }
+      },
+      331 => {
+        need(&name, &p, 2)?;
+        Typed::NoTopic { nick: p[0], channel: p[1], text: get(2) }
+      },
+      332 => {
+        need(&name, &p, 3)?;
+        Typed::TopicIs { nick: p[0], channel: p[1], topic: p[2] }
+      },
+      333 => {
+        need(&name, &p, 4)?;
+        let at = p[3].parse().map_err(|_| Invalid::Param(name.clone(), p[3].to_string()))?;
+        Typed::TopicWhoTime { nick: p[0], channel: p[1], by: p[2], at: at }
+      },
+      353 => {
+        need(&name, &p, 4)?;
+        Typed::NamReply { nick: p[0], symbol: p[1], channel: p[2], names: list(p.get(3), ' ') }
+      },
+      366 => {
+        need(&name, &p, 2)?;
+        Typed::EndOfNames { nick: p[0], channel: p[1], text: get(2) }
+      },
+      376 => {
+        need(&name, &p, 1)?;
+        Typed::EndOfMotd { nick: p[0], text: get(1) }
+      },
+      422 => {
+        need(&name, &p, 1)?;
+        Typed::NoMotd { nick: p[0], text: get(1) }
+      },
+      433 => {
+        need(&name, &p, 2)?;
+        Typed::NicknameInUse { nick: p[0], attempted: p[1], text: get(2) }
+      },
+      471 | 473 | 474 | 475 => {
+        need(&name, &p, 2)?;
+        let (nick, channel, text) = (p[0], p[1], get(2));
+        match n {
+          471 => Typed::ChannelIsFull { nick: nick, channel: channel, text: text },
+          473 => Typed::InviteOnlyChan { nick: nick, channel: channel, text: text },
+          474 => Typed::BannedFromChan { nick: nick, channel: channel, text: text },
+          _ => Typed::BadChannelKey { nick: nick, channel: channel, text: text }
+        }
+      },
+      730 => {
+        need(&name, &p, 2)?;
+        Typed::MonOnline { nick: p[0], targets: list(p.get(1), ',') }
+      },
+      731 => {
+        need(&name, &p, 2)?;
+        Typed::MonOffline { nick: p[0], targets: list(p.get(1), ',') }
+      },
+      734 => {
+        need(&name, &p, 3)?;
+        let limit = p[1].parse().map_err(|_| Invalid::Param(name.clone(), p[1].to_string()))?;
+        Typed::MonListFull { nick: p[0], limit: limit, targets: list(p.get(2), ','), text: get(3) }
+      },
+      900 => {
+        need(&name, &p, 3)?;
+        Typed::LoggedIn { nick: p[0], mask: p[1], account: p[2], text: get(3) }
+      },
+      _ => Typed::Numeric(n, p.clone())
+    };
+    return Ok(typed);
+  }
+}
+
+// cases from https://github.com/ircdocs/parser-tests, msg-split.yaml and msg-join.yaml
+#[cfg(test)]
+mod tests {
+  use serde_json;
+  use super::*;
+
+  // input, tags with their unescaped values, source, verb, params
+  const SPLIT : &'static [(&'static str, &'static [(&'static str, &'static str)], Option<&'static str>, &'static str, &'static [&'static str])] = &[
+    ("foo bar baz asdf", &[], None, "foo", &["bar", "baz", "asdf"]),
+    (":coolguy foo bar baz asdf", &[], Some("coolguy"), "foo", &["bar", "baz", "asdf"]),
+    ("foo bar baz :asdf quux", &[], None, "foo", &["bar", "baz", "asdf quux"]),
+    ("foo bar baz :", &[], None, "foo", &["bar", "baz", ""]),
+    ("foo bar baz ::asdf", &[], None, "foo", &["bar", "baz", ":asdf"]),
+    (":coolguy foo bar baz :asdf quux", &[], Some("coolguy"), "foo", &["bar", "baz", "asdf quux"]),
+    (":coolguy foo bar baz :  asdf quux ", &[], Some("coolguy"), "foo", &["bar", "baz", "  asdf quux "]),
+    (":coolguy PRIVMSG bar :lol :) ", &[], Some("coolguy"), "PRIVMSG", &["bar", "lol :) "]),
+    (":coolguy foo bar baz :", &[], Some("coolguy"), "foo", &["bar", "baz", ""]),
+    (":coolguy foo bar baz :  ", &[], Some("coolguy"), "foo", &["bar", "baz", "  "]),
+    ("@a=b;c=32;k;rt=ql7 foo", &[("a", "b"), ("c", "32"), ("k", ""), ("rt", "ql7")], None, "foo", &[]),
+    ("@a=b\\\\and\\nk;c=72\\s45;d=gh\\:764 foo", &[("a", "b\\and\nk"), ("c", "72 45"), ("d", "gh;764")], None, "foo", &[]),
+    ("@c;h=;a=b :quux ab cd", &[("c", ""), ("h", ""), ("a", "b")], Some("quux"), "ab", &["cd"]),
+    (":src JOIN #chan", &[], Some("src"), "JOIN", &["#chan"]),
+    (":src JOIN :#chan", &[], Some("src"), "JOIN", &["#chan"]),
+    (":src AWAY", &[], Some("src"), "AWAY", &[]),
+    (":src AWAY ", &[], Some("src"), "AWAY", &[]),
+    (":cool\tguy foo bar baz", &[], Some("cool\tguy"), "foo", &["bar", "baz"]),
+    (":coolguy!ag@net\x035w\x03ork.admin PRIVMSG foo :bar baz", &[], Some("coolguy!ag@net\x035w\x03ork.admin"), "PRIVMSG", &["foo", "bar baz"]),
+    (":coolguy!~ag@n\x02et\x0305w\x0fork.admin PRIVMSG foo :bar baz", &[], Some("coolguy!~ag@n\x02et\x0305w\x0fork.admin"), "PRIVMSG", &["foo", "bar baz"]),
+    ("@tag1=value1;tag2;vendor1/tag3=value2;vendor2/tag4= :irc.example.com COMMAND param1 param2 :param3 param3",
+     &[("tag1", "value1"), ("tag2", ""), ("vendor1/tag3", "value2"), ("vendor2/tag4", "")], Some("irc.example.com"), "COMMAND", &["param1", "param2", "param3 param3"]),
+    (":irc.example.com COMMAND param1 param2 :param3 param3", &[], Some("irc.example.com"), "COMMAND", &["param1", "param2", "param3 param3"]),
+    ("@tag1=value1;tag2;vendor1/tag3=value2;vendor2/tag4 COMMAND param1 param2 :param3 param3",
+     &[("tag1", "value1"), ("tag2", ""), ("vendor1/tag3", "value2"), ("vendor2/tag4", "")], None, "COMMAND", &["param1", "param2", "param3 param3"]),
+    ("COMMAND", &[], None, "COMMAND", &[]),
+    ("@foo=\\\\\\\\\\:\\\\s\\s\\r\\n COMMAND", &[("foo", "\\\\;\\s \r\n")], None, "COMMAND", &[]),
+    (":gravel.mozilla.org 432  #momo :Erroneous Nickname: Illegal characters", &[], Some("gravel.mozilla.org"), "432", &["#momo", "Erroneous Nickname: Illegal characters"]),
+    (":gravel.mozilla.org MODE #tckk +n ", &[], Some("gravel.mozilla.org"), "MODE", &["#tckk", "+n"]),
+    (":services.esper.net MODE #foo-bar +o foobar  ", &[], Some("services.esper.net"), "MODE", &["#foo-bar", "+o", "foobar"]),
+    ("@tag1=value\\\\ntest COMMAND", &[("tag1", "value\\ntest")], None, "COMMAND", &[]),
+    ("@tag1=value\\1 COMMAND", &[("tag1", "value1")], None, "COMMAND", &[]),
+    ("@tag1=value1\\ COMMAND", &[("tag1", "value1")], None, "COMMAND", &[]),
+    ("@tag1=1;tag2=3;tag3=4;tag1=5 COMMAND", &[("tag1", "5"), ("tag2", "3"), ("tag3", "4")], None, "COMMAND", &[]),
+    ("@tag1=1;tag2=3;tag3=4;tag1=5;vendor/tag2=8 COMMAND", &[("tag1", "5"), ("tag2", "3"), ("tag3", "4"), ("vendor/tag2", "8")], None, "COMMAND", &[]),
+    (":SomeOp MODE #channel :+i", &[], Some("SomeOp"), "MODE", &["#channel", "+i"]),
+    (":SomeOp MODE #channel +oo SomeUser :AnotherUser", &[], Some("SomeOp"), "MODE", &["#channel", "+oo", "SomeUser", "AnotherUser"])
+  ];
+
+  // lines that come back out exactly as they went in
+  const JOIN : &'static [&'static str] = &[
+    "foo bar baz asdf",
+    ":src AWAY",
+    ":coolguy foo bar baz asdf",
+    "foo bar baz :asdf quux",
+    "foo bar baz :",
+    "foo bar baz ::asdf",
+    ":coolguy foo bar baz :asdf quux",
+    ":coolguy foo bar baz :  asdf quux ",
+    ":coolguy foo bar baz :",
+    "@tag=123 foo",
+    "@tag=\\\\\\:\\s\\r\\n foo",
+    "@tag1=value1;tag2;vendor1/tag3=value2 :irc.example.com COMMAND param1 param2 :param3 param3",
+    ":nick!user@host PRIVMSG #chan :hello there",
+    ":irc.example.com 001 me :Welcome"
+  ];
+
+  #[test]
+  fn split() {
+    for &(input, tags, source, verb, params) in SPLIT {
+      let msg = parse::parse_message("test", 0, input).unwrap_or_else(|e| panic!("{:?}: {}", input, e));
+      let mut keys : Vec<&str> = msg.tags.iter().map(|t| t.key).collect();
+      keys.sort();
+      keys.dedup();
+      assert_eq!(keys.len(), tags.len(), "{:?}", input);
+      for &(k, v) in tags {
+        assert_eq!(msg.tag(k).as_ref().map(|v| &v[..]), Some(v), "{:?}: {}", input, k);
+      }
+      assert_eq!(msg.prefix.as_ref().map(|p| p.to_string()), source.map(|s| s.to_string()), "{:?}", input);
+      assert_eq!(msg.command.to_string(), verb, "{:?}", input);
+      assert_eq!(msg.params, params, "{:?}", input);
+    }
+  }
+
+  #[test]
+  fn round_trip() {
+    for line in JOIN {
+      let msg = parse::parse_message("test", 0, line).unwrap();
+      assert_eq!(&msg.to_string(), line);
+      let json : serde_json::Value = serde_json::from_str(&msg.serialize(false, false, &[], None)).unwrap();
+      assert_eq!(json["params"], serde_json::to_value(&msg.params).unwrap(), "{:?}", line);
+      for t in msg.tags.iter() {
+        assert_eq!(json["tags"][t.key], serde_json::Value::String(msg.tag(t.key).unwrap()), "{:?}", line);
+      }
+      let written = msg.to_string();
+      let again = parse::parse_message("test", 0, &written).unwrap();
+      assert_eq!(again.params, msg.params);
+      assert_eq!(again.tags, msg.tags);
+    }
+  }
+
+  #[test]
+  fn prefixes() {
+    let source = |line| parse::parse_message("test", 0, line).unwrap().prefix.unwrap().to_string();
+    match parse::parse_message("test", 0, ":localhost 001 me :Welcome").unwrap().prefix {
+      Some(Prefix::Server("localhost")) => (),
+      p => panic!("{:?}", p.map(|p| p.to_string()))
+    }
+    match parse::parse_message("test", 0, ":nick@host PRIVMSG me :hi").unwrap().prefix {
+      Some(Prefix::User("nick", None, Some("host"))) => (),
+      p => panic!("{:?}", p.map(|p| p.to_string()))
+    }
+    assert_eq!(source(":nick!user@host PRIVMSG me :hi"), "nick!user@host");
+    match parse::parse_message("test", 0, ":irc.example.org NOTICE * :Looking up your hostname").unwrap().prefix {
+      Some(Prefix::Server("irc.example.org")) => (),
+      p => panic!("{:?}", p.map(|p| p.to_string()))
+    }
+    match parse::parse_message("test", 0, ":nick MODE nick :+i").unwrap().prefix {
+      Some(Prefix::User("nick", None, None)) => (),
+      p => panic!("{:?}", p.map(|p| p.to_string()))
+    }
+    match parse::parse_message("test", 0, ":nick NICK new").unwrap().prefix {
+      Some(Prefix::User("nick", None, None)) => (),
+      p => panic!("{:?}", p.map(|p| p.to_string()))
+    }
+  }
+
+  #[test]
+  fn tabs() {
+    assert!(parse::parse_message("test", 0, "PRIVMSG\t#chan :hi").is_err());
+    assert!(parse::parse_message("test", 0, "PRIVMSG #chan\t:hi").unwrap().params == vec!["#chan\t:hi"]);
+  }
+
+  #[test]
+  fn nicks() {
+    assert_eq!(next_nick("me", 9), Some("me_".to_string()));
+    assert_eq!(next_nick("somebody_", 9), Some("somebody0".to_string()));
+    assert_eq!(next_nick("somebody0", 9), Some("somebody1".to_string()));
+    assert_eq!(next_nick("somebody9", 9), None);
+    assert_eq!(next_nick("somebodyelse", 9), Some("somebody0".to_string()));
+  }
+
+  #[test]
+  fn who() {
+    let q = Query { command: "WHO", target: "*.example.org".to_string(), casemapping: Casemapping::Rfc1459, sent: 0, answer: Answer::Who(Vec::new()) };
+    assert_eq!(q.wants(352, &["me", "#chan", "user", "host.example.org", "irc.example.net", "nick", "H", "0 Real Name"]), (true, false));
+    assert_eq!(q.wants(352, &["me", "#chan", "user", "host.example.com", "irc.example.net", "nick", "H", "0 Real Name"]), (false, false));
+    assert_eq!(q.wants(315, &["me", "#chan", "End of /WHO list."]), (false, true));
+    assert_eq!(q.wants(315, &["me", "*.example.org", "End of /WHO list."]), (true, true));
+  }
+}
@@ -1,14 +1,24 @@
 use serde_json;
 
 use IRC;
+use SQL;
+use TCP;
 use ZMQ;
 
 impl<'a> IRC::Message<'a> {
-  pub fn serialize(&self) -> String {
+  // ignored is whether an ignore rule said to mark it, highlight whether it mentions us,
+  // buffers the conversations it's part of, and reply the id of the message its
+  // +draft/reply is to, if we have it
+  pub fn serialize(&self, ignored: bool, highlight: bool, buffers: &[IRC::Buffer], reply: Option<i64>) -> String {
+    let mut tags = serde_json::Map::new();
+    for t in self.tags.iter() {
+      tags.insert(t.key.to_string(), serde_json::Value::String(t.value()));
+    }
     return json!({
       "protocol": "IRC",
       "server": self.server,
       "id": self.id,
+      "tags": tags,
       "prefix": match self.prefix {
         None => serde_json::value::Value::Null,
         Some(IRC::Prefix::Server(s)) => json!({
@@ -24,11 +34,43 @@ impl<'a> IRC::Message<'a> {
         IRC::Command::Named(ref s) => serde_json::to_value(s).unwrap(),
         IRC::Command::Numeric(n) => serde_json::to_value(n).unwrap()
       },
-      "params": serde_json::to_value(&self.params).unwrap()
+      "name": self.command.name(),
+      "params": serde_json::to_value(&self.params).unwrap(),
+      "ignored": ignored,
+      "highlight": highlight,
+      "buffers": buffers.iter().map(|b| b.name()).collect::<Vec<&str>>(),
+      "reply": reply
     }).to_string();
   }
 }
 
+// a logged message, in the same shape as IRC::Message::serialize() plus its date
+pub fn stored(m: &SQL::Stored) -> serde_json::Value {
+  return json!({
+    "protocol": "IRC",
+    "server": m.network,
+    "id": m.id,
+    "date": m.date,
+    "prefix": match (&m.server, &m.nick) {
+      (&Some(ref s), _) => json!({
+        "server": s
+      }),
+      (&None, &Some(ref nick)) => json!({
+        "nick": nick,
+        "ident": m.ident,
+        "host": m.host
+      }),
+      (&None, &None) => serde_json::value::Value::Null
+    },
+    "command": match m.numeric {
+      Some(n) => serde_json::to_value(n).unwrap(),
+      None => serde_json::to_value(&m.command).unwrap()
+    },
+    "name": m.numeric.and_then(IRC::reply).map(|r| r.name),
+    "params": serde_json::to_value(&m.params).unwrap()
+  });
+}
+
 pub fn okay(sock: &mut ZMQ::Socket) {
   send(sock,
        json!({
@@ -37,18 +79,500 @@ pub fn okay(sock: &mut ZMQ::Socket) {
        }).to_string());
 }
 
+pub fn error(sock: &mut ZMQ::Socket) {
+  send(sock,
+       json!({
+         "type": "status",
+         "status": -1
+       }).to_string());
+}
+
 pub fn sync(sock: &mut ZMQ::Socket,
             irc: &IRC::Context) {
   send(sock, json!({
     "protocol": "int",
     "operand" : "sync",
-    "nick": irc.nick,
+    "nick": irc.me,
     "ident": irc.ident,
     "realname": irc.realname,
+    "away": irc.away,
+    "identified": irc.identified,
+    "connected": irc.sock.is_some(),
+    // the server we're on, or will try next if we aren't connected
+    "upstream": irc.servers.get(irc.server).map(|s| json!({
+      "index": irc.server,
+      "host": s.host,
+      "port": s.port,
+      "tls": s.tls
+    })),
     "channels": serde_json::to_value(&irc.channels).unwrap()
   }).to_string());
 }
 
+pub fn presence(sock: &mut ZMQ::Socket,
+                irc: &IRC::Context,
+                nick: &str,
+                online: bool) {
+  send(sock, json!({
+    "type": "presence",
+    "protocol": "IRC",
+    "server": irc.id,
+    "nick": nick,
+    "online": online
+  }).to_string());
+}
+
+pub fn contacts(sock: &mut ZMQ::Socket,
+                irc: &IRC::Context) {
+  let contacts : Vec<serde_json::Value> = irc.contacts.iter().map(|c| json!({
+    "nick": c,
+    "online": irc.is_online(c)
+  })).collect();
+  send(sock, json!({
+    "type": "contacts",
+    "protocol": "IRC",
+    "server": irc.id,
+    "contacts": contacts
+  }).to_string());
+}
+
+pub fn away_log(sock: &mut ZMQ::Socket,
+                irc: &IRC::Context,
+                messages: &[SQL::Stored]) {
+  let messages : Vec<serde_json::Value> = messages.iter().map(stored).collect();
+  send(sock, json!({
+    "type": "away log",
+    "protocol": "IRC",
+    "server": irc.id,
+    "away": irc.away,
+    "messages": messages
+  }).to_string());
+}
+
+pub fn services(sock: &mut ZMQ::Socket,
+                irc: &IRC::Context) {
+  let chanserv : Vec<serde_json::Value> = irc.chanserv.iter().map(|cs| json!({
+    "channel": cs.channel,
+    "op": cs.op,
+    "invite": cs.invite,
+    "unban": cs.unban
+  })).collect();
+  send(sock, json!({
+    "type": "services",
+    "protocol": "IRC",
+    "server": irc.id,
+    // never hand the password back out
+    "nickserv": irc.services.as_ref().map(|s| json!({
+      "account": s.account,
+      "regain": s.regain,
+      "wait": s.wait
+    })),
+    "identified": irc.identified,
+    "chanserv": chanserv
+  }).to_string());
+}
+
+pub fn autojoin(sock: &mut ZMQ::Socket,
+                irc: &IRC::Context) {
+  let cm = irc.casemapping();
+  let channels : Vec<serde_json::Value> = irc.autojoin.iter().map(|a| json!({
+    "channel": a.name,
+    "key": a.key,
+    "rejoin": a.rejoin,
+    "joined": irc.channels.iter().any(|c| cm.eq(c, &a.name))
+  })).collect();
+  send(sock, json!({
+    "type": "channels",
+    "protocol": "IRC",
+    "server": irc.id,
+    "channels": channels
+  }).to_string());
+}
+
+pub fn connection(sock: &mut ZMQ::Socket,
+                  irc: &IRC::Context,
+                  o: &TCP::Options) {
+  send(sock, json!({
+    "type": "connection",
+    "protocol": "IRC",
+    "server": irc.id,
+    // never hand the password back out
+    "proxy": o.proxy.as_ref().map(|p| json!({
+      "type": p.kind.name(),
+      "host": p.host,
+      "port": p.port,
+      "username": p.username,
+      "remote_dns": p.remote_dns
+    })),
+    "bind": o.bind.map(|b| b.to_string()),
+    "prefer": o.prefer.name()
+  }).to_string());
+}
+
+pub fn servers(sock: &mut ZMQ::Socket,
+               irc: &IRC::Context) {
+  let servers : Vec<serde_json::Value> = irc.servers.iter().map(|s| json!({
+    "host": s.host,
+    "port": s.port,
+    "tls": s.tls,
+    // never hand the password back out
+    "password": s.password.is_some()
+  })).collect();
+  send(sock, json!({
+    "type": "servers",
+    "protocol": "IRC",
+    "server": irc.id,
+    "enabled": irc.enabled,
+    "connected": irc.sock.is_some(),
+    "current": irc.server,
+    "servers": servers
+  }).to_string());
+}
+
+pub fn policies(sock: &mut ZMQ::Socket,
+                policies: &[IRC::Policy],
+                now: i64) {
+  let policies : Vec<serde_json::Value> = policies.iter().map(|p| json!({
+    "host": p.host,
+    "port": p.port,
+    "expires": p.expires,
+    "active": p.expires > now
+  })).collect();
+  send(sock, json!({
+    "type": "sts",
+    "protocol": "IRC",
+    "policies": policies
+  }).to_string());
+}
+
+pub fn invite(sock: &mut ZMQ::Socket,
+              irc: &IRC::Context,
+              invite: &IRC::Invite,
+              joined: bool) {
+  send(sock, json!({
+    "type": "invite",
+    "protocol": "IRC",
+    "server": irc.id,
+    "from": invite.from,
+    "nick": invite.nick,
+    "channel": invite.channel,
+    "at": invite.at,
+    "joined": joined
+  }).to_string());
+}
+
+pub fn invites(sock: &mut ZMQ::Socket,
+               irc: &IRC::Context) {
+  let invites : Vec<serde_json::Value> = irc.invites.iter().map(|i| json!({
+    "from": i.from,
+    "channel": i.channel,
+    "at": i.at
+  })).collect();
+  send(sock, json!({
+    "type": "invites",
+    "protocol": "IRC",
+    "server": irc.id,
+    "invites": invites,
+    "trusted": irc.trusted
+  }).to_string());
+}
+
+// a netsplit or netjoin that's gone quiet, in place of its quits or joins
+pub fn split(sock: &mut ZMQ::Socket,
+             irc: &IRC::Context,
+             kind: &str,
+             split: &IRC::Split) {
+  send(sock, json!({
+    "type": kind,
+    "protocol": "IRC",
+    "server": irc.id,
+    "servers": [split.servers.0, split.servers.1],
+    "nicks": split.nicks,
+    "channels": split.channels,
+    "at": split.at
+  }).to_string());
+}
+
+// a rule that's just been added
+pub fn added(sock: &mut ZMQ::Socket, rule: i64) {
+  send(sock,
+       json!({
+         "type": "status",
+         "status": 0,
+         "rule": rule
+       }).to_string());
+}
+
+pub fn ignores(sock: &mut ZMQ::Socket,
+               ignores: &[IRC::Ignore]) {
+  let rules : Vec<serde_json::Value> = ignores.iter().map(|i| json!({
+    "rule": i.rule,
+    "id": i.network,
+    "kind": i.who.kind(),
+    "pattern": i.who.pattern(),
+    "commands": i.commands,
+    "channels": i.channels,
+    "action": i.action.name()
+  })).collect();
+  send(sock, json!({
+    "type": "ignores",
+    "protocol": "IRC",
+    "rules": rules
+  }).to_string());
+}
+
+pub fn highlight_rules(sock: &mut ZMQ::Socket,
+                       rules: &[IRC::Highlight]) {
+  let rules : Vec<serde_json::Value> = rules.iter().map(|h| json!({
+    "rule": h.rule,
+    "id": h.network,
+    "kind": h.what.kind(),
+    "pattern": h.what.pattern(),
+    "channels": h.channels,
+    "exclude": h.exclude
+  })).collect();
+  send(sock, json!({
+    "type": "highlight rules",
+    "protocol": "IRC",
+    "rules": rules
+  }).to_string());
+}
+
+// a message that mentions us, as it arrives
+pub fn highlight(sock: &mut ZMQ::Socket,
+                 irc: &IRC::Context,
+                 msg: &IRC::Message) {
+  send(sock, json!({
+    "type": "highlight",
+    "protocol": "IRC",
+    "server": irc.id,
+    "id": msg.id,
+    "target": msg.params.get(0),
+    "nick": match msg.prefix {
+      Some(IRC::Prefix::User(nick, ..)) => Some(nick),
+      _ => None
+    }
+  }).to_string());
+}
+
+pub fn highlights(sock: &mut ZMQ::Socket,
+                  messages: &[SQL::Stored]) {
+  let messages : Vec<serde_json::Value> = messages.iter().map(stored).collect();
+  send(sock, json!({
+    "type": "highlights",
+    "protocol": "IRC",
+    "messages": messages
+  }).to_string());
+}
+
+// a buffer that's just got its first message
+pub fn buffer(sock: &mut ZMQ::Socket,
+              irc: &IRC::Context,
+              buffer: i64,
+              b: &IRC::Buffer) {
+  send(sock, json!({
+    "type": "buffer",
+    "protocol": "IRC",
+    "server": irc.id,
+    "buffer": buffer,
+    "name": b.name(),
+    "kind": b.kind()
+  }).to_string());
+}
+
+pub fn buffers(sock: &mut ZMQ::Socket,
+               irc: &IRC::Context,
+               buffers: &[(i64, String, String, Option<i64>)]) {
+  let buffers : Vec<serde_json::Value> = buffers.iter().map(|&(buffer, ref name, ref kind, latest)| json!({
+    "buffer": buffer,
+    "name": name,
+    "kind": kind,
+    "latest": latest
+  })).collect();
+  send(sock, json!({
+    "type": "buffers",
+    "protocol": "IRC",
+    "server": irc.id,
+    "buffers": buffers
+  }).to_string());
+}
+
+pub fn buffered(sock: &mut ZMQ::Socket,
+                irc: &IRC::Context,
+                name: &str,
+                messages: &[SQL::Stored]) {
+  let messages : Vec<serde_json::Value> = messages.iter().map(stored).collect();
+  send(sock, json!({
+    "type": "buffer messages",
+    "protocol": "IRC",
+    "server": irc.id,
+    "name": name,
+    "messages": messages
+  }).to_string());
+}
+
+fn marker(m: &SQL::Marker) -> serde_json::Value {
+  return json!({
+    "name": m.name,
+    "read": m.read,
+    "unread": m.unread,
+    "highlights": m.highlights
+  });
+}
+
+// a buffer's read marker has moved
+pub fn read(sock: &mut ZMQ::Socket,
+            irc: &IRC::Context,
+            m: &SQL::Marker) {
+  let mut read = marker(m);
+  read["type"] = json!("read");
+  read["protocol"] = json!("IRC");
+  read["server"] = json!(irc.id);
+  send(sock, read.to_string());
+}
+
+pub fn markers(sock: &mut ZMQ::Socket,
+               irc: &IRC::Context,
+               markers: &[SQL::Marker]) {
+  let markers : Vec<serde_json::Value> = markers.iter().map(marker).collect();
+  send(sock, json!({
+    "type": "read markers",
+    "protocol": "IRC",
+    "server": irc.id,
+    "buffers": markers
+  }).to_string());
+}
+
+pub fn typing(sock: &mut ZMQ::Socket,
+              irc: &IRC::Context,
+              t: &IRC::Typing) {
+  send(sock, json!({
+    "type": "typing",
+    "protocol": "IRC",
+    "server": irc.id,
+    "name": t.buffer.name(),
+    "nick": t.nick,
+    "state": t.state
+  }).to_string());
+}
+
+// a reaction to a message we have, as it arrives
+pub fn react(sock: &mut ZMQ::Socket,
+             irc: &IRC::Context,
+             msg: &IRC::Message,
+             to: i64,
+             react: &str) {
+  send(sock, json!({
+    "type": "react",
+    "protocol": "IRC",
+    "server": irc.id,
+    "id": msg.id,
+    "to": to,
+    "nick": match msg.prefix {
+      Some(IRC::Prefix::User(nick, ..)) => Some(nick),
+      _ => None
+    },
+    "react": react
+  }).to_string());
+}
+
+pub fn reactions(sock: &mut ZMQ::Socket,
+                 irc: &IRC::Context,
+                 id: i64,
+                 reactions: &[(String, Vec<String>)]) {
+  let reactions : Vec<serde_json::Value> = reactions.iter().map(|&(ref react, ref nicks)| json!({
+    "react": react,
+    "nicks": nicks
+  })).collect();
+  send(sock, json!({
+    "type": "reactions",
+    "protocol": "IRC",
+    "server": irc.id,
+    "message": id,
+    "reactions": reactions
+  }).to_string());
+}
+
+pub fn matched(sock: &mut ZMQ::Socket, matches: bool) {
+  send(sock,
+       json!({
+         "type": "mask",
+         "protocol": "IRC",
+         "matches": matches
+       }).to_string());
+}
+
+pub fn mask(sock: &mut ZMQ::Socket, mask: &str) {
+  send(sock,
+       json!({
+         "type": "mask",
+         "protocol": "IRC",
+         "mask": mask
+       }).to_string());
+}
+
+// the reply to a query, once the server has finished answering it
+pub fn answer(sock: &mut ZMQ::Socket,
+              irc: &IRC::Context,
+              answer: &Result<IRC::Answer, String>) {
+  let a = match *answer {
+    Err(ref reason) => {
+      send(sock, json!({
+        "type": "status",
+        "status": -1,
+        "reason": reason
+      }).to_string());
+      return;
+    },
+    Ok(ref a) => a
+  };
+  let (query, result) = match *a {
+    IRC::Answer::Whois(ref w) => ("whois", json!({
+      "nick": w.nick,
+      "user": w.user,
+      "host": w.host,
+      "realname": w.realname,
+      "server": w.server,
+      "server_info": w.server_info,
+      "channels": w.channels,
+      "idle": w.idle,
+      "signon": w.signon,
+      "account": w.account,
+      "away": w.away,
+      "operator": w.operator,
+      "secure": w.secure
+    })),
+    IRC::Answer::Who(ref who) => ("who", serde_json::Value::Array(who.iter().map(|w| json!({
+      "channel": w.channel,
+      "user": w.user,
+      "host": w.host,
+      "server": w.server,
+      "nick": w.nick,
+      "flags": w.flags,
+      "hops": w.hops,
+      "realname": w.realname
+    })).collect())),
+    IRC::Answer::Names(ref names) => ("names", serde_json::to_value(names).unwrap()),
+    IRC::Answer::List(ref list) => ("list", serde_json::Value::Array(list.iter().map(|l| json!({
+      "channel": l.channel,
+      "users": l.users,
+      "topic": l.topic
+    })).collect())),
+    IRC::Answer::Bans(ref bans) => ("bans", serde_json::Value::Array(bans.iter().map(|b| json!({
+      "mask": b.mask,
+      "by": b.by,
+      "at": b.at
+    })).collect()))
+  };
+  send(sock, json!({
+    "type": "query",
+    "protocol": "IRC",
+    "server": irc.id,
+    "query": query,
+    "result": result
+  }).to_string());
+}
+
 fn send(sock: &mut ZMQ::Socket, s: String) {
   let msg = ZMQ::Msg::new_with_size(s.len());
   msg.data().clone_from_slice(s.as_bytes());
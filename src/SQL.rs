@@ -0,0 +1,731 @@
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+
+use error::*;
+use IRC;
+use TCP;
+
+// a message as it was logged, see messages in schema.sql
+pub struct Stored {
+  pub network: String,
+  pub id: i64,
+  pub date: i64,
+
+  pub server: Option<String>,
+  pub nick: Option<String>,
+  pub ident: Option<String>,
+  pub host: Option<String>,
+
+  pub command: Option<String>,
+  pub numeric: Option<u16>,
+  pub params: Vec<String>
+}
+
+// the columns of messages in the order stored() expects them
+const COLUMNS : &'static str = "messages.network, messages.id, messages.date, messages.server, messages.nick, messages.ident, messages.host, messages.command, messages.numeric";
+// leaves out messages an ignore rule hid, for anything sent to IRC clients
+const VISIBLE : &'static str = "NOT EXISTS (SELECT 1 FROM hidden WHERE hidden.network = messages.network AND hidden.id = messages.id)";
+
+// run a query selecting COLUMNS, and fill in the params of each message it returns
+fn stored(sql: &Connection, query: &str, args: &[&ToSql]) -> Result<Vec<Stored>> {
+  let mut ret = Vec::new();
+  {
+    let mut stmt = sql.prepare(query).chain_err(|| "SQLite: prepare failure in message lookup")?;
+    let rows = stmt.query_map(args, |r| Stored {
+      network: r.get(0),
+      id: r.get(1),
+      date: r.get(2),
+      server: r.get(3),
+      nick: r.get(4),
+      ident: r.get(5),
+      host: r.get(6),
+      command: r.get(7),
+      numeric: r.get::<_, Option<i64>>(8).map(|n| n as u16),
+      params: Vec::new()
+    }).chain_err(|| "SQLite: query failure in message lookup")?;
+    for m in rows {
+      ret.push(m.chain_err(|| "SQLite: row failure in message lookup")?);
+    }
+  }
+  for m in ret.iter_mut() {
+    m.params = params(sql, &m.network, m.id)?;
+  }
+  return Ok(ret);
+}
+
+fn params(sql: &Connection, network: &str, id: i64) -> Result<Vec<String>> {
+  let mut stmt = sql.prepare("SELECT param FROM params WHERE network = ?1 AND id = ?2 ORDER BY idx")
+    .chain_err(|| "SQLite: prepare failure in param lookup")?;
+  let rows = stmt.query_map(&[&network, &id], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in param lookup")?;
+  let mut ret = Vec::new();
+  for p in rows {
+    ret.push(p.chain_err(|| "SQLite: row failure in param lookup")?);
+  }
+  return Ok(ret);
+}
+
+pub fn contacts(sql: &Connection, network: &str) -> Result<Vec<String>> {
+  let mut stmt = sql.prepare("SELECT nick FROM contacts WHERE network = ?1 ORDER BY nick")
+    .chain_err(|| "SQLite: prepare failure in contact lookup")?;
+  let rows = stmt.query_map(&[&network], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in contact lookup")?;
+  let mut ret = Vec::new();
+  for nick in rows {
+    ret.push(nick.chain_err(|| "SQLite: row failure in contact lookup")?);
+  }
+  return Ok(ret);
+}
+
+pub fn contact_add(sql: &Connection, network: &str, nick: &str) -> Result<()> {
+  sql.execute("INSERT OR IGNORE INTO contacts (network, nick) VALUES (?1, ?2)", &[&network, &nick])
+    .chain_err(|| "SQLite: insert failure in contact add")?;
+  return Ok(());
+}
+
+pub fn contact_remove(sql: &Connection, network: &str, nick: &str) -> Result<()> {
+  sql.execute("DELETE FROM contacts WHERE network = ?1 AND nick = ?2", &[&network, &nick])
+    .chain_err(|| "SQLite: delete failure in contact remove")?;
+  return Ok(());
+}
+
+pub fn away_log(sql: &Connection, network: &str) -> Result<Vec<Stored>> {
+  return stored(sql,
+                &format!("SELECT {} FROM messages JOIN away_log USING (network, id) WHERE network = ?1 ORDER BY id", COLUMNS),
+                &[&network]);
+}
+
+pub fn away_log_add(sql: &Connection, network: &str, id: i64) -> Result<()> {
+  sql.execute("INSERT OR IGNORE INTO away_log (network, id) VALUES (?1, ?2)", &[&network, &id])
+    .chain_err(|| "SQLite: insert failure in away log add")?;
+  return Ok(());
+}
+
+pub fn away_log_clear(sql: &Connection, network: &str) -> Result<()> {
+  sql.execute("DELETE FROM away_log WHERE network = ?1", &[&network])
+    .chain_err(|| "SQLite: delete failure in away log clear")?;
+  return Ok(());
+}
+
+// channels and queries with messages or notices after id, in the order the first of
+// them arrived, for playing back to an IRC client; the server buffer is never played back
+pub fn missed(sql: &Connection, network: &str, id: i64) -> Result<Vec<String>> {
+  let mut stmt = sql.prepare(&format!("SELECT buffers.name FROM buffers JOIN buffered ON buffered.buffer = buffers.buffer JOIN messages ON messages.network = buffered.network AND messages.id = buffered.id WHERE buffers.network = ?1 AND buffers.kind != 'server' AND messages.id > ?2 AND messages.command IN ('PRIVMSG', 'NOTICE') AND {} GROUP BY buffers.buffer ORDER BY MIN(messages.id)", VISIBLE))
+    .chain_err(|| "SQLite: prepare failure in missed lookup")?;
+  let rows = stmt.query_map(&[&network, &id], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in missed lookup")?;
+  let mut ret = Vec::new();
+  for b in rows {
+    ret.push(b.chain_err(|| "SQLite: row failure in missed lookup")?);
+  }
+  return Ok(ret);
+}
+
+// the newest limit messages and notices in a buffer after id, oldest first
+pub fn since(sql: &Connection, network: &str, name: &str, id: i64, limit: usize) -> Result<Vec<Stored>> {
+  let mut ret = stored(sql,
+                       &format!("SELECT {} FROM messages JOIN buffered ON buffered.network = messages.network AND buffered.id = messages.id JOIN buffers ON buffers.buffer = buffered.buffer WHERE buffers.network = ?1 AND buffers.name = ?2 AND messages.id > ?3 AND messages.command IN ('PRIVMSG', 'NOTICE') AND {} ORDER BY messages.id DESC LIMIT {}", COLUMNS, VISIBLE, limit),
+                       &[&network, &name, &id])?;
+  ret.reverse();
+  return Ok(ret);
+}
+
+// a buffer's number, adding it if it's new, and whether it was
+pub fn buffer(sql: &Connection, network: &str, b: &IRC::Buffer) -> Result<(i64, bool)> {
+  let added = sql.execute("INSERT OR IGNORE INTO buffers (network, name, kind) VALUES (?1, ?2, ?3)", &[&network, &b.name(), &b.kind()])
+    .chain_err(|| "SQLite: insert failure in buffer addition")?;
+  let buffer = sql.query_row("SELECT buffer FROM buffers WHERE network = ?1 AND name = ?2", &[&network, &b.name()], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in buffer lookup")?;
+  return Ok((buffer, added > 0));
+}
+
+// every buffer on a network, with the id of its newest message
+pub fn buffers(sql: &Connection, network: &str) -> Result<Vec<(i64, String, String, Option<i64>)>> {
+  let mut stmt = sql.prepare("SELECT buffers.buffer, buffers.name, buffers.kind, MAX(buffered.id) FROM buffers LEFT JOIN buffered ON buffered.buffer = buffers.buffer WHERE buffers.network = ?1 GROUP BY buffers.buffer ORDER BY buffers.buffer")
+    .chain_err(|| "SQLite: prepare failure in buffer lookup")?;
+  let rows = stmt.query_map(&[&network], |r| (r.get(0), r.get(1), r.get(2), r.get(3)))
+    .chain_err(|| "SQLite: query failure in buffer lookup")?;
+  let mut ret = Vec::new();
+  for b in rows {
+    ret.push(b.chain_err(|| "SQLite: row failure in buffer lookup")?);
+  }
+  return Ok(ret);
+}
+
+// the newest limit messages in a buffer from before id, if given, oldest first
+pub fn buffered(sql: &Connection, network: &str, name: &str, before: Option<i64>, limit: usize) -> Result<Vec<Stored>> {
+  let mut ret = stored(sql,
+                       &format!("SELECT {} FROM messages JOIN buffered ON buffered.network = messages.network AND buffered.id = messages.id JOIN buffers ON buffers.buffer = buffered.buffer WHERE buffers.network = ?1 AND buffers.name = ?2 AND messages.id < ?3 ORDER BY messages.id DESC LIMIT {}", COLUMNS, limit),
+                       &[&network, &name, &before.unwrap_or(i64::max_value())])?;
+  ret.reverse();
+  return Ok(ret);
+}
+
+// how far we've read in a buffer, and how many messages and notices, and highlights
+// among them, came after that
+pub struct Marker {
+  pub name: String,
+  pub read: Option<i64>,
+  pub unread: i64,
+  pub highlights: i64
+}
+
+// read markers for every buffer on a network, or just the one named
+fn markers_where(sql: &Connection, network: &str, name: Option<&str>) -> Result<Vec<Marker>> {
+  let query = format!("SELECT buffers.name, read_markers.id, \
+                       (SELECT COUNT(*) FROM buffered JOIN messages ON messages.network = buffered.network AND messages.id = buffered.id WHERE buffered.buffer = buffers.buffer AND buffered.id > IFNULL(read_markers.id, -1) AND messages.command IN ('PRIVMSG', 'NOTICE')), \
+                       (SELECT COUNT(*) FROM buffered JOIN highlights ON highlights.network = buffered.network AND highlights.id = buffered.id WHERE buffered.buffer = buffers.buffer AND buffered.id > IFNULL(read_markers.id, -1)) \
+                       FROM buffers LEFT JOIN read_markers ON read_markers.buffer = buffers.buffer WHERE buffers.network = ?1 {} ORDER BY buffers.buffer",
+                      if name.is_some() { "AND buffers.name = ?2" } else { "" });
+  let mut stmt = sql.prepare(&query).chain_err(|| "SQLite: prepare failure in read marker lookup")?;
+  let row = |r: &::rusqlite::Row| Marker {
+    name: r.get(0),
+    read: r.get(1),
+    unread: r.get(2),
+    highlights: r.get(3)
+  };
+  let rows = match name {
+    None => stmt.query_map(&[&network], row),
+    Some(ref name) => stmt.query_map(&[&network, name], row)
+  }.chain_err(|| "SQLite: query failure in read marker lookup")?;
+  let mut ret = Vec::new();
+  for m in rows {
+    ret.push(m.chain_err(|| "SQLite: row failure in read marker lookup")?);
+  }
+  return Ok(ret);
+}
+
+pub fn markers(sql: &Connection, network: &str) -> Result<Vec<Marker>> {
+  return markers_where(sql, network, None);
+}
+
+pub fn marker(sql: &Connection, network: &str, name: &str) -> Result<Option<Marker>> {
+  return Ok(markers_where(sql, network, Some(name))?.pop());
+}
+
+// move a buffer's read marker forward to id, never back, saying whether it moved
+pub fn mark(sql: &Connection, network: &str, name: &str, id: i64) -> Result<bool> {
+  let added = sql.execute("INSERT OR IGNORE INTO read_markers (buffer, id) SELECT buffer, ?3 FROM buffers WHERE network = ?1 AND name = ?2",
+                          &[&network, &name, &id])
+    .chain_err(|| "SQLite: insert failure in read marker")?;
+  let moved = sql.execute("UPDATE read_markers SET id = ?3 WHERE id < ?3 AND buffer = (SELECT buffer FROM buffers WHERE network = ?1 AND name = ?2)",
+                          &[&network, &name, &id])
+    .chain_err(|| "SQLite: update failure in read marker")?;
+  return Ok(added + moved > 0);
+}
+
+// the newest message in a buffer from no later than date
+pub fn read_by(sql: &Connection, network: &str, name: &str, date: i64) -> Result<Option<i64>> {
+  return sql.query_row("SELECT MAX(messages.id) FROM messages JOIN buffered ON buffered.network = messages.network AND buffered.id = messages.id JOIN buffers ON buffers.buffer = buffered.buffer WHERE buffers.network = ?1 AND buffers.name = ?2 AND messages.date <= ?3",
+                       &[&network, &name, &date], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in read marker lookup");
+}
+
+// the newest message the network gave a msgid, for the +draft/reply of a reply or reaction
+pub fn msgid(sql: &Connection, network: &str, msgid: &str) -> Result<Option<i64>> {
+  return sql.query_row("SELECT MAX(id) FROM tags WHERE network = ?1 AND key = 'msgid' AND value = ?2",
+                       &[&network, &msgid], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in msgid lookup");
+}
+
+// the reactions to a message, each with the nicks that reacted that way, in the order
+// they first came
+pub fn reactions(sql: &Connection, network: &str, id: i64) -> Result<Vec<(String, Vec<String>)>> {
+  let mut stmt = sql.prepare("SELECT react.value, messages.nick FROM tags AS target \
+                              JOIN tags AS reply ON reply.network = target.network AND reply.key = '+draft/reply' AND reply.value = target.value \
+                              JOIN tags AS react ON react.network = reply.network AND react.id = reply.id AND react.key = '+draft/react' \
+                              JOIN messages ON messages.network = reply.network AND messages.id = reply.id \
+                              WHERE target.network = ?1 AND target.id = ?2 AND target.key = 'msgid' ORDER BY reply.id")
+    .chain_err(|| "SQLite: prepare failure in reaction lookup")?;
+  let rows = stmt.query_map(&[&network, &id], |r| (r.get::<_, String>(0), r.get::<_, Option<String>>(1)))
+    .chain_err(|| "SQLite: query failure in reaction lookup")?;
+  let mut ret : Vec<(String, Vec<String>)> = Vec::new();
+  for row in rows {
+    let (react, nick) = row.chain_err(|| "SQLite: row failure in reaction lookup")?;
+    let nick = match nick {
+      Some(n) => n,
+      None => continue
+    };
+    if !ret.iter().any(|r| r.0 == react) {
+      ret.push((react.clone(), Vec::new()));
+    }
+    let nicks = &mut ret.iter_mut().find(|r| r.0 == react).unwrap().1;
+    if !nicks.iter().any(|n| n.eq_ignore_ascii_case(&nick)) {
+      nicks.push(nick);
+    }
+  }
+  return Ok(ret);
+}
+
+// one end of a range of messages, by id or by date
+pub enum Bound {
+  Id(i64),
+  Date(i64)
+}
+impl Bound {
+  fn condition(&self, op: &str) -> String {
+    return match *self {
+      Bound::Id(id) => format!("messages.id {} {}", op, id),
+      Bound::Date(date) => format!("messages.date {} {}", op, date)
+    };
+  }
+}
+
+// messages and notices in a channel, or a query with nick when me is set, strictly
+// between the bounds; the newest limit of them if newest is set and the oldest otherwise,
+// but always returned oldest first
+pub fn history(sql: &Connection, network: &str, target: &str, me: Option<&str>,
+               after: Option<&Bound>, before: Option<&Bound>, newest: bool, limit: usize) -> Result<Vec<Stored>> {
+  let mut conditions = vec!["messages.network = ?1".to_string(),
+                            "messages.command IN ('PRIVMSG', 'NOTICE')".to_string(),
+                            VISIBLE.to_string()];
+  conditions.push(match me {
+    None => "target.param = ?2 COLLATE NOCASE".to_string(),
+    // our own side of a query isn't logged, only what they sent us
+    Some(_) => "messages.nick = ?2 COLLATE NOCASE AND target.param = ?3 COLLATE NOCASE".to_string()
+  });
+  conditions.extend(after.map(|b| b.condition(">")));
+  conditions.extend(before.map(|b| b.condition("<")));
+  let query = format!("SELECT {} FROM messages JOIN params AS target ON target.network = messages.network AND target.id = messages.id AND target.idx = 0 WHERE {} ORDER BY messages.id {} LIMIT {}",
+                      COLUMNS, conditions.join(" AND "), if newest { "DESC" } else { "ASC" }, limit);
+  let mut ret = match me {
+    None => stored(sql, &query, &[&network, &target])?,
+    Some(me) => stored(sql, &query, &[&network, &target, &me])?
+  };
+  if newest {
+    ret.reverse();
+  }
+  return Ok(ret);
+}
+
+// channels and queries with messages between two dates, with the date of the newest,
+// the first limit of them by that date
+pub fn targets(sql: &Connection, network: &str, chantypes: &str, from: i64, to: i64, limit: usize) -> Result<Vec<(String, i64)>> {
+  let chantypes : Vec<String> = chantypes.chars().map(|c| format!("'{}'", c.to_string().replace("'", "''"))).collect();
+  let query = format!("SELECT CASE WHEN substr(target.param, 1, 1) IN ({}) THEN target.param ELSE messages.nick END AS buffer, MAX(messages.date) AS latest FROM messages JOIN params AS target ON target.network = messages.network AND target.id = messages.id AND target.idx = 0 WHERE messages.network = ?1 AND messages.command IN ('PRIVMSG', 'NOTICE') AND messages.date > ?2 AND messages.date < ?3 AND {} GROUP BY lower(buffer) ORDER BY latest LIMIT {}",
+                      chantypes.join(", "), VISIBLE, limit);
+  let mut stmt = sql.prepare(&query).chain_err(|| "SQLite: prepare failure in target lookup")?;
+  let rows = stmt.query_map(&[&network, &from.min(to), &from.max(to)], |r| (r.get(0), r.get(1)))
+    .chain_err(|| "SQLite: query failure in target lookup")?;
+  let mut ret = Vec::new();
+  for t in rows {
+    ret.push(t.chain_err(|| "SQLite: row failure in target lookup")?);
+  }
+  return Ok(ret);
+}
+
+// when a message on a network was logged, None if there's no such message
+pub fn date(sql: &Connection, network: &str, id: i64) -> Result<Option<i64>> {
+  let mut stmt = sql.prepare("SELECT date FROM messages WHERE network = ?1 AND id = ?2")
+    .chain_err(|| "SQLite: prepare failure in date lookup")?;
+  let mut rows = stmt.query_map(&[&network, &id], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in date lookup")?;
+  return match rows.next() {
+    Some(date) => Ok(Some(date.chain_err(|| "SQLite: row failure in date lookup")?)),
+    None => Ok(None)
+  };
+}
+
+// the newest message we've logged on a network
+pub fn last_id(sql: &Connection, network: &str) -> Result<Option<i64>> {
+  return sql.query_row("SELECT MAX(id) FROM messages WHERE network = ?1", &[&network], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in last id lookup");
+}
+
+// the last message a named IRC client was sent, None if it's never attached before
+pub fn delivered(sql: &Connection, client: &str, network: &str) -> Result<Option<i64>> {
+  let mut stmt = sql.prepare("SELECT id FROM delivered WHERE client = ?1 AND network = ?2")
+    .chain_err(|| "SQLite: prepare failure in delivered lookup")?;
+  let mut rows = stmt.query_map(&[&client, &network], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in delivered lookup")?;
+  return match rows.next() {
+    Some(id) => Ok(Some(id.chain_err(|| "SQLite: row failure in delivered lookup")?)),
+    None => Ok(None)
+  };
+}
+
+pub fn delivered_set(sql: &Connection, client: &str, network: &str, id: i64) -> Result<()> {
+  sql.execute("INSERT OR REPLACE INTO delivered (client, network, id) VALUES (?1, ?2, ?3)", &[&client, &network, &id])
+    .chain_err(|| "SQLite: insert failure in delivered update")?;
+  return Ok(());
+}
+
+pub fn services(sql: &Connection, network: &str) -> Result<Option<IRC::Services>> {
+  let mut stmt = sql.prepare("SELECT account, password, regain, wait FROM services WHERE network = ?1")
+    .chain_err(|| "SQLite: prepare failure in services lookup")?;
+  let mut rows = stmt.query_map(&[&network], |r| IRC::Services {
+    account: r.get(0),
+    password: r.get(1),
+    regain: r.get(2),
+    wait: r.get(3)
+  }).chain_err(|| "SQLite: query failure in services lookup")?;
+  return match rows.next() {
+    Some(s) => Ok(Some(s.chain_err(|| "SQLite: row failure in services lookup")?)),
+    None => Ok(None)
+  };
+}
+
+pub fn services_set(sql: &Connection, network: &str, s: &IRC::Services) -> Result<()> {
+  sql.execute("INSERT OR REPLACE INTO services (network, account, password, regain, wait) VALUES (?1, ?2, ?3, ?4, ?5)",
+              &[&network, &s.account, &s.password, &s.regain, &s.wait])
+    .chain_err(|| "SQLite: insert failure in services set")?;
+  return Ok(());
+}
+
+pub fn services_unset(sql: &Connection, network: &str) -> Result<()> {
+  sql.execute("DELETE FROM services WHERE network = ?1", &[&network])
+    .chain_err(|| "SQLite: delete failure in services unset")?;
+  return Ok(());
+}
+
+pub fn chanserv(sql: &Connection, network: &str) -> Result<Vec<IRC::ChanServ>> {
+  let mut stmt = sql.prepare("SELECT channel, op, invite, unban FROM chanserv WHERE network = ?1 ORDER BY channel")
+    .chain_err(|| "SQLite: prepare failure in chanserv lookup")?;
+  let rows = stmt.query_map(&[&network], |r| IRC::ChanServ {
+    channel: r.get(0),
+    op: r.get(1),
+    invite: r.get(2),
+    unban: r.get(3)
+  }).chain_err(|| "SQLite: query failure in chanserv lookup")?;
+  let mut ret = Vec::new();
+  for cs in rows {
+    ret.push(cs.chain_err(|| "SQLite: row failure in chanserv lookup")?);
+  }
+  return Ok(ret);
+}
+
+pub fn chanserv_set(sql: &Connection, network: &str, cs: &IRC::ChanServ) -> Result<()> {
+  sql.execute("INSERT OR REPLACE INTO chanserv (network, channel, op, invite, unban) VALUES (?1, ?2, ?3, ?4, ?5)",
+              &[&network, &cs.channel, &cs.op, &cs.invite, &cs.unban])
+    .chain_err(|| "SQLite: insert failure in chanserv set")?;
+  return Ok(());
+}
+
+pub fn chanserv_remove(sql: &Connection, network: &str, channel: &str) -> Result<()> {
+  sql.execute("DELETE FROM chanserv WHERE network = ?1 AND channel = ?2", &[&network, &channel])
+    .chain_err(|| "SQLite: delete failure in chanserv remove")?;
+  return Ok(());
+}
+
+pub fn autojoin(sql: &Connection, network: &str) -> Result<Vec<IRC::Channel>> {
+  let mut stmt = sql.prepare("SELECT channel, key, rejoin FROM autojoin WHERE network = ?1 ORDER BY channel")
+    .chain_err(|| "SQLite: prepare failure in autojoin lookup")?;
+  let rows = stmt.query_map(&[&network], |r| IRC::Channel {
+    name: r.get(0),
+    key: r.get(1),
+    rejoin: r.get(2)
+  }).chain_err(|| "SQLite: query failure in autojoin lookup")?;
+  let mut ret = Vec::new();
+  for c in rows {
+    ret.push(c.chain_err(|| "SQLite: row failure in autojoin lookup")?);
+  }
+  return Ok(ret);
+}
+
+pub fn autojoin_set(sql: &Connection, network: &str, c: &IRC::Channel) -> Result<()> {
+  sql.execute("INSERT OR REPLACE INTO autojoin (network, channel, key, rejoin) VALUES (?1, ?2, ?3, ?4)",
+              &[&network, &c.name, &c.key, &c.rejoin])
+    .chain_err(|| "SQLite: insert failure in autojoin set")?;
+  return Ok(());
+}
+
+pub fn autojoin_remove(sql: &Connection, network: &str, channel: &str) -> Result<()> {
+  sql.execute("DELETE FROM autojoin WHERE network = ?1 AND channel = ?2", &[&network, &channel])
+    .chain_err(|| "SQLite: delete failure in autojoin remove")?;
+  return Ok(());
+}
+
+pub fn connection(sql: &Connection, network: &str) -> Result<TCP::Options> {
+  let mut stmt = sql.prepare("SELECT proxy, proxy_host, proxy_port, proxy_username, proxy_password, remote_dns, bind, prefer FROM connection WHERE network = ?1")
+    .chain_err(|| "SQLite: prepare failure in connection lookup")?;
+  let mut rows = stmt.query_map(&[&network], |r| {
+    let proxy = r.get::<_, Option<String>>(0).and_then(|p| TCP::ProxyKind::from_name(&p));
+    TCP::Options {
+      proxy: proxy.map(|kind| TCP::Proxy {
+        kind: kind,
+        host: r.get(1),
+        port: r.get::<_, i64>(2) as u16,
+        username: r.get(3),
+        password: r.get(4),
+        remote_dns: r.get(5)
+      }),
+      bind: r.get::<_, Option<String>>(6).and_then(|b| b.parse().ok()),
+      prefer: TCP::Prefer::from_name(r.get::<_, Option<String>>(7).as_ref().map(|p| &p[..])).unwrap_or(TCP::Prefer::Any)
+    }
+  }).chain_err(|| "SQLite: query failure in connection lookup")?;
+  return match rows.next() {
+    Some(o) => Ok(o.chain_err(|| "SQLite: row failure in connection lookup")?),
+    None => Ok(TCP::Options::default())
+  };
+}
+
+pub fn connection_set(sql: &Connection, network: &str, o: &TCP::Options) -> Result<()> {
+  let bind = o.bind.map(|b| b.to_string());
+  let prefer = o.prefer.name();
+  match o.proxy {
+    Some(ref p) => {
+      let port = p.port as i64;
+      sql.execute("INSERT OR REPLACE INTO connection (network, proxy, proxy_host, proxy_port, proxy_username, proxy_password, remote_dns, bind, prefer) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                  &[&network, &p.kind.name(), &p.host, &port, &p.username, &p.password, &p.remote_dns, &bind, &prefer])
+    },
+    None => {
+      sql.execute("INSERT OR REPLACE INTO connection (network, bind, prefer) VALUES (?1, ?2, ?3)",
+                  &[&network, &bind, &prefer])
+    }
+  }.chain_err(|| "SQLite: insert failure in connection set")?;
+  return Ok(());
+}
+
+pub fn connection_unset(sql: &Connection, network: &str) -> Result<()> {
+  sql.execute("DELETE FROM connection WHERE network = ?1", &[&network])
+    .chain_err(|| "SQLite: delete failure in connection unset")?;
+  return Ok(());
+}
+
+// everything stored about a network, put into its context
+pub fn load(sql: &Connection, irc: &mut IRC::Context) -> Result<()> {
+  irc.servers = servers(sql, &irc.id)?;
+  irc.enabled = enabled(sql, &irc.id)?;
+  irc.options = connection(sql, &irc.id)?;
+  irc.contacts = contacts(sql, &irc.id)?;
+  irc.services = services(sql, &irc.id)?;
+  irc.chanserv = chanserv(sql, &irc.id)?;
+  irc.autojoin = autojoin(sql, &irc.id)?;
+  irc.policies = policies(sql)?;
+  irc.trusted = trusted(sql, &irc.id)?;
+  return Ok(());
+}
+
+// every ignore rule, skipping any that no longer make sense
+pub fn ignores(sql: &Connection) -> Result<Vec<IRC::Ignore>> {
+  let mut stmt = sql.prepare("SELECT rule, network, kind, pattern, commands, channels, action FROM ignores ORDER BY rule")
+    .chain_err(|| "SQLite: prepare failure in ignore lookup")?;
+  let rows = stmt.query_map(&[], |r| (r.get::<_, i64>(0), r.get::<_, Option<String>>(1), r.get::<_, String>(2), r.get::<_, String>(3),
+                                      r.get::<_, String>(4), r.get::<_, String>(5), r.get::<_, String>(6)))
+    .chain_err(|| "SQLite: query failure in ignore lookup")?;
+  let mut ret = Vec::new();
+  for row in rows {
+    let (rule, network, kind, pattern, commands, channels, action) = row.chain_err(|| "SQLite: row failure in ignore lookup")?;
+    match (IRC::Ignored::new(&kind, &pattern), IRC::Ignoring::from_name(&action)) {
+      (Some(who), Some(action)) => ret.push(IRC::Ignore {
+        rule: rule,
+        network: network,
+        who: who,
+        commands: commands.split_whitespace().map(|c| c.to_string()).collect(),
+        channels: channels.split_whitespace().map(|c| c.to_string()).collect(),
+        action: action
+      }),
+      _ => eprintln!("Warning: skipping ignore rule {}: bad {} {:?}", rule, kind, pattern)
+    }
+  }
+  return Ok(ret);
+}
+
+// store a new rule, giving back its number
+pub fn ignore_add(sql: &Connection, i: &IRC::Ignore) -> Result<i64> {
+  sql.execute("INSERT INTO ignores (network, kind, pattern, commands, channels, action) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+              &[&i.network, &i.who.kind(), &i.who.pattern(), &i.commands.join(" "), &i.channels.join(" "), &i.action.name()])
+    .chain_err(|| "SQLite: insert failure in ignore addition")?;
+  return Ok(sql.last_insert_rowid());
+}
+
+pub fn ignore_remove(sql: &Connection, rule: i64) -> Result<()> {
+  sql.execute("DELETE FROM ignores WHERE rule = ?1", &[&rule])
+    .chain_err(|| "SQLite: delete failure in ignore removal")?;
+  return Ok(());
+}
+
+// every highlight rule, skipping any that no longer make sense
+pub fn highlight_rules(sql: &Connection) -> Result<Vec<IRC::Highlight>> {
+  let mut stmt = sql.prepare("SELECT rule, network, kind, pattern, channels, exclude FROM highlight_rules ORDER BY rule")
+    .chain_err(|| "SQLite: prepare failure in highlight rule lookup")?;
+  let rows = stmt.query_map(&[], |r| (r.get::<_, i64>(0), r.get::<_, Option<String>>(1), r.get::<_, String>(2), r.get::<_, String>(3),
+                                      r.get::<_, String>(4), r.get::<_, bool>(5)))
+    .chain_err(|| "SQLite: query failure in highlight rule lookup")?;
+  let mut ret = Vec::new();
+  for row in rows {
+    let (rule, network, kind, pattern, channels, exclude) = row.chain_err(|| "SQLite: row failure in highlight rule lookup")?;
+    match IRC::Highlighted::new(&kind, &pattern) {
+      Some(what) => ret.push(IRC::Highlight {
+        rule: rule,
+        network: network,
+        what: what,
+        channels: channels.split_whitespace().map(|c| c.to_string()).collect(),
+        exclude: exclude
+      }),
+      None => eprintln!("Warning: skipping highlight rule {}: bad {} {:?}", rule, kind, pattern)
+    }
+  }
+  return Ok(ret);
+}
+
+// store a new rule, giving back its number
+pub fn highlight_rule_add(sql: &Connection, h: &IRC::Highlight) -> Result<i64> {
+  sql.execute("INSERT INTO highlight_rules (network, kind, pattern, channels, exclude) VALUES (?1, ?2, ?3, ?4, ?5)",
+              &[&h.network, &h.what.kind(), &h.what.pattern(), &h.channels.join(" "), &h.exclude])
+    .chain_err(|| "SQLite: insert failure in highlight rule addition")?;
+  return Ok(sql.last_insert_rowid());
+}
+
+pub fn highlight_rule_remove(sql: &Connection, rule: i64) -> Result<()> {
+  sql.execute("DELETE FROM highlight_rules WHERE rule = ?1", &[&rule])
+    .chain_err(|| "SQLite: delete failure in highlight rule removal")?;
+  return Ok(());
+}
+
+// the newest limit highlights on any network from before date, if given, oldest first
+pub fn highlights(sql: &Connection, before: Option<i64>, limit: usize) -> Result<Vec<Stored>> {
+  let mut ret = stored(sql,
+                       &format!("SELECT {} FROM messages JOIN highlights USING (network, id) WHERE messages.date < ?1 ORDER BY messages.date DESC LIMIT {}", COLUMNS, limit),
+                       &[&before.unwrap_or(i64::max_value())])?;
+  ret.reverse();
+  return Ok(ret);
+}
+
+pub fn trusted(sql: &Connection, network: &str) -> Result<Vec<String>> {
+  let mut stmt = sql.prepare("SELECT mask FROM trusted WHERE network = ?1 ORDER BY mask")
+    .chain_err(|| "SQLite: prepare failure in trusted lookup")?;
+  let rows = stmt.query_map(&[&network], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in trusted lookup")?;
+  let mut ret = Vec::new();
+  for m in rows {
+    ret.push(m.chain_err(|| "SQLite: row failure in trusted lookup")?);
+  }
+  return Ok(ret);
+}
+
+pub fn trusted_add(sql: &Connection, network: &str, mask: &str) -> Result<()> {
+  sql.execute("INSERT OR IGNORE INTO trusted (network, mask) VALUES (?1, ?2)", &[&network, &mask])
+    .chain_err(|| "SQLite: insert failure in trusted addition")?;
+  return Ok(());
+}
+
+pub fn trusted_remove(sql: &Connection, network: &str, mask: &str) -> Result<()> {
+  sql.execute("DELETE FROM trusted WHERE network = ?1 AND mask = ?2", &[&network, &mask])
+    .chain_err(|| "SQLite: delete failure in trusted removal")?;
+  return Ok(());
+}
+
+// networks added by IRC clients, as (network, nick, ident, realname)
+pub fn identities(sql: &Connection) -> Result<Vec<(String, String, String, String)>> {
+  let mut stmt = sql.prepare("SELECT network, nick, ident, realname FROM identities ORDER BY network")
+    .chain_err(|| "SQLite: prepare failure in identity lookup")?;
+  let rows = stmt.query_map(&[], |r| (r.get(0), r.get(1), r.get(2), r.get(3)))
+    .chain_err(|| "SQLite: query failure in identity lookup")?;
+  let mut ret = Vec::new();
+  for i in rows {
+    ret.push(i.chain_err(|| "SQLite: row failure in identity lookup")?);
+  }
+  return Ok(ret);
+}
+
+pub fn identity_set(sql: &Connection, network: &str, nick: &str, ident: &str, realname: &str) -> Result<()> {
+  sql.execute("INSERT OR REPLACE INTO identities (network, nick, ident, realname) VALUES (?1, ?2, ?3, ?4)", &[&network, &nick, &ident, &realname])
+    .chain_err(|| "SQLite: insert failure in identity update")?;
+  return Ok(());
+}
+
+// forget a network along with how to connect to it, its logs and other settings stay
+// forget a network's settings, so a new one by the same name starts from scratch, but
+// keep its logs
+pub fn identity_remove(sql: &mut Connection, network: &str) -> Result<()> {
+  let tx = sql.transaction().chain_err(|| "SQLite: transaction failure in identity removal")?;
+  for table in &["identities", "servers", "networks", "services", "chanserv", "autojoin", "connection", "contacts", "trusted"] {
+    tx.execute(&format!("DELETE FROM {} WHERE network = ?1", table), &[&network])
+      .chain_err(|| "SQLite: delete failure in identity removal")?;
+  }
+  tx.commit().chain_err(|| "SQLite: commit failure in identity removal")?;
+  return Ok(());
+}
+
+// STS policies for every host, expired ones included until something replaces them
+pub fn policies(sql: &Connection) -> Result<Vec<IRC::Policy>> {
+  let mut stmt = sql.prepare("SELECT host, port, expires FROM sts ORDER BY host")
+    .chain_err(|| "SQLite: prepare failure in STS lookup")?;
+  let rows = stmt.query_map(&[], |r| IRC::Policy {
+    host: r.get(0),
+    port: r.get::<_, i64>(1) as u16,
+    expires: r.get(2)
+  }).chain_err(|| "SQLite: query failure in STS lookup")?;
+  let mut ret = Vec::new();
+  for p in rows {
+    ret.push(p.chain_err(|| "SQLite: row failure in STS lookup")?);
+  }
+  return Ok(ret);
+}
+
+pub fn policy_set(sql: &Connection, p: &IRC::Policy) -> Result<()> {
+  sql.execute("INSERT OR REPLACE INTO sts (host, port, expires) VALUES (?1, ?2, ?3)", &[&p.host, &(p.port as i64), &p.expires])
+    .chain_err(|| "SQLite: insert failure in STS update")?;
+  return Ok(());
+}
+
+pub fn policy_remove(sql: &Connection, host: &str) -> Result<()> {
+  sql.execute("DELETE FROM sts WHERE host = ?1", &[&host])
+    .chain_err(|| "SQLite: delete failure in STS removal")?;
+  return Ok(());
+}
+
+pub fn enabled(sql: &Connection, network: &str) -> Result<bool> {
+  let mut stmt = sql.prepare("SELECT enabled FROM networks WHERE network = ?1")
+    .chain_err(|| "SQLite: prepare failure in network lookup")?;
+  let mut rows = stmt.query_map(&[&network], |r| r.get(0))
+    .chain_err(|| "SQLite: query failure in network lookup")?;
+  return match rows.next() {
+    Some(e) => Ok(e.chain_err(|| "SQLite: row failure in network lookup")?),
+    None => Ok(true)
+  };
+}
+
+pub fn enabled_set(sql: &Connection, network: &str, enabled: bool) -> Result<()> {
+  sql.execute("INSERT OR REPLACE INTO networks (network, enabled) VALUES (?1, ?2)", &[&network, &enabled])
+    .chain_err(|| "SQLite: insert failure in network enable")?;
+  return Ok(());
+}
+
+pub fn servers(sql: &Connection, network: &str) -> Result<Vec<IRC::Server>> {
+  let mut stmt = sql.prepare("SELECT host, port, tls, password FROM servers WHERE network = ?1 ORDER BY idx")
+    .chain_err(|| "SQLite: prepare failure in server lookup")?;
+  let rows = stmt.query_map(&[&network], |r| IRC::Server {
+    host: r.get(0),
+    port: r.get::<_, i64>(1) as u16,
+    tls: r.get(2),
+    password: r.get(3)
+  }).chain_err(|| "SQLite: query failure in server lookup")?;
+  let mut ret = Vec::new();
+  for s in rows {
+    ret.push(s.chain_err(|| "SQLite: row failure in server lookup")?);
+  }
+  return Ok(ret);
+}
+
+// replace a network's whole server list, since adding or removing one renumbers the rest
+pub fn servers_set(sql: &mut Connection, network: &str, servers: &[IRC::Server]) -> Result<()> {
+  let tx = sql.transaction().chain_err(|| "SQLite: transaction failure in server update")?;
+  tx.execute("DELETE FROM servers WHERE network = ?1", &[&network])
+    .chain_err(|| "SQLite: delete failure in server update")?;
+  for (idx, s) in servers.iter().enumerate() {
+    let idx = idx as i64;
+    let port = s.port as i64;
+    tx.execute("INSERT INTO servers (network, idx, host, port, tls, password) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+               &[&network, &idx, &s.host, &port, &s.tls, &s.password])
+      .chain_err(|| "SQLite: insert failure in server update")?;
+  }
+  tx.commit().chain_err(|| "SQLite: commit failure in server update")?;
+  return Ok(());
+}
+
+// bring the numerics table up to date with the names we know
+pub fn numerics_set(sql: &mut Connection) -> Result<()> {
+  let tx = sql.transaction().chain_err(|| "SQLite: transaction failure in numeric update")?;
+  for &(code, name, target) in IRC::NUMERICS {
+    let r = IRC::Reply { code: code, name: name, target: target };
+    let code = code as i64;
+    let target = target.map(|t| t as i64);
+    tx.execute("INSERT OR REPLACE INTO numerics (numeric, name, error, target) VALUES (?1, ?2, ?3, ?4)",
+               &[&code, &name, &r.error(), &target])
+      .chain_err(|| "SQLite: insert failure in numeric update")?;
+  }
+  tx.commit().chain_err(|| "SQLite: commit failure in numeric update")?;
+  return Ok(());
+}
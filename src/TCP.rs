@@ -0,0 +1,711 @@
+use libc;
+use native_tls::{HandshakeError, MidHandshakeTlsStream, TlsConnector, TlsStream};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use error::*;
+
+// how long connecting to an address, talking to the proxy, or the TLS handshake may take, in seconds
+const TIMEOUT : i64 = 30;
+
+// a connection to a server, with or without TLS
+pub enum Stream {
+  Plain(TcpStream),
+  Tls(TlsStream<TcpStream>)
+}
+impl Stream {
+  fn tcp(&self) -> &TcpStream {
+    return match *self {
+      Stream::Plain(ref s) => s,
+      Stream::Tls(ref s) => s.get_ref()
+    };
+  }
+  pub fn set_nonblocking(&self) -> Result<()> {
+    self.tcp().set_nonblocking(true).chain_err(|| "TCP: failed to make socket nonblocking")?;
+    return Ok(());
+  }
+  // our end of the connection and theirs, None if the socket can't say
+  pub fn addrs(&self) -> Option<(SocketAddr, SocketAddr)> {
+    return match (self.tcp().local_addr(), self.tcp().peer_addr()) {
+      (Ok(local), Ok(peer)) => Some((local, peer)),
+      _ => None
+    };
+  }
+  // make the next read return EOF, so the main loop notices we're gone
+  pub fn shutdown(&self) {
+    let _ = self.tcp().shutdown(Shutdown::Both);
+  }
+}
+impl Read for Stream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    return match *self {
+      Stream::Plain(ref mut s) => s.read(buf),
+      Stream::Tls(ref mut s) => s.read(buf)
+    };
+  }
+}
+impl Write for Stream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    return match *self {
+      Stream::Plain(ref mut s) => s.write(buf),
+      Stream::Tls(ref mut s) => s.write(buf)
+    };
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    return match *self {
+      Stream::Plain(ref mut s) => s.flush(),
+      Stream::Tls(ref mut s) => s.flush()
+    };
+  }
+}
+impl AsRawFd for Stream {
+  fn as_raw_fd(&self) -> RawFd {
+    return self.tcp().as_raw_fd();
+  }
+}
+
+// the buffers of a nonblocking line based connection, both ways
+#[derive(Default)]
+pub struct Lines {
+  // bytes read that don't make up a whole line yet, and bytes still to be written
+  rbuf: Vec<u8>,
+  wbuf: Vec<u8>,
+  // skipping the rest of a line that was too long
+  discarding: bool
+}
+impl Lines {
+  pub fn clear(&mut self) {
+    self.rbuf.clear();
+    self.wbuf.clear();
+    self.discarding = false;
+  }
+
+  // queue a line, adding its line ending
+  pub fn push(&mut self, line: &str) {
+    self.wbuf.extend_from_slice(line.as_bytes());
+    self.wbuf.extend_from_slice(b"\r\n");
+  }
+
+  pub fn pending(&self) -> bool {
+    return !self.wbuf.is_empty();
+  }
+
+  // write as much as the socket will take, a failed write shuts the connection down
+  // so that the next read sees EOF; id is only for the warning
+  pub fn flush(&mut self, sock: &mut Stream, id: &str) {
+    while !self.wbuf.is_empty() {
+      match sock.write(&self.wbuf) {
+        Ok(n) if n > 0 => {
+          self.wbuf.drain(..n);
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        r => {
+          if let Err(e) = r {
+            eprintln!("Warning: {}: write failure: {}", id, e);
+          }
+          sock.shutdown();
+          self.wbuf.clear();
+        }
+      }
+    }
+  }
+
+  // read everything sent so far, returning each complete line of at most max bytes
+  // without its line ending, and whether the connection has reached EOF
+  pub fn read(&mut self, sock: &mut Stream, max: usize, id: &str) -> (Vec<String>, bool) {
+    let mut eof = false;
+    let mut buf = [0; 4096];
+    loop {
+      match sock.read(&mut buf) {
+        Ok(0) => {
+          eof = true;
+          break;
+        },
+        Ok(n) => self.rbuf.extend_from_slice(&buf[..n]),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => {
+          eprintln!("Warning: {}: read failure: {}", id, e);
+          eof = true;
+          break;
+        }
+      }
+    }
+
+    let mut lines = Vec::new();
+    while let Some(i) = self.rbuf.iter().position(|&b| b == b'\n') {
+      let mut line : Vec<u8> = self.rbuf.drain(..i+1).collect();
+      line.pop();
+      if line.last() == Some(&b'\r') {
+        line.pop();
+      }
+      if self.discarding {
+        self.discarding = false;
+      } else if line.len() > max {
+        eprintln!("Warning: {}: dropping overlong line of {} bytes", id, line.len());
+      } else if !line.is_empty() {
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+      }
+    }
+    // a partial line that's already too long will never be any use, so drop it now
+    // rather than buffering it until the other end gets round to ending it
+    if self.rbuf.len() > max {
+      eprintln!("Warning: {}: dropping overlong line", id);
+      self.rbuf.clear();
+      self.discarding = true;
+    }
+    return (lines, eof);
+  }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ProxyKind {
+  Socks5,
+  Http
+}
+
+#[derive(Clone, Debug)]
+pub struct Proxy {
+  pub kind: ProxyKind,
+  pub host: String,
+  pub port: u16,
+  pub username: Option<String>,
+  pub password: Option<String>,
+  // let the proxy resolve the server's name, needed for onion services
+  pub remote_dns: bool
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Prefer {
+  Any,
+  V4,
+  V6
+}
+
+// how to reach a network, see connection in schema.sql
+#[derive(Clone, Debug)]
+pub struct Options {
+  pub proxy: Option<Proxy>,
+  // local address to connect from
+  pub bind: Option<IpAddr>,
+  // which address family to try first when a name resolves to both
+  pub prefer: Prefer
+}
+impl Default for Options {
+  fn default() -> Options {
+    return Options {
+      proxy: None,
+      bind: None,
+      prefer: Prefer::Any
+    };
+  }
+}
+
+impl ProxyKind {
+  pub fn name(&self) -> &'static str {
+    return match *self {
+      ProxyKind::Socks5 => "socks5",
+      ProxyKind::Http => "http"
+    };
+  }
+  pub fn from_name(s: &str) -> Option<ProxyKind> {
+    return match s {
+      "socks5" => Some(ProxyKind::Socks5),
+      "http" => Some(ProxyKind::Http),
+      _ => None
+    };
+  }
+}
+
+impl Prefer {
+  pub fn name(&self) -> Option<&'static str> {
+    return match *self {
+      Prefer::Any => None,
+      Prefer::V4 => Some("ipv4"),
+      Prefer::V6 => Some("ipv6")
+    };
+  }
+  pub fn from_name(s: Option<&str>) -> Option<Prefer> {
+    return match s {
+      None => Some(Prefer::Any),
+      Some("ipv4") => Some(Prefer::V4),
+      Some("ipv6") => Some(Prefer::V6),
+      Some(_) => None
+    };
+  }
+}
+
+// a connection on its way up: connecting, then talking to the proxy if there is one,
+// then the TLS handshake if we want one. None of it blocks, the main loop's poll says
+// when the socket is ready and advance() gets on with it, the way Lines does once we're up
+pub struct Dial {
+  // None only once advance() has failed
+  stage: Option<Stage>,
+  // addresses still to try if the one we're connecting to fails, the proxy's if there is one
+  addrs: Vec<SocketAddr>,
+  bind: Option<IpAddr>,
+  proxy: Option<Proxy>,
+  // what we're connecting to, for errors
+  peer: String,
+  // the server, the way the proxy is to be asked for it
+  target: String,
+  port: u16,
+  // the name to check the certificate against, None for plaintext
+  tls: Option<String>,
+  // bytes for the proxy still to be written, and as much of its reply as we've needed so far
+  wbuf: Vec<u8>,
+  rbuf: Vec<u8>,
+  // when connecting to the current address, or the current handshake, gives up
+  deadline: i64
+}
+
+enum Stage {
+  Connect(TcpStream),
+  Socks5(TcpStream, Socks),
+  Http(TcpStream),
+  Tls(MidHandshakeTlsStream<TcpStream>),
+  Ready(Stream)
+}
+
+// the SOCKS5 reply we're waiting for
+enum Socks {
+  Method,
+  Auth,
+  Connect
+}
+
+// start connecting to host:port, through a proxy if there is one, and over TLS if tls
+pub fn dial(host: &str, port: u16, tls: bool, opts: &Options, now: i64) -> Result<Dial> {
+  // looking names up still blocks, but only for as long as the resolver takes
+  let (mut addrs, peer, target) = match opts.proxy {
+    None => (resolve(host, port, opts)?, format!("{}:{}", host, port), host.to_string()),
+    Some(ref proxy) => {
+      // resolve locally if asked to, the proxy then only ever sees an address
+      let target = if proxy.remote_dns {
+        host.to_string()
+      } else {
+        resolve(host, port, opts)?[0].ip().to_string()
+      };
+      (resolve(&proxy.host, proxy.port, opts)?, format!("proxy {}:{}", proxy.host, proxy.port), target)
+    }
+  };
+  // popped from the back, most preferred first
+  addrs.reverse();
+  let mut d = Dial {
+    stage: None,
+    addrs: addrs,
+    bind: opts.bind,
+    proxy: opts.proxy.clone(),
+    peer: peer,
+    target: target,
+    port: port,
+    tls: if tls { Some(host.to_string()) } else { None },
+    wbuf: Vec::new(),
+    rbuf: Vec::new(),
+    deadline: 0
+  };
+  d.next(now, None)?;
+  return Ok(d);
+}
+
+impl Dial {
+  fn tcp(&self) -> Option<&TcpStream> {
+    return match self.stage {
+      Some(Stage::Connect(ref s)) | Some(Stage::Socks5(ref s, _)) | Some(Stage::Http(ref s)) => Some(s),
+      Some(Stage::Tls(ref s)) => Some(s.get_ref()),
+      Some(Stage::Ready(ref s)) => Some(s.tcp()),
+      None => None
+    };
+  }
+
+  pub fn fd(&self) -> Option<RawFd> {
+    return self.tcp().map(|s| s.as_raw_fd());
+  }
+
+  // while connecting, or with something for the proxy still to write
+  pub fn wants_write(&self) -> bool {
+    return match self.stage {
+      Some(Stage::Connect(_)) => true,
+      Some(Stage::Socks5(..)) | Some(Stage::Http(_)) => !self.wbuf.is_empty(),
+      _ => false
+    };
+  }
+
+  // our end of the connection and theirs, servers ask ident before we're all the way up
+  pub fn addrs(&self) -> Option<(SocketAddr, SocketAddr)> {
+    return match self.tcp().map(|s| (s.local_addr(), s.peer_addr())) {
+      Some((Ok(local), Ok(peer))) => Some((local, peer)),
+      _ => None
+    };
+  }
+
+  // get as far as the socket lets us without waiting, returning the stream once it's
+  // ready for IRC; call it whenever poll says the socket is ready, and every so often
+  // anyway so that a stage that's taking too long times out
+  pub fn advance(&mut self, now: i64) -> Result<Option<Stream>> {
+    loop {
+      let stage = match self.stage.take() {
+        Some(s) => s,
+        None => bail!("TCP: connection to {} already failed", self.peer)
+      };
+      match stage {
+        Stage::Connect(sock) => {
+          let failed = match sock.take_error() {
+            Ok(Some(e)) | Err(e) => Some(e),
+            Ok(None) => match sock.peer_addr() {
+              Ok(_) => None,
+              Err(ref e) if e.kind() == io::ErrorKind::NotConnected && now < self.deadline => {
+                self.stage = Some(Stage::Connect(sock));
+                return Ok(None);
+              },
+              Err(ref e) if e.kind() == io::ErrorKind::NotConnected =>
+                Some(io::Error::new(io::ErrorKind::TimedOut, "connection timed out")),
+              Err(e) => Some(e)
+            }
+          };
+          match failed {
+            Some(e) => self.next(now, Some(e))?,
+            None => self.connected(sock, now)?
+          }
+        },
+        Stage::Socks5(mut sock, step) => {
+          send(&mut sock, &mut self.wbuf).chain_err(|| "SOCKS5: write failure")?;
+          if !self.wbuf.is_empty() || !fill(&mut sock, &mut self.rbuf, |r| socks5_wants(&step, r)).chain_err(|| "SOCKS5: read failure")? {
+            return self.wait(Stage::Socks5(sock, step), now, "SOCKS5");
+          }
+          let next = match self.proxy {
+            Some(ref proxy) => socks5_next(&step, &self.rbuf, proxy, &self.target, self.port)?,
+            None => None
+          };
+          self.rbuf.clear();
+          match next {
+            Some((step, req)) => {
+              self.wbuf = req;
+              self.stage = Some(Stage::Socks5(sock, step));
+            },
+            None => self.secure(sock, now)?
+          }
+        },
+        Stage::Http(mut sock) => {
+          send(&mut sock, &mut self.wbuf).chain_err(|| "HTTP: write failure")?;
+          if !self.wbuf.is_empty() || !fill(&mut sock, &mut self.rbuf, http_wants).chain_err(|| "HTTP: read failure")? {
+            return self.wait(Stage::Http(sock), now, "HTTP");
+          }
+          http_status(&self.rbuf)?;
+          self.rbuf.clear();
+          self.secure(sock, now)?;
+        },
+        Stage::Tls(mid) => {
+          let host = self.tls.clone().unwrap_or_default();
+          match handshaken(mid.handshake(), &host)? {
+            Stage::Tls(mid) => return self.wait(Stage::Tls(mid), now, "TLS"),
+            s => self.stage = Some(s)
+          }
+        },
+        Stage::Ready(s) => return Ok(Some(s))
+      }
+    }
+  }
+
+  // put a stage back until the socket is ready again, unless it's taken too long
+  fn wait(&mut self, stage: Stage, now: i64, what: &str) -> Result<Option<Stream>> {
+    if now >= self.deadline {
+      bail!("{}: {} timed out", what, self.peer);
+    }
+    self.stage = Some(stage);
+    return Ok(None);
+  }
+
+  // start connecting to the next address, or give up with why the last one failed
+  fn next(&mut self, now: i64, mut last: Option<io::Error>) -> Result<()> {
+    while let Some(addr) = self.addrs.pop() {
+      match start(&addr, self.bind) {
+        Ok(sock) => {
+          self.deadline = now + TIMEOUT;
+          self.stage = Some(Stage::Connect(sock));
+          return Ok(());
+        },
+        Err(e) => last = Some(e)
+      }
+    }
+    return match last {
+      Some(e) => Err(e).chain_err(|| format!("TCP: connection failure to {}", self.peer)),
+      None => bail!("TCP: no usable address for {}", self.peer)
+    };
+  }
+
+  // connected to the server or the proxy, start talking to whichever it is
+  fn connected(&mut self, sock: TcpStream, now: i64) -> Result<()> {
+    self.deadline = now + TIMEOUT;
+    let stage = match self.proxy {
+      Some(ref proxy) if proxy.kind == ProxyKind::Socks5 => {
+        self.wbuf = socks5_hello(proxy);
+        Stage::Socks5(sock, Socks::Method)
+      },
+      Some(ref proxy) => {
+        self.wbuf = http_connect(proxy, &self.target, self.port);
+        Stage::Http(sock)
+      },
+      None => return self.secure(sock, now)
+    };
+    self.stage = Some(stage);
+    return Ok(());
+  }
+
+  // through to the server, start the TLS handshake if we want one, or we're there
+  fn secure(&mut self, sock: TcpStream, now: i64) -> Result<()> {
+    self.deadline = now + TIMEOUT;
+    let stage = match self.tls {
+      Some(ref host) => {
+        let connector = TlsConnector::new().chain_err(|| "TLS: failed to set up connector")?;
+        handshaken(connector.connect(host, sock), host)?
+      },
+      None => Stage::Ready(Stream::Plain(sock))
+    };
+    self.stage = Some(stage);
+    return Ok(());
+  }
+}
+
+// what a step of a nonblocking TLS handshake leaves us with
+fn handshaken(r: ::std::result::Result<TlsStream<TcpStream>, HandshakeError<TcpStream>>, host: &str) -> Result<Stage> {
+  return match r {
+    Ok(s) => Ok(Stage::Ready(Stream::Tls(s))),
+    Err(HandshakeError::WouldBlock(mid)) => Ok(Stage::Tls(mid)),
+    Err(HandshakeError::Failure(e)) => bail!("TLS: handshake failure with {}: {}", host, e)
+  };
+}
+
+// write as much of buf as the socket takes right now
+fn send(sock: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<()> {
+  while !buf.is_empty() {
+    match sock.write(buf) {
+      Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+      Ok(n) => {
+        buf.drain(..n);
+      },
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e)
+    }
+  }
+  return Ok(());
+}
+
+// read into buf until wants says it's got all it needs, never more, since anything
+// past a proxy's reply belongs to the IRC server; false if the socket ran dry first
+fn fill<F>(sock: &mut TcpStream, buf: &mut Vec<u8>, wants: F) -> Result<bool>
+  where F: Fn(&[u8]) -> Result<usize> {
+  let mut chunk = [0; 256];
+  loop {
+    let n = wants(buf)?.min(chunk.len());
+    if n == 0 {
+      return Ok(true);
+    }
+    match sock.read(&mut chunk[..n]) {
+      Ok(0) => bail!("connection closed"),
+      Ok(n) => buf.extend_from_slice(&chunk[..n]),
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e).chain_err(|| "read failure")
+    }
+  }
+}
+
+// resolve host, most preferred addresses first
+fn resolve(host: &str, port: u16, opts: &Options) -> Result<Vec<SocketAddr>> {
+  let mut addrs : Vec<SocketAddr> = (host, port).to_socket_addrs()
+    .chain_err(|| format!("TCP: failed to resolve {}", host))?
+    .filter(|a| match opts.bind {
+      // we can't connect to an address of a different family than the one we bind to
+      Some(b) => b.is_ipv4() == a.is_ipv4(),
+      None => true
+    })
+    .collect();
+  match opts.prefer {
+    Prefer::V4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+    Prefer::V6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+    Prefer::Any => ()
+  }
+  if addrs.is_empty() {
+    bail!("TCP: no usable address for {}", host);
+  }
+  return Ok(addrs);
+}
+
+// start a nonblocking connect, from bind if given; std can do neither, so do it by hand
+fn start(addr: &SocketAddr, bind: Option<IpAddr>) -> io::Result<TcpStream> {
+  unsafe {
+    let family = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = libc::socket(family, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0);
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    // owns fd from here on, so it gets closed if anything fails
+    let sock = TcpStream::from_raw_fd(fd);
+    if let Some(b) = bind {
+      let (local, local_len) = sockaddr(&SocketAddr::new(b, 0));
+      if libc::bind(fd, &local as *const _ as *const libc::sockaddr, local_len) < 0 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+    let (remote, remote_len) = sockaddr(addr);
+    if libc::connect(fd, &remote as *const _ as *const libc::sockaddr, remote_len) < 0 {
+      let e = io::Error::last_os_error();
+      if e.raw_os_error() != Some(libc::EINPROGRESS) {
+        return Err(e);
+      }
+    }
+    return Ok(sock);
+  }
+}
+
+unsafe fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+  let mut storage : libc::sockaddr_storage = mem::zeroed();
+  match *addr {
+    SocketAddr::V4(ref a) => {
+      let sin = &mut storage as *mut _ as *mut libc::sockaddr_in;
+      (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+      (*sin).sin_port = a.port().to_be();
+      (*sin).sin_addr.s_addr = u32::from(*a.ip()).to_be();
+      (storage, mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+    },
+    SocketAddr::V6(ref a) => {
+      let sin6 = &mut storage as *mut _ as *mut libc::sockaddr_in6;
+      (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+      (*sin6).sin6_port = a.port().to_be();
+      (*sin6).sin6_addr.s6_addr = a.ip().octets();
+      (*sin6).sin6_flowinfo = a.flowinfo();
+      (*sin6).sin6_scope_id = a.scope_id();
+      (storage, mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+    }
+  }
+}
+
+// RFC 1928's greeting, offering RFC 1929 username/password authentication if we have one
+fn socks5_hello(proxy: &Proxy) -> Vec<u8> {
+  return if proxy.username.is_some() { vec![5, 2, 0, 2] } else { vec![5, 1, 0] };
+}
+
+// how many more bytes of a reply we need before we can act on it
+fn socks5_wants(step: &Socks, reply: &[u8]) -> Result<usize> {
+  let len = match *step {
+    Socks::Method | Socks::Auth => 2,
+    Socks::Connect if reply.len() < 5 => 5,
+    // the bound address and port follow, we've no use for them but they have to go
+    Socks::Connect => match reply[3] {
+      1 => 4 + 4 + 2,
+      4 => 4 + 16 + 2,
+      3 => 5 + reply[4] as usize + 2,
+      t => bail!("SOCKS5: unknown address type {}", t)
+    }
+  };
+  return Ok(len - reply.len().min(len));
+}
+
+// what to send the proxy after a whole reply, None once it's connected us
+fn socks5_next(step: &Socks, reply: &[u8], proxy: &Proxy, host: &str, port: u16) -> Result<Option<(Socks, Vec<u8>)>> {
+  return match *step {
+    Socks::Method => match (reply[0], reply[1]) {
+      (5, 0) => Ok(Some((Socks::Connect, socks5_connect(host, port)?))),
+      (5, 2) if proxy.username.is_some() => {
+        let user = proxy.username.as_ref().map_or("", |u| &u[..]).as_bytes();
+        let pass = proxy.password.as_ref().map_or("", |p| &p[..]).as_bytes();
+        if user.len() > 255 || pass.len() > 255 {
+          bail!("SOCKS5: username or password too long");
+        }
+        let mut req = vec![1, user.len() as u8];
+        req.extend_from_slice(user);
+        req.push(pass.len() as u8);
+        req.extend_from_slice(pass);
+        Ok(Some((Socks::Auth, req)))
+      },
+      _ => bail!("SOCKS5: no acceptable authentication method")
+    },
+    Socks::Auth if reply[1] != 0 => bail!("SOCKS5: authentication failure"),
+    Socks::Auth => Ok(Some((Socks::Connect, socks5_connect(host, port)?))),
+    Socks::Connect if reply[1] != 0 => bail!("SOCKS5: connect request failed with reply {}", reply[1]),
+    Socks::Connect => Ok(None)
+  };
+}
+
+fn socks5_connect(host: &str, port: u16) -> Result<Vec<u8>> {
+  let mut req = vec![5, 1, 0];
+  match host.parse::<IpAddr>() {
+    Ok(IpAddr::V4(a)) => {
+      req.push(1);
+      req.extend_from_slice(&a.octets());
+    },
+    Ok(IpAddr::V6(a)) => {
+      req.push(4);
+      req.extend_from_slice(&a.octets());
+    },
+    Err(_) => {
+      if host.len() > 255 {
+        bail!("SOCKS5: hostname too long");
+      }
+      req.push(3);
+      req.push(host.len() as u8);
+      req.extend_from_slice(host.as_bytes());
+    }
+  }
+  req.push((port >> 8) as u8);
+  req.push(port as u8);
+  return Ok(req);
+}
+
+fn http_connect(proxy: &Proxy, host: &str, port: u16) -> Vec<u8> {
+  let authority = if host.contains(':') {
+    format!("[{}]:{}", host, port)
+  } else {
+    format!("{}:{}", host, port)
+  };
+  let mut req = format!("CONNECT {a} HTTP/1.1\r\nHost: {a}\r\n", a=authority);
+  if let Some(ref user) = proxy.username {
+    let creds = format!("{}:{}", user, proxy.password.as_ref().map_or("", |p| &p[..]));
+    req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", base64(creds.as_bytes())));
+  }
+  req.push_str("\r\n");
+  return req.into_bytes();
+}
+
+// a byte at a time, we can't tell where the headers end any sooner
+fn http_wants(head: &[u8]) -> Result<usize> {
+  if head.ends_with(b"\r\n\r\n") {
+    return Ok(0);
+  }
+  if head.len() > 8192 {
+    bail!("HTTP: proxy response headers too long");
+  }
+  return Ok(1);
+}
+
+fn http_status(head: &[u8]) -> Result<()> {
+  let head = String::from_utf8_lossy(head);
+  let status = head.lines().next().unwrap_or("");
+  match status.split_whitespace().nth(1) {
+    Some(code) if code.starts_with('2') => return Ok(()),
+    _ => bail!("HTTP: proxy refused CONNECT: {}", status)
+  }
+}
+
+fn base64(data: &[u8]) -> String {
+  const TABLE : &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut ret = String::new();
+  for chunk in data.chunks(3) {
+    let n = (chunk[0] as u32) << 16 |
+            (*chunk.get(1).unwrap_or(&0) as u32) << 8 |
+            *chunk.get(2).unwrap_or(&0) as u32;
+    for i in 0..4 {
+      if i <= chunk.len() {
+        ret.push(TABLE[(n >> (18 - 6 * i) & 63) as usize] as char);
+      } else {
+        ret.push('=');
+      }
+    }
+  }
+  return ret;
+}
@@ -2,8 +2,9 @@ extern crate envy;
 #[macro_use]
 extern crate error_chain;
 extern crate libc;
+extern crate native_tls;
 extern crate nom;
-extern crate rbot_parser;
+extern crate regex;
 extern crate rusqlite;
 #[macro_use]
 extern crate serde_derive;
@@ -12,26 +13,49 @@ extern crate serde_json;
 extern crate time;
 
 use rusqlite::Connection;
+use std::cmp;
 use std::io::{self, Write};
-use std::net::TcpStream;
-use std::os::unix::io::*;
 use std::path::Path;
 use std::ptr;
 
+#[allow(non_snake_case)]
+mod BNC;
+#[allow(non_snake_case)]
+mod IDENT;
 #[allow(non_snake_case)]
 mod IRC;
 #[allow(non_snake_case)]
 mod JSON;
 #[allow(non_snake_case)]
+mod SQL;
+#[allow(non_snake_case)]
+mod TCP;
+#[allow(non_snake_case)]
 mod ZMQ;
 
 const SCHEMA : &'static str = include_str!("schema.sql");
+// how long to wait on sockets before doing periodic work, in milliseconds
+const TICK : i64 = 1000;
+// the most highlights a client can ask for at once
+const HIGHLIGHT_LIMIT : usize = 100;
+// the most messages from a buffer a client can ask for at once
+const BUFFER_LIMIT : usize = 1000;
 
 #[derive(Deserialize, Debug)]
 struct Configuration {
   zmq_pub_listen: String,
   zmq_rep_listen: String,
-  sqlite_path: String
+  sqlite_path: String,
+  // seconds without any client request before we mark ourselves away, never if unset
+  away_timeout: Option<i64>,
+  away_message: Option<String>,
+  // where to listen for ordinary IRC clients, and the password they need; no listener if unset
+  irc_listen: Option<String>,
+  irc_password: Option<String>,
+  // most missed messages to play back to an IRC client per channel or query
+  playback_limit: Option<usize>,
+  // where to answer ident queries, no ident server if unset
+  ident_listen: Option<String>
 }
 
 mod error {
@@ -60,6 +84,101 @@ fn main() {
     print!("Exiting.");
   }
 }
+// publish and store whatever changes in a network's state mean for clients, and answer
+// the request waiting on a query
+fn dispatch(events: Vec<IRC::Event>,
+            irc: &IRC::Context,
+            sock_pub: &mut ZMQ::Socket,
+            sock_rep: &mut ZMQ::Socket,
+            bnc: &mut BNC::Bouncer,
+            sql: &Connection) -> Result<()> {
+  for event in events {
+    match event {
+      IRC::Event::Presence(nick, online) => JSON::presence(sock_pub, irc, &nick, online),
+      IRC::Event::Missed(id) => SQL::away_log_add(sql, &irc.id, id)?,
+      IRC::Event::Channels => JSON::sync(sock_pub, irc),
+      IRC::Event::Connection => {
+        JSON::sync(sock_pub, irc);
+        bnc.connection(irc);
+      },
+      IRC::Event::Answer(ref a) => JSON::answer(sock_rep, irc, a),
+      IRC::Event::Policy(_, Some(ref p)) => SQL::policy_set(sql, p)?,
+      IRC::Event::Policy(ref host, None) => SQL::policy_remove(sql, host)?,
+      IRC::Event::Invite(ref invite, joined) => JSON::invite(sock_pub, irc, invite, joined),
+      IRC::Event::Netsplit(ref s) => JSON::split(sock_pub, irc, "netsplit", s),
+      IRC::Event::Netjoin(ref s) => JSON::split(sock_pub, irc, "netjoin", s),
+      IRC::Event::Typing(ref t) => JSON::typing(sock_pub, irc, t),
+      IRC::Event::Read(ref target, ref timestamp) => {
+        let id = match BNC::reference(timestamp) {
+          Some(SQL::Bound::Date(date)) => SQL::read_by(sql, &irc.id, target, date)?,
+          _ => None
+        };
+        if let Some(id) = id {
+          if SQL::mark(sql, &irc.id, target, id)? {
+            if let Some(m) = SQL::marker(sql, &irc.id, target)? {
+              JSON::read(sock_pub, irc, &m);
+            }
+          }
+        }
+      }
+    }
+  }
+  return Ok(());
+}
+
+// the server in an add or edit request, on top of base for edits
+fn server_options(c: &serde_json::Value, base: Option<IRC::Server>) -> Option<IRC::Server> {
+  let tls = c["tls"].as_bool().or(base.as_ref().map(|b| b.tls)).unwrap_or(false);
+  let port = match c["port"].as_u64() {
+    Some(n) if n > 0 && n < 65536 => n as u16,
+    Some(_) => return None,
+    None => base.as_ref().map_or(if tls { 6697 } else { 6667 }, |b| b.port)
+  };
+  let password = match c["password"] {
+    serde_json::Value::Null => base.as_ref().and_then(|b| b.password.clone()),
+    // an empty password removes it
+    ref p => match p.as_str()? {
+      "" => None,
+      p => Some(p.to_string())
+    }
+  };
+  return Some(IRC::Server {
+    host: c["host"].as_str().map(|h| h.to_string()).or(base.map(|b| b.host))?,
+    port: port,
+    tls: tls,
+    password: password
+  });
+}
+
+// the options in a connection set request, None if they don't make sense
+fn connection_options(c: &serde_json::Value) -> Option<TCP::Options> {
+  let proxy = match c["proxy"] {
+    serde_json::Value::Null => None,
+    ref p => Some(TCP::Proxy {
+      kind: TCP::ProxyKind::from_name(p["type"].as_str()?)?,
+      host: p["host"].as_str()?.to_string(),
+      port: p["port"].as_u64().and_then(|n| if n > 0 && n < 65536 { Some(n as u16) } else { None })?,
+      username: p["username"].as_str().map(|u| u.to_string()),
+      password: p["password"].as_str().map(|u| u.to_string()),
+      remote_dns: p["remote_dns"].as_bool().unwrap_or(true)
+    })
+  };
+  let bind = match c["bind"].as_str() {
+    Some(b) => Some(b.parse().ok()?),
+    None => None
+  };
+  return Some(TCP::Options {
+    proxy: proxy,
+    bind: bind,
+    prefer: TCP::Prefer::from_name(c["prefer"].as_str())?
+  });
+}
+
+// services settings end up in PRIVMSGs, so they can't have anything in them that ends a line
+fn sendable(s: &str) -> bool {
+  return !s.contains(|c| c == '\r' || c == '\n' || c == '\0');
+}
+
 // so i can use ? operator
 fn run() -> Result<()> {
   // TODO: print usage on undefined environment variable
@@ -68,6 +187,7 @@ fn run() -> Result<()> {
   let ctx = ZMQ::Context::new();
   let mut ctx_sql = Connection::open(Path::new(&config.sqlite_path)).chain_err(|| "SQLite: database open failure")?;
   ctx_sql.execute_batch(SCHEMA).chain_err(|| "sqlite: schema execution failure")?;
+  SQL::numerics_set(&mut ctx_sql)?;
 
   let mut sock_pub = ctx.socket(ZMQ::PUB);
   let mut sock_rep = ctx.socket(ZMQ::REP);
@@ -75,33 +195,45 @@ fn run() -> Result<()> {
   sock_pub.bind(&config.zmq_pub_listen);
   sock_rep.bind(&config.zmq_rep_listen);
 
+  let mut bnc = BNC::Bouncer::new(config.irc_listen.as_ref().map(|a| &a[..]),
+                                  config.irc_password.as_ref().map(|p| &p[..]),
+                                  config.playback_limit.unwrap_or(BNC::PLAYBACK_LIMIT))?;
+
+  let mut ident = match config.ident_listen {
+    Some(ref addr) => Some(IDENT::Responder::new(addr)?),
+    None => None
+  };
+
+  let mut ignores = SQL::ignores(&ctx_sql)?;
+  let mut highlights = SQL::highlight_rules(&ctx_sql)?;
+
   let mut irc_ctxs = Vec::new();
-  irc_ctxs.push(IRC::Context {
-    sock: TcpStream::connect(("localhost", 6668)).chain_err(|| "TCP: connection failure")?,
-    id: "localhost",
-    host: "localhost",
-    port: 6668,
-    nick: "nick",
-    ident: "user",
-    realname: "real",
-    channels: Vec::new()
-  });
-  irc_ctxs[0].connect();
-  
-  let mut items = [
-    ZMQ::PollItem {
-      socket: sock_rep.as_ptr(),
-      fd: 0,
-      events: ZMQ::IN | ZMQ::ERR,
-      revents: 0
-    },
-    ZMQ::PollItem {
-      socket: ptr::null(),
-      fd: irc_ctxs[0].sock.as_raw_fd(),
-      events: ZMQ::IN | ZMQ::ERR,
-      revents: 0
-    }];
-  
+  irc_ctxs.push(IRC::Context::new(
+    "localhost".to_string(),
+    "nick".to_string(),
+    "user".to_string(),
+    "real".to_string()));
+  // and whatever networks IRC clients have added
+  for (network, nick, ident, realname) in SQL::identities(&ctx_sql)? {
+    irc_ctxs.push(IRC::Context::new(network, nick, ident, realname));
+  }
+  for irc in irc_ctxs.iter_mut() {
+    SQL::load(&ctx_sql, irc)?;
+    if irc.servers.is_empty() {
+      // nothing configured yet, so use what we always have
+      irc.servers.push(IRC::Server {
+        host: "localhost".to_string(),
+        port: 6668,
+        tls: false,
+        password: None
+      });
+    }
+  }
+  // the first tick connects everything that's enabled
+
+  let away_message = config.away_message.clone().unwrap_or("Not here right now".to_string());
+  let mut last_activity = time::now_utc().to_timespec().sec;
+
   let mut id : i64 = ctx_sql.query_row("SELECT MAX(id) FROM messages", &[], |r| {
     match r.get_checked::<_, i64>(0) {
       Ok(n) => n + 1,
@@ -110,63 +242,560 @@ fn run() -> Result<()> {
   }).chain_err(|| "SQLite: query failure in id initial value lookup")?;
 
   loop {
-    let mut ss = ZMQ::poll(&mut items, -1);
+    // only networks we're connected to get polled, and which those are changes as we go
+    // REP has to answer one request before taking the next, so while a query is
+    // waiting on the server, new requests wait too
+    let querying = irc_ctxs.iter().any(|irc| irc.querying());
+    let mut items = vec![
+      ZMQ::PollItem {
+        socket: sock_rep.as_ptr(),
+        fd: 0,
+        events: if querying { 0 } else { ZMQ::IN | ZMQ::ERR },
+        revents: 0
+      }];
+    let mut polled = Vec::new();
+    for (i, irc) in irc_ctxs.iter().enumerate() {
+      if let Some(fd) = irc.fd() {
+        items.push(ZMQ::PollItem {
+          socket: ptr::null(),
+          fd: fd,
+          events: if irc.wants_write() {
+            ZMQ::IN | ZMQ::ERR | ZMQ::OUT as i16
+          } else {
+            ZMQ::IN | ZMQ::ERR
+          },
+          revents: 0
+        });
+        polled.push(i);
+      }
+    }
+    // then the IRC clients attached to us, None standing for the listener itself
+    let mut attached = Vec::new();
+    if let Some(fd) = bnc.fd() {
+      items.push(ZMQ::PollItem {
+        socket: ptr::null(),
+        fd: fd,
+        events: ZMQ::IN | ZMQ::ERR,
+        revents: 0
+      });
+      attached.push(None);
+    }
+    for (i, c) in bnc.clients.iter().enumerate() {
+      items.push(ZMQ::PollItem {
+        socket: ptr::null(),
+        fd: c.fd(),
+        events: if c.wants_write() {
+          ZMQ::IN | ZMQ::ERR | ZMQ::OUT as i16
+        } else {
+          ZMQ::IN | ZMQ::ERR
+        },
+        revents: 0
+      });
+      attached.push(Some(i));
+    }
+    // and last, ident queries, None again standing for the listener
+    let mut asking = Vec::new();
+    if let Some(ref ident) = ident {
+      items.push(ZMQ::PollItem {
+        socket: ptr::null(),
+        fd: ident.fd(),
+        events: ZMQ::IN | ZMQ::ERR,
+        revents: 0
+      });
+      asking.push(None);
+      for (i, q) in ident.queries.iter().enumerate() {
+        items.push(ZMQ::PollItem {
+          socket: ptr::null(),
+          fd: q.fd(),
+          events: ZMQ::IN | ZMQ::ERR,
+          revents: 0
+        });
+        asking.push(Some(i));
+      }
+    }
+
+    ZMQ::poll(&mut items, TICK);
+
+    let now = time::now_utc().to_timespec();
+    for irc in irc_ctxs.iter_mut() {
+      let events = irc.tick(now.sec)?;
+      dispatch(events, irc, &mut sock_pub, &mut sock_rep, &mut bnc, &ctx_sql)?;
+      if let Some(t) = config.away_timeout {
+        irc.auto_away(now.sec - last_activity >= t, &away_message)?;
+      }
+    }
 
     // REQ/REP socket, we reply
     if items[0].revents > 0 {
       let rmsg = ZMQ::Msg::new();
       rmsg.recv(&mut sock_rep, 0);
       let c : serde_json::Value = serde_json::from_slice(rmsg.data()).chain_err(|| "JSON: parse failure from client")?;
+      // any request at all means somebody is around
+      last_activity = now.sec;
+      if config.away_timeout.is_some() {
+        for irc in irc_ctxs.iter_mut() {
+          irc.auto_away(false, &away_message)?;
+        }
+      }
 
       // interpret client request
       match c.get("type").and_then(|s| {
         s.as_str().and_then(|s| {
           Some(s.as_ref())})}) {
         Some("server") => {
-          match c["operator"].as_str().unwrap().as_ref() {
-            "add" => (),
-            "edit" => (),
-            "remove" => (),
-            "enable" => (),
-            "disable" => (),
-            "list" => (),
-            &_ => ()
-          };
-          JSON::okay(&mut sock_rep);
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref mut irc = irc_ctxs[i];
+          let index = c["index"].as_u64().map(|n| n as usize);
+          match (c["operator"].as_str(), index) {
+            (Some("add"), _) => {
+              match server_options(&c, None) {
+                Some(server) => {
+                  let at = index.unwrap_or(irc.servers.len()).min(irc.servers.len());
+                  irc.servers.insert(at, server);
+                  SQL::servers_set(&mut ctx_sql, &irc.id, &irc.servers)?;
+                  JSON::okay(&mut sock_rep);
+                },
+                None => JSON::error(&mut sock_rep)
+              }
+            },
+            (Some("edit"), Some(n)) if n < irc.servers.len() => {
+              match server_options(&c, Some(irc.servers[n].clone())) {
+                Some(server) => {
+                  irc.servers[n] = server;
+                  SQL::servers_set(&mut ctx_sql, &irc.id, &irc.servers)?;
+                  JSON::okay(&mut sock_rep);
+                },
+                None => JSON::error(&mut sock_rep)
+              }
+            },
+            (Some("remove"), Some(n)) if n < irc.servers.len() => {
+              irc.servers.remove(n);
+              if irc.server > n {
+                irc.server -= 1;
+              }
+              SQL::servers_set(&mut ctx_sql, &irc.id, &irc.servers)?;
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("enable"), _) => {
+              SQL::enabled_set(&ctx_sql, &irc.id, true)?;
+              irc.enabled = true;
+              irc.reconnect_at = now.sec;
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("disable"), _) => {
+              SQL::enabled_set(&ctx_sql, &irc.id, false)?;
+              irc.enabled = false;
+              let events = irc.quit("Disconnecting", now.sec)?;
+              dispatch(events, irc, &mut sock_pub, &mut sock_rep, &mut bnc, &ctx_sql)?;
+              JSON::okay(&mut sock_rep);
+            },
+            // reconnect, to a particular server if given one
+            (Some("jump"), _) => {
+              match index {
+                Some(n) if n >= irc.servers.len() => JSON::error(&mut sock_rep),
+                _ => {
+                  let events = irc.quit("Changing servers", now.sec)?;
+                  if let Some(n) = index {
+                    irc.server = n;
+                  }
+                  dispatch(events, irc, &mut sock_pub, &mut sock_rep, &mut bnc, &ctx_sql)?;
+                  JSON::okay(&mut sock_rep);
+                }
+              }
+            },
+            (Some("list"), _) => JSON::servers(&mut sock_rep, irc),
+            _ => JSON::error(&mut sock_rep)
+          }
         },
-        Some("SYNC") => {
+        Some("contact") => {
           let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
-          let ref irc = &irc_ctxs[i];
-          JSON::sync(&mut sock_rep, irc);
+          let ref mut irc = irc_ctxs[i];
+          match (c["operator"].as_str(), c["nick"].as_str()) {
+            (Some("add"), Some(nick)) => {
+              SQL::contact_add(&ctx_sql, &irc.id, nick)?;
+              irc.contact_add(nick)?;
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("remove"), Some(nick)) => {
+              SQL::contact_remove(&ctx_sql, &irc.id, nick)?;
+              irc.contact_remove(nick)?;
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("list"), _) => JSON::contacts(&mut sock_rep, irc),
+            _ => JSON::error(&mut sock_rep)
+          }
         },
-        // TODO: move this logic to the IRC module somehow...
-        // also TODO: this should work with *every* IRC command
-        Some("IRC") => {
+        Some("away") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref mut irc = irc_ctxs[i];
+          match (c["operator"].as_str(), c["message"].as_str()) {
+            (Some("set"), Some(m)) => {
+              irc.set_away(Some(m))?;
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("unset"), _) => {
+              irc.set_away(None)?;
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("log"), _) => JSON::away_log(&mut sock_rep, irc, &SQL::away_log(&ctx_sql, &irc.id)?),
+            (Some("clear"), _) => {
+              SQL::away_log_clear(&ctx_sql, &irc.id)?;
+              JSON::okay(&mut sock_rep);
+            },
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("services") => {
           let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
           let ref mut irc = irc_ctxs[i];
-          match c["command"].as_str().unwrap().as_ref() {
-                "JOIN" => {
-                  irc.join(c["params"][0].as_str().unwrap().clone().as_ref());
-                  // when we join a new channel, broadcast new state to everyone
-                  JSON::sync(&mut sock_pub, &irc);
+          match c["operator"].as_str() {
+            Some("set") => {
+              let regain = c["regain"].as_str().unwrap_or("GHOST").to_uppercase();
+              let account = c["account"].as_str().unwrap_or(&irc.nick);
+              match c["password"].as_str() {
+                Some(password) if (regain == "GHOST" || regain == "RECOVER" || regain == "REGAIN") &&
+                                  sendable(account) && sendable(password) => {
+                  let s = IRC::Services {
+                    account: account.to_string(),
+                    password: password.to_string(),
+                    regain: regain,
+                    wait: c["wait"].as_bool().unwrap_or(true)
+                  };
+                  SQL::services_set(&ctx_sql, &irc.id, &s)?;
+                  irc.services = Some(s);
                   JSON::okay(&mut sock_rep);
                 },
-                "PART" => {
-                  irc.part(c["params"][0].as_str().unwrap().clone().as_ref(),
-                           c["params"][1].as_str().unwrap().clone().as_ref());
-                  // when we join a new channel, broadcast new state to everyone
-                  JSON::sync(&mut sock_pub, &irc);
+                _ => JSON::error(&mut sock_rep)
+              }
+            },
+            Some("unset") => {
+              SQL::services_unset(&ctx_sql, &irc.id)?;
+              irc.services = None;
+              JSON::okay(&mut sock_rep);
+            },
+            Some("chanserv") => {
+              match c["channel"].as_str() {
+                Some(channel) if sendable(channel) => {
+                  let cs = IRC::ChanServ {
+                    channel: channel.to_string(),
+                    op: c["op"].as_bool().unwrap_or(false),
+                    invite: c["invite"].as_bool().unwrap_or(false),
+                    unban: c["unban"].as_bool().unwrap_or(false)
+                  };
+                  if cs.op || cs.invite || cs.unban {
+                    SQL::chanserv_set(&ctx_sql, &irc.id, &cs)?;
+                    irc.chanserv.retain(|x| !x.channel.eq_ignore_ascii_case(channel));
+                    irc.chanserv.push(cs);
+                  } else {
+                    SQL::chanserv_remove(&ctx_sql, &irc.id, channel)?;
+                    irc.chanserv.retain(|x| !x.channel.eq_ignore_ascii_case(channel));
+                  }
                   JSON::okay(&mut sock_rep);
                 },
-                "PRIVMSG" => {
-                  irc.privmsg(c["params"][0].as_str().unwrap().clone().as_ref(),
-                              c["params"][1].as_str().unwrap().clone().as_ref());
+                _ => JSON::error(&mut sock_rep)
+              }
+            },
+            Some("list") => JSON::services(&mut sock_rep, irc),
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("channel") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref mut irc = irc_ctxs[i];
+          match (c["operator"].as_str(), c["channel"].as_str()) {
+            (Some("add"), Some(channel)) => {
+              let a = IRC::Channel {
+                name: channel.to_string(),
+                key: c["key"].as_str().map(|k| k.to_string()),
+                rejoin: c["rejoin"].as_bool().unwrap_or(false)
+              };
+              SQL::autojoin_set(&ctx_sql, &irc.id, &a)?;
+              irc.autojoin.retain(|x| !x.name.eq_ignore_ascii_case(channel));
+              irc.autojoin.push(a);
+              if irc.registered {
+                irc.autojoin()?;
+              }
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("remove"), Some(channel)) => {
+              SQL::autojoin_remove(&ctx_sql, &irc.id, channel)?;
+              irc.autojoin.retain(|x| !x.name.eq_ignore_ascii_case(channel));
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("list"), _) => JSON::autojoin(&mut sock_rep, irc),
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        // takes effect the next time we connect
+        Some("connection") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref mut irc = irc_ctxs[i];
+          match c["operator"].as_str() {
+            Some("set") => {
+              match connection_options(&c) {
+                Some(o) => {
+                  SQL::connection_set(&ctx_sql, &irc.id, &o)?;
+                  irc.options = o;
                   JSON::okay(&mut sock_rep);
                 },
-                _ => {
-                  JSON::sync(&mut sock_rep, &irc);
+                None => JSON::error(&mut sock_rep)
+              }
+            },
+            Some("unset") => {
+              SQL::connection_unset(&ctx_sql, &irc.id)?;
+              irc.options = TCP::Options::default();
+              JSON::okay(&mut sock_rep);
+            },
+            Some("get") => JSON::connection(&mut sock_rep, irc, &SQL::connection(&ctx_sql, &irc.id)?),
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("invite") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref mut irc = irc_ctxs[i];
+          match (c["operator"].as_str(), c["channel"].as_str(), c["mask"].as_str()) {
+            (Some("list"), _, _) => JSON::invites(&mut sock_rep, irc),
+            (Some("accept"), Some(channel), _) => {
+              if irc.invite_accept(channel)? {
+                JSON::okay(&mut sock_rep);
+              } else {
+                JSON::error(&mut sock_rep);
+              }
+            },
+            (Some("decline"), Some(channel), _) => {
+              if irc.invite_decline(channel) {
+                JSON::okay(&mut sock_rep);
+              } else {
+                JSON::error(&mut sock_rep);
+              }
+            },
+            (Some("trust"), _, Some(mask)) => {
+              SQL::trusted_add(&ctx_sql, &irc.id, mask)?;
+              if !irc.trusted.iter().any(|m| m == mask) {
+                irc.trusted.push(mask.to_string());
+              }
+              JSON::okay(&mut sock_rep);
+            },
+            (Some("distrust"), _, Some(mask)) => {
+              SQL::trusted_remove(&ctx_sql, &irc.id, mask)?;
+              irc.trusted.retain(|m| m != mask);
+              JSON::okay(&mut sock_rep);
+            },
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        // rules for every network have no id
+        Some("ignore") => {
+          match c["operator"].as_str() {
+            Some("list") => JSON::ignores(&mut sock_rep, &ignores),
+            Some("add") => {
+              let strings = |v: &serde_json::Value| -> Vec<String> {
+                v.as_array().map_or(Vec::new(), |a| a.iter().filter_map(|s| s.as_str()).map(|s| s.to_string()).collect())
+              };
+              let network = c["id"].as_str().map(|n| n.to_string());
+              let who = IRC::Ignored::new(c["kind"].as_str().unwrap_or("mask"), c["pattern"].as_str().unwrap_or(""));
+              let action = IRC::Ignoring::from_name(c["action"].as_str().unwrap_or("drop"));
+              match (who, action) {
+                (Some(who), Some(action)) if network.as_ref().map_or(true, |n| irc_ctxs.iter().any(|irc| irc.id == *n)) => {
+                  let mut rule = IRC::Ignore {
+                    rule: 0,
+                    network: network,
+                    who: who,
+                    commands: strings(&c["commands"]),
+                    channels: strings(&c["channels"]),
+                    action: action
+                  };
+                  rule.rule = SQL::ignore_add(&ctx_sql, &rule)?;
+                  JSON::added(&mut sock_rep, rule.rule);
+                  ignores.push(rule);
+                },
+                _ => JSON::error(&mut sock_rep)
+              }
+            },
+            Some("remove") => {
+              match c["rule"].as_i64() {
+                Some(rule) if ignores.iter().any(|i| i.rule == rule) => {
+                  SQL::ignore_remove(&ctx_sql, rule)?;
+                  ignores.retain(|i| i.rule != rule);
+                  JSON::okay(&mut sock_rep);
+                },
+                _ => JSON::error(&mut sock_rep)
+              }
+            },
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("highlight") => {
+          match c["operator"].as_str() {
+            Some("list") => JSON::highlight_rules(&mut sock_rep, &highlights),
+            Some("add") => {
+              let network = c["id"].as_str().map(|n| n.to_string());
+              let what = IRC::Highlighted::new(c["kind"].as_str().unwrap_or("word"), c["pattern"].as_str().unwrap_or(""));
+              match what {
+                Some(what) if network.as_ref().map_or(true, |n| irc_ctxs.iter().any(|irc| irc.id == *n)) => {
+                  let mut rule = IRC::Highlight {
+                    rule: 0,
+                    network: network,
+                    what: what,
+                    channels: c["channels"].as_array().map_or(Vec::new(), |a| a.iter().filter_map(|s| s.as_str()).map(|s| s.to_string()).collect()),
+                    exclude: c["exclude"].as_bool().unwrap_or(false)
+                  };
+                  rule.rule = SQL::highlight_rule_add(&ctx_sql, &rule)?;
+                  JSON::added(&mut sock_rep, rule.rule);
+                  highlights.push(rule);
+                },
+                _ => JSON::error(&mut sock_rep)
+              }
+            },
+            Some("remove") => {
+              match c["rule"].as_i64() {
+                Some(rule) if highlights.iter().any(|h| h.rule == rule) => {
+                  SQL::highlight_rule_remove(&ctx_sql, rule)?;
+                  highlights.retain(|h| h.rule != rule);
+                  JSON::okay(&mut sock_rep);
+                },
+                _ => JSON::error(&mut sock_rep)
+              }
+            },
+            Some("recent") => {
+              let limit = c["limit"].as_u64().map_or(HIGHLIGHT_LIMIT, |l| cmp::min(l as usize, HIGHLIGHT_LIMIT));
+              JSON::highlights(&mut sock_rep, &SQL::highlights(&ctx_sql, c["before"].as_i64(), limit)?);
+            },
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("buffer") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref irc = irc_ctxs[i];
+          match (c["operator"].as_str(), c["name"].as_str()) {
+            (Some("list"), _) => JSON::buffers(&mut sock_rep, irc, &SQL::buffers(&ctx_sql, &irc.id)?),
+            (Some("messages"), Some(name)) => {
+              let limit = c["limit"].as_u64().map_or(BUFFER_LIMIT, |l| cmp::min(l as usize, BUFFER_LIMIT));
+              JSON::buffered(&mut sock_rep, irc, name, &SQL::buffered(&ctx_sql, &irc.id, name, c["before"].as_i64(), limit)?);
+            },
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("read") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref mut irc = irc_ctxs[i];
+          match (c["operator"].as_str(), c["name"].as_str(), c["read"].as_i64()) {
+            (Some("list"), _, _) => JSON::markers(&mut sock_rep, irc, &SQL::markers(&ctx_sql, &irc.id)?),
+            (Some("set"), Some(name), Some(read)) => {
+              match (SQL::marker(&ctx_sql, &irc.id, name)?, SQL::date(&ctx_sql, &irc.id, read)?) {
+                (Some(_), Some(date)) => {
+                  if SQL::mark(&ctx_sql, &irc.id, name, read)? {
+                    if let Some(m) = SQL::marker(&ctx_sql, &irc.id, name)? {
+                      JSON::read(&mut sock_pub, irc, &m);
+                    }
+                    irc.mark_read(name, &BNC::timestamp(date))?;
+                  }
+                  JSON::okay(&mut sock_rep);
+                },
+                _ => JSON::error(&mut sock_rep)
+              }
+            },
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("reactions") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref irc = irc_ctxs[i];
+          match c["message"].as_i64() {
+            Some(id) => JSON::reactions(&mut sock_rep, irc, id, &SQL::reactions(&ctx_sql, &irc.id, id)?),
+            None => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("mask") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref irc = irc_ctxs[i];
+          let string = |k: &str| c[k].as_str().map(|s| s.to_string());
+          let user = IRC::User {
+            nick: string("nick").unwrap_or_else(|| "*".to_string()),
+            user: string("user"),
+            host: string("host"),
+            account: string("account"),
+            realname: string("realname")
+          };
+          match (c["operator"].as_str(), c["mask"].as_str(), c["shape"].as_str().and_then(IRC::Shape::from_name)) {
+            (Some("match"), Some(mask), _) => JSON::matched(&mut sock_rep, irc.matches(mask, &user)),
+            (Some("ban"), _, Some(shape)) => match irc.ban(&user, shape) {
+              Some(mask) => JSON::mask(&mut sock_rep, &mask),
+              None => JSON::error(&mut sock_rep)
+            },
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        // STS policies aren't per network, so there's no id
+        Some("sts") => {
+          match (c["operator"].as_str(), c["host"].as_str()) {
+            (Some("list"), _) => JSON::policies(&mut sock_rep, &SQL::policies(&ctx_sql)?, now.sec),
+            (Some("remove"), Some(host)) => {
+              SQL::policy_remove(&ctx_sql, host)?;
+              for irc in irc_ctxs.iter_mut() {
+                irc.policies.retain(|p| !p.host.eq_ignore_ascii_case(host));
+              }
+              JSON::okay(&mut sock_rep);
+            },
+            _ => JSON::error(&mut sock_rep)
+          }
+        },
+        Some("query") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref mut irc = irc_ctxs[i];
+          // otherwise the reply waits for the server to finish answering, see dispatch
+          if !irc.query(c["query"].as_str().unwrap_or(""), c["target"].as_str(), now.sec)? {
+            JSON::error(&mut sock_rep);
+          }
+        },
+        Some("SYNC") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref irc = &irc_ctxs[i];
+          JSON::sync(&mut sock_rep, irc);
+        },
+        // TODO: move this logic to the IRC module somehow...
+        Some("IRC") => {
+          let i = IRC::lookup(c["id"].as_str().unwrap(), &irc_ctxs);
+          let ref mut irc = irc_ctxs[i];
+          let params : Vec<&str> = match c["params"].as_array() {
+            Some(p) => p.iter().filter_map(|p| p.as_str()).collect(),
+            None => Vec::new()
+          };
+          // client-only tags to send with it, like +typing
+          let tags : Vec<(String, String)> = match c["tags"].as_object() {
+            Some(t) => t.iter().map(|(k, v)| (k.clone(), v.as_str().unwrap_or("").to_string())).collect(),
+            None => Vec::new()
+          };
+          match IRC::command::Typed::new(c["command"].as_str().unwrap_or(""), params) {
+            Ok(ref cmd) if cmd.line().is_err() => JSON::error(&mut sock_rep),
+            Ok(ref cmd) if !tags.is_empty() => {
+              if irc.send_tagged(cmd, &tags)? {
+                match *cmd {
+                  IRC::command::Typed::Privmsg { .. } | IRC::command::Typed::Notice { .. } => bnc.echo(irc, cmd, None),
+                  _ => ()
                 }
+                JSON::okay(&mut sock_rep);
+              } else {
+                JSON::error(&mut sock_rep);
+              }
+            },
+            // the new state is broadcast once the server confirms the JOIN or PART
+            Ok(IRC::command::Typed::Join { channels, keys }) => {
+              let mut keys = keys.into_iter();
+              let chans = channels.into_iter().map(|c| (c.to_string(), keys.next().map(|k| k.to_string()))).collect();
+              irc.join_many(chans)?;
+              JSON::okay(&mut sock_rep);
+            },
+            Ok(cmd) => {
+              irc.send(&cmd)?;
+              match cmd {
+                IRC::command::Typed::Privmsg { .. } | IRC::command::Typed::Notice { .. } => bnc.echo(irc, &cmd, None),
+                _ => ()
               }
+              JSON::okay(&mut sock_rep);
+            },
+            Err(_) => JSON::error(&mut sock_rep)
+          }
         },
         // TODO: this should print the JSON
         // also TODO: update to rust 1.19 so i can use eprint!
@@ -175,65 +804,164 @@ fn run() -> Result<()> {
         Some(_) => {io::stderr().write(b"Warning: invalid type in request").chain_err(|| "write failure")?;},
         None => {io::stderr().write(b"Warning: invalid JSON in request: missing type field").chain_err(|| "write failure")?;}
       }
-
-      ss -= 1;
-      if ss == 0 { continue; }
     }
 
     // IRC sockets
-    for s in 1..items.len() {
+    for s in 1..1+polled.len() {
       if items[s].revents > 0 {
-        let ref mut irc = irc_ctxs[s-1];
+        let ref mut irc = irc_ctxs[polled[s-1]];
+        // tick() has already moved a connection on its way up along as far as it'll go
+        if irc.dialing() {
+          continue;
+        }
 
-        let now = time::now_utc().to_timespec();
+        // send whatever was waiting for the socket to become writable
+        irc.flush();
 
-        let line = IRC::readline(&mut irc.sock);
-        let msg = IRC::parse::parse_message("localhost", id, &line).unwrap();
-        //println!("items:{:?}\n, msg:{:?}\n serailize:{}\n", items, msg, msg.serialize());
+        // a single read can hold any number of lines, or only part of one
+        let (lines, eof) = irc.read();
+        // messages borrow the network's id, and handle() needs irc to itself
+        let network = irc.id.clone();
+        for line in lines {
+          let now = time::now_utc().to_timespec();
+          // every line from the server, for watching what a network sends us
+          #[cfg(feature = "verbose")]
+          println!("{}: {}", irc.id, line);
 
-        let (command, numeric) : (Option<String>, Option<u16>) = match msg.command {
-          IRC::Command::Named(ref c) => (Some(c.clone().into_owned()), None),
-          IRC::Command::Numeric(n) => (None, Some(n))
-        };
+          // fit now into 64-bit integer
+          // FIXME: doesn't this truncate the number of seconds after the year 2038?
+          let then = now.sec << 32 | now.nsec as i64;
+
+          let msg = match IRC::parse::parse_message(&network, id, &line) {
+            Ok(msg) => msg,
+            Err(e) => {
+              eprintln!("Warning: {}: unparseable line: {}", irc.id, e);
+              // keep it anyway, it might matter to somebody
+              ctx_sql.execute("INSERT INTO messages (network, id, date, direction, gibberish) VALUES (?1, ?2, ?3, 0, ?4)",
+                              &[&irc.id, &id, &then, &line]).chain_err(|| "SQLite: insert failure in gibberish")?;
+              id += 1;
+              continue;
+            }
+          };
+          let ignored = IRC::ignoring(&ignores, irc, &msg);
+          // nothing from ignored people counts, not even marked ones
+          let highlight = ignored.is_none() && IRC::highlighted(&highlights, irc, &msg);
+          let buffers = irc.buffers(&msg);
+          let reply = match msg.tag("+draft/reply") {
+            Some(r) => SQL::msgid(&ctx_sql, &irc.id, &r)?,
+            None => None
+          };
+          // buffers this message is the first in, to announce once it's stored
+          let mut added = Vec::new();
+
+          let (command, numeric) : (Option<String>, Option<u16>) = match msg.command {
+            IRC::Command::Named(ref c) => (Some(c.clone().into_owned()), None),
+            IRC::Command::Numeric(n) => (None, Some(n))
+          };
+
+          match msg.prefix {
+            /* don't log messages without prefixes - i think this is only PING? */
+            None => (),
+            Some(_) if ignored == Some(IRC::Ignoring::Drop) => (),
+            Some(ref p) => {
+              let (server, nick, ident, host) = match p {
+                &IRC::Prefix::Server(server) => (Some(server), None, None, None),
+                // technically, the server can omit the user or the host, even if it gives you a nick
+                &IRC::Prefix::User(nick, user, host) => (None, Some(nick), user, host)
+              };
+              let tx = ctx_sql.transaction().unwrap();
+              tx.execute("INSERT INTO messages (network, id, date, direction, server, nick, ident, host, command, numeric, gibberish) VALUES (?1, ?2, ?3, 0, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                         &[&irc.id, &id, &then, &server, &nick, &ident, &host, &command, &numeric, &None::<&str>]).unwrap();
+              let mut idx = 0;
+              for ref p in msg.params.clone() {
+                tx.execute("INSERT INTO params (id, network, idx, param) VALUES (?1, ?2, ?3, ?4)", &[&id, &irc.id, &idx, p]).unwrap();
+                idx += 1;
+              }
+              for t in msg.tags.iter() {
+                // a repeated tag is the same as its last occurrence
+                tx.execute("INSERT OR REPLACE INTO tags (id, network, key, value) VALUES (?1, ?2, ?3, ?4)",
+                           &[&id, &irc.id, &t.key, &t.value()]).unwrap();
+              }
+              if highlight {
+                tx.execute("INSERT INTO highlights (network, id) VALUES (?1, ?2)", &[&irc.id, &id]).unwrap();
+              }
+              if ignored == Some(IRC::Ignoring::Hide) {
+                tx.execute("INSERT INTO hidden (network, id) VALUES (?1, ?2)", &[&irc.id, &id]).unwrap();
+              }
+              for b in buffers.iter() {
+                let (buffer, new) = SQL::buffer(&tx, &irc.id, b)?;
+                tx.execute("INSERT OR IGNORE INTO buffered (network, id, buffer) VALUES (?1, ?2, ?3)", &[&irc.id, &id, &buffer]).unwrap();
+                if new {
+                  added.push((buffer, b));
+                }
+              }
+              tx.commit();
+              id += 1;
+            }
+          }
 
-        match msg.prefix {
-          /* don't log messages without prefixes - i think this is only PING? */
-          None => (),
-          Some(ref p) => {
-            let (server, nick, ident, host) = match p {
-              &IRC::Prefix::Server(server) => (Some(server), None, None, None),
-              // technically, the server can omit the user or the host, even if it gives you a nick
-              &IRC::Prefix::User(nick, user, host) => (None, Some(nick), Some(user), Some(host))
-            };
-            // fit now into 64-bit integer
-            // FIXME: doesn't this truncate the number of seconds after the year 2038?
-            let then = now.sec << 32 | now.nsec as i64;
-            let tx = ctx_sql.transaction().unwrap();
-            // TODO: deal with gibberish
-            tx.execute("INSERT INTO messages (network, id, date, server, nick, ident, host, command, numeric, gibberish) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-                       &[&"localhost", &id, &then, &server, &nick, &ident, &host, &command, &numeric, &None::<&str>]).unwrap();
-            let mut idx = 0;
-            for ref p in msg.params.clone() {
-              tx.execute("INSERT INTO params (id, network, idx, param) VALUES (?1, ?2, ?3, ?4)", &[&id, &"localhost", &idx, p]).unwrap();
-              idx += 1;
+          // stored unless dropped, but hidden messages aren't published, and neither is a
+          // netsplit's worth of quits and joins
+          let quiet = ignored.map_or(false, |i| i >= IRC::Ignoring::Hide);
+          for (buffer, b) in added {
+            JSON::buffer(&mut sock_pub, irc, buffer, b);
+          }
+          if !irc.folds(&msg) && !quiet {
+            sock_pub.send(msg.serialize(ignored.is_some(), highlight, &buffers, reply).as_bytes(), 0);
+            if let (Some(to), Some(react)) = (reply, msg.tag("+draft/react")) {
+              JSON::react(&mut sock_pub, irc, &msg, to, &react);
             }
-            tx.commit();
-            id += 1;
+          }
+          if highlight {
+            JSON::highlight(&mut sock_pub, irc, &msg);
+          }
+
+          let mut events = irc.handle(&msg, now.sec)?;
+          // we still keep track of what ignored people do, but they don't go in the away log
+          if quiet {
+            events.retain(|e| match *e {
+              IRC::Event::Missed(_) => false,
+              _ => true
+            });
+          }
+          dispatch(events, irc, &mut sock_pub, &mut sock_rep, &mut bnc, &ctx_sql)?;
+          if !quiet {
+            bnc.relay(irc, &msg, &line, then);
           }
         }
 
-        /* see if we need to respond to anything eg PING */
-        match command {
-          Some(ref s) => if s == "PING" { irc.pong(msg.params.get(0).unwrap().to_string()); },
-          // don't send PINGs to the publisher
-          _ => ()
-        };
+        // EOF, we've lost the server
+        if eof {
+          let events = irc.disconnect(time::now_utc().to_timespec().sec);
+          dispatch(events, irc, &mut sock_pub, &mut sock_rep, &mut bnc, &ctx_sql)?;
+        }
+      }
+    }
 
-        sock_pub.send(msg.serialize().as_bytes(), 0);
+    let answered = 1 + polled.len() + attached.len();
+    for (s, a) in attached.into_iter().enumerate() {
+      if items[1+polled.len()+s].revents > 0 {
+        match a {
+          None => bnc.accept(now.sec),
+          // somebody chatting through an IRC client is around too
+          Some(i) => if bnc.read(i, &mut irc_ctxs, &mut ctx_sql)? {
+            last_activity = now.sec;
+          }
+        }
+      }
+    }
+    bnc.tick(now.sec, &irc_ctxs, &ctx_sql)?;
 
-        ss -= 1;
-        if ss == 0 { break; }
+    if let Some(ref mut ident) = ident {
+      for (s, a) in asking.into_iter().enumerate() {
+        if items[answered+s].revents > 0 {
+          match a {
+            None => ident.accept(now.sec),
+            Some(i) => ident.read(i, &irc_ctxs)
+          }
+        }
       }
+      ident.tick(now.sec);
     }
   };
 
@@ -12,7 +12,7 @@ CREATE TABLE IF NOT EXISTS messages (
 
   date      INTEGER NOT NULL,
 
-  /* sent or recieved */
+  /* sent (1) or recieved (0) */
   direction BOOLEAN NOT NULL,
 
   /* prefix */
@@ -40,4 +40,206 @@ CREATE TABLE IF NOT EXISTS params (
   param     TEXT    NOT NULL,
   PRIMARY KEY (id, network, idx),
   FOREIGN KEY (id, network) REFERENCES messages(id, network)
-);
\ No newline at end of file
+);
+/* nicks we want to be told about when they come online or go offline */
+CREATE TABLE IF NOT EXISTS contacts (
+  network   TEXT    NOT NULL,
+  nick      TEXT    NOT NULL COLLATE NOCASE,
+  PRIMARY KEY (network, nick)
+);
+
+/* private messages and highlights that arrived while we were away */
+CREATE TABLE IF NOT EXISTS away_log (
+  network   TEXT    NOT NULL,
+  id        INTEGER NOT NULL,
+  PRIMARY KEY (network, id),
+  FOREIGN KEY (id, network) REFERENCES messages(id, network)
+);
+
+/* how to identify to NickServ on each network */
+CREATE TABLE IF NOT EXISTS services (
+  network   TEXT    NOT NULL,
+  account   TEXT    NOT NULL,
+  password  TEXT    NOT NULL,
+  regain    TEXT    NOT NULL DEFAULT 'GHOST' CHECK (regain IN ('GHOST', 'RECOVER', 'REGAIN')),
+  /* hold back joins until NickServ confirms we identified */
+  wait      BOOLEAN NOT NULL DEFAULT 1,
+  PRIMARY KEY (network)
+);
+
+/* what to ask ChanServ for in each channel */
+CREATE TABLE IF NOT EXISTS chanserv (
+  network   TEXT    NOT NULL,
+  channel   TEXT    NOT NULL COLLATE NOCASE,
+  op        BOOLEAN NOT NULL DEFAULT 0,
+  invite    BOOLEAN NOT NULL DEFAULT 0,
+  unban     BOOLEAN NOT NULL DEFAULT 0,
+  PRIMARY KEY (network, channel)
+);
+
+/* channels to join whenever we connect */
+CREATE TABLE IF NOT EXISTS autojoin (
+  network   TEXT    NOT NULL,
+  channel   TEXT    NOT NULL COLLATE NOCASE,
+  key       TEXT,
+  /* join again after being kicked */
+  rejoin    BOOLEAN NOT NULL DEFAULT 0,
+  PRIMARY KEY (network, channel)
+);
+
+/* how to reach each network, every column may be left null for a plain direct connection */
+CREATE TABLE IF NOT EXISTS connection (
+  network        TEXT    NOT NULL,
+  proxy          TEXT    CHECK (proxy IN ('socks5', 'http')),
+  proxy_host     TEXT,
+  proxy_port     INTEGER,
+  proxy_username TEXT,
+  proxy_password TEXT,
+  /* let the proxy resolve names, needed for onion services */
+  remote_dns     BOOLEAN NOT NULL DEFAULT 1,
+  /* local address to connect from */
+  bind           TEXT,
+  prefer         TEXT    CHECK (prefer IN ('ipv4', 'ipv6')),
+  PRIMARY KEY (network),
+  CHECK (proxy IS NULL OR (proxy_host IS NOT NULL AND proxy_port IS NOT NULL))
+);
+
+/* every network we know about */
+CREATE TABLE IF NOT EXISTS networks (
+  network   TEXT    NOT NULL,
+  /* whether to stay connected */
+  enabled   BOOLEAN NOT NULL DEFAULT 1,
+  PRIMARY KEY (network)
+);
+
+/* networks added by IRC clients, with who we are on them, on top of the one we always have */
+CREATE TABLE IF NOT EXISTS identities (
+  network   TEXT    NOT NULL,
+  nick      TEXT    NOT NULL,
+  ident     TEXT    NOT NULL,
+  realname  TEXT    NOT NULL,
+  PRIMARY KEY (network)
+);
+
+/* masks of people whose invites we accept straight away, per network */
+CREATE TABLE IF NOT EXISTS trusted (
+  network   TEXT    NOT NULL,
+  mask      TEXT    NOT NULL,
+  PRIMARY KEY (network, mask)
+);
+
+/* people to ignore, see IRC::Ignore */
+CREATE TABLE IF NOT EXISTS ignores (
+  rule      INTEGER PRIMARY KEY,
+  /* NULL for every network */
+  network   TEXT,
+  kind      TEXT    NOT NULL CHECK (kind IN ('mask', 'account', 'regex')),
+  pattern   TEXT    NOT NULL,
+  /* space separated, empty for any */
+  commands  TEXT    NOT NULL DEFAULT '',
+  channels  TEXT    NOT NULL DEFAULT '',
+  action    TEXT    NOT NULL DEFAULT 'drop' CHECK (action IN ('mark', 'hide', 'drop'))
+);
+
+/* what mentions us besides our nick, see IRC::Highlight */
+CREATE TABLE IF NOT EXISTS highlight_rules (
+  rule      INTEGER PRIMARY KEY,
+  /* NULL for every network */
+  network   TEXT,
+  kind      TEXT    NOT NULL CHECK (kind IN ('nick', 'word', 'regex', 'mask')),
+  pattern   TEXT    NOT NULL DEFAULT '',
+  /* space separated, empty for any */
+  channels  TEXT    NOT NULL DEFAULT '',
+  exclude   BOOLEAN NOT NULL DEFAULT 0
+);
+
+/* messages that mentioned us */
+CREATE TABLE IF NOT EXISTS highlights (
+  network   TEXT    NOT NULL,
+  id        INTEGER NOT NULL,
+  PRIMARY KEY (network, id),
+  FOREIGN KEY (id, network) REFERENCES messages(id, network)
+);
+
+/* conversations: channels, queries named after the other party, and each network's server buffer, named '' */
+CREATE TABLE IF NOT EXISTS buffers (
+  buffer    INTEGER PRIMARY KEY,
+  network   TEXT    NOT NULL,
+  name      TEXT    NOT NULL COLLATE NOCASE,
+  kind      TEXT    NOT NULL CHECK (kind IN ('server', 'channel', 'query')),
+  UNIQUE (network, name)
+);
+
+/* which buffers each message is in, every channel someone was in for their QUIT or NICK */
+CREATE TABLE IF NOT EXISTS buffered (
+  network   TEXT    NOT NULL,
+  id        INTEGER NOT NULL,
+  buffer    INTEGER NOT NULL,
+  PRIMARY KEY (network, id, buffer),
+  FOREIGN KEY (id, network) REFERENCES messages(id, network),
+  FOREIGN KEY (buffer) REFERENCES buffers(buffer)
+);
+
+/* the newest message read in each buffer */
+CREATE TABLE IF NOT EXISTS read_markers (
+  buffer    INTEGER PRIMARY KEY,
+  id        INTEGER NOT NULL,
+  FOREIGN KEY (buffer) REFERENCES buffers(buffer)
+);
+
+/* messages an ignore rule said to hide, kept out of what IRC clients are sent */
+CREATE TABLE IF NOT EXISTS hidden (
+  network   TEXT    NOT NULL,
+  id        INTEGER NOT NULL,
+  PRIMARY KEY (network, id),
+  FOREIGN KEY (id, network) REFERENCES messages(id, network)
+);
+
+/* IRCv3 STS policies, only connect to host over TLS on port until expires, a unix time */
+CREATE TABLE IF NOT EXISTS sts (
+  host      TEXT    NOT NULL COLLATE NOCASE,
+  port      INTEGER NOT NULL,
+  expires   INTEGER NOT NULL,
+  PRIMARY KEY (host)
+);
+
+/* the servers making up each network, tried in order of idx */
+CREATE TABLE IF NOT EXISTS servers (
+  network   TEXT    NOT NULL,
+  idx       INTEGER NOT NULL,
+  host      TEXT    NOT NULL,
+  port      INTEGER NOT NULL,
+  tls       BOOLEAN NOT NULL DEFAULT 0,
+  password  TEXT,
+  PRIMARY KEY (network, idx)
+);
+
+/* IRCv3 message tags, unescaped */
+CREATE TABLE IF NOT EXISTS tags (
+  id        INTEGER NOT NULL,
+  network   TEXT    NOT NULL,
+  key       TEXT    NOT NULL,
+  value     TEXT    NOT NULL,
+  PRIMARY KEY (id, network, key),
+  FOREIGN KEY (id, network) REFERENCES messages(id, network)
+);
+
+/* to find messages by the msgid the network gave them, for replies and reactions */
+CREATE INDEX IF NOT EXISTS tags_value ON tags (network, key, value);
+
+/* names for numerics, filled in from IRC::NUMERICS at startup so messages.numeric can be joined against it */
+CREATE TABLE IF NOT EXISTS numerics (
+  numeric   INTEGER PRIMARY KEY,
+  name      TEXT    NOT NULL,
+  error     BOOLEAN NOT NULL,
+  /* index of the param the reply is about, see IRC::Reply */
+  target    INTEGER
+);
+
+/* the last message each named IRC client attached to us was sent, to play back the rest next time */
+CREATE TABLE IF NOT EXISTS delivered (
+  client    TEXT    NOT NULL,
+  network   TEXT    NOT NULL,
+  id        INTEGER NOT NULL,
+  PRIMARY KEY (client, network)
+);